@@ -0,0 +1,12 @@
+#![no_main]
+
+use kidfile::{file_data::FileData, DecodeOptions};
+use libfuzzer_sys::fuzz_target;
+
+// detection and identification with every decoder, then whatever chain the heuristics pick
+fuzz_target!(|data: &[u8]| {
+	let mut file = FileData::Memory {buf: data.into()};
+	let _ = kidfile::probe(&mut file);
+	let _ = kidfile::identify(&mut file);
+	let _ = kidfile::auto_decode_full(&mut file, None, &DecodeOptions::default());
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use kidfile::{file_data::FileData, DecodeOptions};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+	let _ = kidfile::DEFAULT_REGISTRY.decode_with("cps", &mut FileData::Memory {buf: data.into()}, &DecodeOptions::default());
+});
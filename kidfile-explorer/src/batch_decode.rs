@@ -1,8 +1,7 @@
-use std::{borrow::Cow, collections::VecDeque, fs::{self}, sync::{atomic::{self, AtomicBool, AtomicUsize}, Arc, RwLock}, thread::{self, JoinHandle}};
+use std::{borrow::Cow, collections::VecDeque, fs::{self}, sync::{atomic::{self, AtomicU32, AtomicUsize}, Arc, RwLock}, thread::{self, JoinHandle}};
 use egui::{Align, Button, Context, Id, Label, Layout, Modal, ProgressBar, TextEdit};
-use image::ExtendedColorType;
-use kidfile::{auto_decode_step, DynData};
-use crate::{complex_path::ComplexPath, dirty_config, BATCH_CONVERT_IMAGES, BATCH_DECOMPRESS, BATCH_EXTRACT_ARCHIVES, EXTRACTION_SUFFIX};
+use kidfile::{auto_decode_step, image::ExportOptions, CancelToken, DecodeOptions, DynData, ErrorKind};
+use crate::{complex_path::ComplexPath, dirty_config, log, BATCH_CONVERT_IMAGES, BATCH_DECOMPRESS, BATCH_EXTRACT_ARCHIVES, EXTRACTION_SUFFIX};
 
 enum BatchStatus {
 	Configuring,
@@ -20,8 +19,10 @@ pub struct BatchDecode {
 	pending_files: Arc<RwLock<VecDeque<ComplexPath>>>,
 	found_file_count: Arc<AtomicUsize>,
 	processed_file_count: Arc<AtomicUsize>,
+	/// how far each thread is into its current file, in thousandths
+	file_progress: Arc<Vec<AtomicU32>>,
 	threads: Vec<JoinHandle<()>>,
-	cancel: Arc<AtomicBool>
+	cancel: CancelToken
 }
 
 fn survey(files: &mut VecDeque<ComplexPath>, path: &ComplexPath) {
@@ -46,8 +47,9 @@ impl BatchDecode {
 			pending_files: Arc::new(RwLock::new(VecDeque::new())),
 			found_file_count: Arc::new(AtomicUsize::new(0)),
 			processed_file_count: Arc::new(AtomicUsize::new(0)),
+			file_progress: Arc::new(Vec::new()),
 			threads: Vec::new(),
-			cancel: Arc::new(AtomicBool::new(false))
+			cancel: CancelToken::new()
 		}
 	}
 
@@ -61,7 +63,9 @@ impl BatchDecode {
 		self.found_file_count.store(self.pending_files.read().unwrap().len(), atomic::Ordering::SeqCst);
 		self.processed_file_count.store(0, atomic::Ordering::SeqCst);
 		let root_parent = Arc::new(self.path.get_physical().parent().unwrap().to_path_buf());
-		for _ in 0..num_cpus::get() {
+		let thread_count = num_cpus::get();
+		self.file_progress = Arc::new((0..thread_count).map(|_| AtomicU32::new(0)).collect());
+		for thread_idx in 0..thread_count {
 			let root_parent = root_parent.clone();
 			let extraction_suffix = self.extraction_suffix.clone();
 			let extract_archives = self.extract_archives;
@@ -71,6 +75,11 @@ impl BatchDecode {
 			let found_file_count = self.found_file_count.clone();
 			let processed_file_count = self.processed_file_count.clone();
 			let cancel = self.cancel.clone();
+			let file_progress = self.file_progress.clone();
+			let decode_options = DecodeOptions::default().with_cancel(cancel.clone()).with_progress({
+				let file_progress = file_progress.clone();
+				move |progress| file_progress[thread_idx].store((progress.fraction() * 1000.0) as u32, atomic::Ordering::Relaxed)
+			});
 			self.threads.push(thread::spawn(move || {
 				while let Some(path) = {pending_files.write().unwrap().pop_front()} {
 					let mut target = root_parent.to_path_buf();
@@ -88,10 +97,12 @@ impl BatchDecode {
 					if let Ok(mut data) = path.load() {
 						let mut steps_taken = Vec::new();
 						loop {
-							if cancel.load(atomic::Ordering::Acquire) {
+							if cancel.is_cancelled() {
 								return;
 							}
-							match auto_decode_step(data.to_mut(), path.get_archive_format(), path.get_archive_format()) {
+							let step = decode_options.check_depth(steps_taken.len() + 1)
+								.and_then(|_| auto_decode_step(data.to_mut(), path.get_archive_format(), path.get_archive_format(), &decode_options));
+							match step {
 								Ok((step, DynData::Raw(raw))) => {
 									steps_taken.push(step);
 									data = Cow::Owned(raw);
@@ -99,17 +110,16 @@ impl BatchDecode {
 								Ok((_, DynData::Image(img))) => {
 									fs::create_dir_all(&target.parent().unwrap()).unwrap();
 									if convert_images {
-										for (i, frame) in img.frames.iter().enumerate() {
-											let mut file_name = target.file_name().unwrap().to_owned();
-											if i > 0 {
-												file_name.push(format!(".{i}.png"));
-											} else {
-												file_name.push(".png");
-											}
-											image::save_buffer(target.with_file_name(file_name), frame.as_rgba_bytes(), frame.width, frame.height, ExtendedColorType::Rgba8).unwrap();
+										let mut file_name = target.file_name().unwrap().to_owned();
+										file_name.push(".png");
+										if let Err(e) = img.export(target.with_file_name(file_name), &ExportOptions::default()) {
+											log!("could not convert '{}': {e}", target.display());
 										}
 									} else if decompress && (!steps_taken.is_empty() || !path.is_physical()) {
-										fs::write(&target, data.to_mut().read()).unwrap();
+										match data.to_mut().read() {
+											Ok(bytes) => fs::write(&target, bytes).unwrap(),
+											Err(e) => log!("could not read '{}': {e}", target.display())
+										}
 									}
 									break;
 								}
@@ -124,20 +134,28 @@ impl BatchDecode {
 										});
 									} else if decompress && (!steps_taken.is_empty() || !path.is_physical()) {
 										fs::create_dir_all(&target.parent().unwrap()).unwrap();
-										fs::write(&target, data.to_mut().read()).unwrap();
+										match data.to_mut().read() {
+											Ok(bytes) => fs::write(&target, bytes).unwrap(),
+											Err(e) => log!("could not read '{}': {e}", target.display())
+										}
 									}
 									break;
 								}
+								Err(e) if e.kind == ErrorKind::Cancelled => return,
 								Err(_) => {
 									if decompress && (!steps_taken.is_empty() || !path.is_physical()) {
 										fs::create_dir_all(&target.parent().unwrap()).unwrap();
-										fs::write(&target, data.to_mut().read()).unwrap();
+										match data.to_mut().read() {
+											Ok(bytes) => fs::write(&target, bytes).unwrap(),
+											Err(e) => log!("could not read '{}': {e}", target.display())
+										}
 									}
 									break;
 								}
 							}
 						}
 					}
+					file_progress[thread_idx].store(0, atomic::Ordering::Relaxed);
 					processed_file_count.fetch_add(1, atomic::Ordering::SeqCst);
 				}
 			}));
@@ -211,9 +229,10 @@ impl BatchDecode {
 					ui.label(format!("{} threads running", self.threads.len()));
 					ui.label(format!("Found {} files", found));
 					ui.label(format!("Processed {} files", processed));
-					ui.add(ProgressBar::new(processed as f32 / found as f32));
+					let in_flight = self.file_progress.iter().map(|x| x.load(atomic::Ordering::Relaxed) as f32 / 1000.0).sum::<f32>();
+					ui.add(ProgressBar::new((processed as f32 + in_flight) / found as f32));
 					ui.separator();
-					let already_canceling = self.cancel.load(atomic::Ordering::Acquire);
+					let already_canceling = self.cancel.is_cancelled();
 					if let BatchStatus::Finished = self.status {
 						ui.label("Done");
 						if ui.add(Button::new("OK").small()).clicked() {
@@ -221,7 +240,7 @@ impl BatchDecode {
 						}
 					} else {
 						if ui.add_enabled(!already_canceling, Button::new("Cancel").small()).clicked() {
-							self.cancel.store(true, atomic::Ordering::Release);
+							self.cancel.cancel();
 							close = true;
 						}
 					}
@@ -1,6 +1,15 @@
 use std::{borrow::Cow, ffi::{OsStr, OsString}, path::{Path, PathBuf, StripPrefixError}, sync::Arc};
 use kidfile::{file_data::FileData, Archive};
 
+// mapped so big disc images aren't copied into memory, streamed if the file can't be mapped
+fn load_physical(path: PathBuf) -> Result<Cow<'static, FileData>, ()> {
+	if let Ok(data) = FileData::mmap(&path) {
+		return Ok(Cow::Owned(data));
+	}
+	let size = std::fs::metadata(&path).map_err(|_| ())?.len() as usize;
+	Ok(Cow::Owned(FileData::Stream {path, file: None, start: 0, size, read_ahead: Default::default()}))
+}
+
 #[derive(Clone)]
 pub enum ComplexPath {
 	Physical(PathBuf, bool),
@@ -220,9 +229,7 @@ impl ComplexPath {
 		match self {
 			Self::Physical(p, is_dir) => {
 				assert!(*is_dir);
-				let path = p.join(name);
-				let size = std::fs::metadata(&path).map_err(|_| ())?.len() as usize;
-				Ok(Cow::Owned(FileData::Stream {path, file: None, start: 0, size}))
+				load_physical(p.join(name))
 			}
 			Self::Archive(_, _, arc, subfile) => {
 				assert!(subfile.is_empty());
@@ -241,8 +248,7 @@ impl ComplexPath {
 		match self {
 			Self::Physical(p, is_dir) => {
 				assert!(!*is_dir);
-				let size = std::fs::metadata(&p).map_err(|_| ())?.len() as usize;
-				Ok(Cow::Owned(FileData::Stream {path: p.clone(), file: None, start: 0, size}))
+				load_physical(p.clone())
 			}
 			Self::Archive(_, _, arc, subfile) => {
 				assert!(!subfile.is_empty());
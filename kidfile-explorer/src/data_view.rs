@@ -1,8 +1,8 @@
-use std::ffi::OsString;
-use egui::{Align, ColorImage, Layout, ScrollArea, TextEdit, TextureHandle, Ui, Vec2};
-use kidfile::{file_data::FileData, image::PixelFormat};
+use std::{ffi::OsString, fs::File, io::BufWriter, time::Duration};
+use egui::{Align, Button, ColorImage, Layout, ScrollArea, Sense, Slider, TextEdit, TextureHandle, TextureOptions, Ui, Vec2};
+use kidfile::{file_data::FileData, image::Image, Candidate, Certainty, Error};
 
-use crate::icon_button;
+use crate::{icon_button, log};
 
 pub enum DataView {
 	None,
@@ -11,16 +11,43 @@ pub enum DataView {
 		file_name: OsString,
 		hex: String,
 		error_msg: String,
+		candidates: Vec<Candidate>,
 		reset_view: bool
 	},
-	Image(Vec<(ColorImage, TextureHandle, PixelFormat)>)
+	Image {
+		/// each frame with its original format, alpha mode and placement, shown after its size
+		frames: Vec<(ColorImage, TextureHandle, String)>,
+		/// kept to be saved as an animation and to switch palettes
+		image: Image,
+		/// the frame in the big view
+		current: usize,
+		playing: bool,
+		/// when the current frame started showing, in egui's input time
+		frame_start: f64
+	}
+}
+
+fn save_animation(image: &Image) {
+	if let Some(dst) = rfd::FileDialog::new().add_filter("Animated PNG", &["png"]).add_filter("GIF", &["gif"]).save_file() {
+		let result = File::create(&dst).map_err(Error::from).and_then(|file| {
+			if dst.extension().is_some_and(|x| x.eq_ignore_ascii_case("gif")) {
+				image.write_gif(BufWriter::new(file))
+			} else {
+				image.write_apng(BufWriter::new(file))
+			}
+		});
+		if let Err(e) = result {
+			log!("could not save animation: {e}");
+		}
+	}
 }
 
 impl DataView {
 	pub fn new_raw(mut file_data: FileData, file_name: OsString, error_msg: String) -> Self {
 		const MAX_LEN: usize = 2048;
 		const BYTES_IN_LINE: usize = 16;
-		let mut buf = unsafe {Box::new_uninit_slice(file_data.len().min(MAX_LEN)).assume_init()};
+		let candidates = kidfile::probe(&mut file_data);
+		let mut buf = vec![0u8; file_data.len().min(MAX_LEN)].into_boxed_slice();
 		if file_data.read_chunk_exact(&mut buf, 0).is_ok() {
 			let mut hex = String::new();
 			for (i, line_chunk) in buf.chunks(BYTES_IN_LINE).enumerate() {
@@ -51,25 +78,39 @@ impl DataView {
 			} else {
 				hex += &format!("file displayed in full, size is 0x{:X}/{}", file_data.len(), file_data.len());
 			}
-			Self::Raw {file_data, file_name, hex, error_msg, reset_view: true}
+			Self::Raw {file_data, file_name, hex, error_msg, candidates, reset_view: true}
 		} else {
-			Self::Raw {file_data, file_name, hex: "<error reading file>".into(), error_msg, reset_view: true}
+			Self::Raw {file_data, file_name, hex: "<error reading file>".into(), error_msg, candidates, reset_view: true}
 		}
 	}
 
 	pub fn ui(&mut self, ui: &mut Ui) {
 		match self {
 			Self::None => {}
-			Self::Raw {file_data, file_name, hex, error_msg, reset_view} => {
+			Self::Raw {file_data, file_name, hex, error_msg, candidates, reset_view} => {
 				ui.vertical(|ui| {
 					ui.horizontal(|ui| {
 						ui.label(error_msg.as_str());
 						if ui.add(icon_button!("icons/edit-download.svg").small()).on_hover_text("Save").clicked() {
 							if let Some(dst) = rfd::FileDialog::new().set_file_name(file_name.to_string_lossy()).save_file() {
-								std::fs::write(dst, file_data.read()).unwrap();
+								match file_data.read() {
+									Ok(bytes) => std::fs::write(dst, bytes).unwrap(),
+									Err(e) => log!("could not read file: {e}")
+								}
 							}
 						}
 					});
+					let match_count = candidates.iter().filter(|x| x.certainty != Certainty::Impossible).count();
+					ui.collapsing(format!("Detection ({match_count} possible)"), |ui| {
+						for candidate in candidates.iter() {
+							let text = format!("{} ({}): {}", candidate.id, candidate.score, candidate.reason);
+							match candidate.certainty {
+								Certainty::Certain => ui.strong(text),
+								Certainty::Possible => ui.label(text),
+								Certainty::Impossible => ui.weak(text)
+							};
+						}
+					});
 					ScrollArea::both().id_salt("hex").show(ui, |ui| {
 						if *reset_view {
 							ui.scroll_to_cursor(Some(Align::Min));
@@ -84,7 +125,24 @@ impl DataView {
 					});
 				});
 			}
-			Self::Image(frames) => {
+			Self::Image {frames, image, current, playing, frame_start} => {
+				let now = ui.input(|x| x.time);
+				if *playing {
+					let animation = image.animation_or_default();
+					let duration = animation.duration(*current).as_secs_f64();
+					if now - *frame_start >= duration {
+						if *current + 1 < frames.len() {
+							*current += 1;
+						} else if animation.looping {
+							*current = 0;
+						} else {
+							*playing = false;
+						}
+						*frame_start = now;
+					}
+					ui.ctx().request_repaint_after(Duration::from_secs_f64((*frame_start + animation.duration(*current).as_secs_f64() - now).max(0.0)));
+				}
+				let mut new_palette = None;
 				ui.with_layout(Layout::right_to_left(Align::TOP), |ui| {
 					if frames.len() > 1 {
 						const OTHER_FRAMES_WIDTH: f32 = 160.0;
@@ -93,8 +151,8 @@ impl DataView {
 							ScrollArea::vertical().show(ui, |ui| {
 								ui.set_min_width(OTHER_FRAMES_WIDTH);
 								ui.vertical_centered_justified(|ui| {
-									for (frame_idx, (egui_img, tex, fmt)) in frames.iter().enumerate().skip(1) {
-										if frame_idx >= 2 {
+									for (frame_idx, (egui_img, tex, fmt)) in frames.iter().enumerate() {
+										if frame_idx >= 1 {
 											ui.separator();
 										}
 										ui.horizontal(|ui| {
@@ -103,29 +161,74 @@ impl DataView {
 												ui.ctx().copy_image(egui_img.clone());
 											}
 										});
-										ui.add(egui::Image::new(tex).fit_to_exact_size(Vec2::new(OTHER_FRAMES_WIDTH, OTHER_FRAMES_HEIGHT)));
+										if ui.add(egui::Image::new(tex).fit_to_exact_size(Vec2::new(OTHER_FRAMES_WIDTH, OTHER_FRAMES_HEIGHT)).sense(Sense::click())).clicked() {
+											*current = frame_idx;
+											*playing = false;
+										}
 									}
 								});
 							});
 						});
 					}
 					ui.vertical(|ui| {
+						let (egui_img, tex, fmt) = &frames[*current];
 						ui.horizontal(|ui| {
 							if frames.len() > 1 {
-								ui.label(format!("#0, {}x{}, {} ({} frames)", frames[0].0.width(), frames[0].0.height(), frames[0].2, frames.len()));
+								ui.label(format!("#{}, {}x{}, {} ({} frames)", *current, egui_img.width(), egui_img.height(), fmt, frames.len()));
 							} else {
-								ui.label(format!("{}x{}, {}", frames[0].0.width(), frames[0].0.height(), frames[0].2));
+								ui.label(format!("{}x{}, {}", egui_img.width(), egui_img.height(), fmt));
 							}
 							if ui.add(icon_button!("icons/edit-copy.svg").small()).on_hover_text("Copy to clipboard").clicked() {
-								ui.ctx().copy_image(frames[0].0.clone());
+								ui.ctx().copy_image(egui_img.clone());
 							}
 						});
+						if frames.len() > 1 {
+							ui.horizontal(|ui| {
+								if ui.add(Button::new(if *playing {"Pause"} else {"Play"}).small()).clicked() {
+									*playing = !*playing;
+									*frame_start = now;
+								}
+								if ui.add(icon_button!("icons/go-previous.svg").small()).on_hover_text("Previous frame").clicked() {
+									*current = (*current + frames.len() - 1) % frames.len();
+									*playing = false;
+								}
+								if ui.add(icon_button!("icons/go-next.svg").small()).on_hover_text("Next frame").clicked() {
+									*current = (*current + 1) % frames.len();
+									*playing = false;
+								}
+								if ui.add(Slider::new(current, 0..=frames.len() - 1).show_value(false)).changed() {
+									*playing = false;
+								}
+								let duration = image.animation_or_default().duration(*current).as_millis();
+								ui.label(if image.animation.is_some() {format!("{duration} ms")} else {format!("{duration} ms (default)")});
+								if ui.add(icon_button!("icons/edit-download.svg").small()).on_hover_text("Save as animated PNG or GIF").clicked() {
+									save_animation(image);
+								}
+							});
+						}
+						if let Some(indexed) = &image.frames[*current].indexed && indexed.palettes.len() > 1 {
+							let mut palette_idx = indexed.selected;
+							if ui.add(Slider::new(&mut palette_idx, 0..=indexed.palettes.len() - 1).prefix("Palette ")).changed() {
+								new_palette = Some(palette_idx);
+							}
+						}
 						ui.centered_and_justified(|ui| {
-							ui.add(egui::Image::new(&frames[0].1).fit_to_exact_size(ui.available_size()));
+							ui.add(egui::Image::new(tex).fit_to_exact_size(ui.available_size()));
 						});
 					});
 					ui.separator();
 				});
+				if let Some(palette_idx) = new_palette {
+					let frame = &mut image.frames[*current];
+					match frame.select_palette(palette_idx) {
+						Ok(()) => {
+							let egui_img = ColorImage::from_rgba_unmultiplied([frame.width as usize, frame.height as usize], frame.as_rgba_bytes());
+							frames[*current].1.set(egui_img.clone(), TextureOptions::LINEAR);
+							frames[*current].0 = egui_img;
+						}
+						Err(e) => log!("could not switch palette: {e}")
+					}
+				}
 			}
 		}
 	}
@@ -6,7 +6,7 @@ use complex_path::ComplexPath;
 use data_view::DataView;
 use egui::{epaint::text::{FontInsert, FontPriority, InsertFontFamily}, popup, vec2, Align, Button, CentralPanel, Context, FontData, FontFamily, Grid, Key, Label, Layout, Modifiers, PopupCloseBehavior, Pos2, Rect, ScrollArea, Separator, TextBuffer, TextStyle, TextWrapMode, TextureOptions, TopBottomPanel, Ui, UiBuilder, Vec2, ViewportBuilder, Visuals};
 use egui_dock::{DockArea, DockState, NodeIndex, SurfaceIndex, TabAddAlign, TabViewer};
-use kidfile::{auto_decode_full, DynData};
+use kidfile::{auto_decode_full, decode_as, image::AlphaMode, DecodeOptions, DynData, DEFAULT_REGISTRY};
 use rfd::FileDialog;
 use serde_json::Value;
 
@@ -136,7 +136,11 @@ struct ExplorerTab {
 	pub past: Vec<ComplexPath>,
 	pub future: Vec<ComplexPath>,
 	pub batch_task: Option<BatchDecode>,
-	pub archive_prefix_filter: Option<OsString>
+	pub archive_prefix_filter: Option<OsString>,
+	/// decoder chain being put together in the "Decode As..." popup
+	pub decode_chain: Vec<&'static str>,
+	/// when set, the selected file is decoded with exactly these decoders instead of automatically
+	pub forced_chain: Option<Vec<&'static str>>
 }
 
 impl ExplorerTab {
@@ -151,7 +155,9 @@ impl ExplorerTab {
 			past: Vec::new(),
 			future: Vec::new(),
 			batch_task: None,
-			archive_prefix_filter: None
+			archive_prefix_filter: None,
+			decode_chain: Vec::new(),
+			forced_chain: None
 		};
 		tab.refresh(ctx);
 		tab
@@ -208,27 +214,40 @@ impl ExplorerTab {
 					let in_archive = self.path.get_archive_format();
 					if let Ok(mut file_data) = self.path.load_file(&c.name) {
 						let mut len = file_data.len();
-						let decoded = auto_decode_full(file_data.to_mut(), in_archive);
+						let decoded = if let Some(chain) = &self.forced_chain {
+							decode_as(file_data.to_mut(), chain, &DecodeOptions::default())
+						} else {
+							auto_decode_full(file_data.to_mut(), in_archive, &DecodeOptions::default())
+						};
 						match decoded.data {
 							DynData::Raw(raw_data) => {
+								let error_msg = decoded.error.as_ref().map_or_else(String::new, |e| e.to_string());
 								let msg = if decoded.steps_taken.is_empty() {
-									format!("no steps taken; {}", decoded.error_msg)
+									format!("no steps taken; {}", error_msg)
 								} else {
-									format!("steps taken: {}; {}", decoded.steps_taken.join(" -> "), decoded.error_msg)
+									format!("steps taken: {}; {}", decoded.steps_taken.join(" -> "), error_msg)
 								};
 								len = raw_data.len();
 								self.view = DataView::new_raw(raw_data, c.name.clone(), msg);
 							}
-							DynData::Image(ref img) =>{
+							DynData::Image(img) => {
 								let mut frames = Vec::new();
 								for frame in &img.frames {
 									let egui_img = egui::ColorImage::from_rgba_unmultiplied([frame.width as usize, frame.height as usize], frame.as_rgba_bytes());
-									frames.push((egui_img.clone(), ctx.load_texture("image", egui_img, TextureOptions::LINEAR), frame.og_fmt));
+									let mut desc = frame.og_fmt.to_string();
+									if frame.alpha_mode != AlphaMode::Full {
+										desc += &format!(", {}", frame.alpha_mode);
+									}
+									if !frame.placement.is_empty() {
+										desc += &format!(", {}", frame.placement);
+									}
+									frames.push((egui_img.clone(), ctx.load_texture("image", egui_img, TextureOptions::LINEAR), desc));
 								}
-								self.view = DataView::Image(frames);
+								self.view = DataView::Image {frames, image: img, current: 0, playing: false, frame_start: 0.0};
 							}
 							DynData::Archive(arc) => {
 								self.selection = None;
+								self.forced_chain = None;
 								self.path.append_archive(&c.name, arc);
 								self.refresh(ctx);
 								return;
@@ -254,6 +273,7 @@ impl ExplorerTab {
 	pub fn select(&mut self, ctx: &Context, idx: usize) {
 		if idx < self.children.len() {
 			self.selection = Some((idx, Vec::new()));
+			self.forced_chain = None;
 			self.refresh(ctx);
 		} else {
 			self.selection = None;
@@ -500,6 +520,54 @@ impl<'a> TabViewer for ExplorerTabViewer<'a> {
 								tab.batch_task = Some(task);
 							}
 						}
+						let can_decode_as = tab.selection.as_ref().is_some_and(|x| !tab.children[x.0].is_dir);
+						let decode_as_response = ui.add_enabled(can_decode_as, Button::new("Decode As...").small());
+						let popup_id = ui.make_persistent_id("decode as popup");
+						if decode_as_response.clicked() {
+							tab.decode_chain = tab.forced_chain.clone().unwrap_or_default();
+							ui.memory_mut(|mem| mem.toggle_popup(popup_id));
+						}
+						popup::popup_below_widget(
+							ui,
+							popup_id,
+							&decode_as_response,
+							PopupCloseBehavior::CloseOnClickOutside,
+							|ui| {
+								ui.set_width(LIST_WIDTH);
+								if tab.decode_chain.is_empty() {
+									ui.label("click decoders to build a chain");
+								} else {
+									ui.label(tab.decode_chain.join(" -> "));
+								}
+								ui.horizontal(|ui| {
+									if ui.add_enabled(!tab.decode_chain.is_empty(), Button::new("Apply").small()).clicked() {
+										tab.forced_chain = Some(tab.decode_chain.clone());
+										log!("decoding as {}", tab.decode_chain.join(" -> "));
+										tab.refresh(ui.ctx());
+										ui.memory_mut(|mem| mem.close_popup());
+									}
+									if ui.add_enabled(!tab.decode_chain.is_empty(), Button::new("Clear").small()).clicked() {
+										tab.decode_chain.clear();
+									}
+									if ui.add_enabled(tab.forced_chain.is_some(), Button::new("Automatic").small()).clicked() {
+										tab.forced_chain = None;
+										tab.refresh(ui.ctx());
+										ui.memory_mut(|mem| mem.close_popup());
+									}
+								});
+								ui.separator();
+								ScrollArea::vertical().max_height(320.0).show(ui, |ui| {
+									ui.with_layout(Layout::top_down_justified(Align::Min), |ui| {
+										for id in DEFAULT_REGISTRY.ids() {
+											let desc = DEFAULT_REGISTRY.desc(id).unwrap_or_default();
+											if ui.add(Button::new(id).frame(false)).on_hover_text(desc).clicked() {
+												tab.decode_chain.push(id);
+											}
+										}
+									});
+								});
+							}
+						);
 					});
 					ui.separator();
 					ui.spacing_mut().button_padding.y = 0.0;
@@ -538,7 +606,7 @@ impl<'a> TabViewer for ExplorerTabViewer<'a> {
 				ui.centered_and_justified(|ui| {
 					match tab.view {
 						DataView::None => {}
-						DataView::Image(..) => {
+						DataView::Image {..} => {
 							ui.add(image16!("icons/image-x-generic.svg"));
 						}
 						DataView::Raw {..} => {
@@ -1,15 +1,17 @@
-use crate::{Certainty, Decoder};
+use crate::{Decoder, Detection, Error, HeaderInfo, Progress};
 use super::{Archive, ArchiveEntry};
 
 pub const ENTRY_AFS: Decoder<Archive> = Decoder {
 	id: "afs",
 	desc: "CRI AFS archive used in most KID games",
-	detect: |file| Certainty::certain_if(file.starts_with(b"AFS\0")),
-	decode: |file| {
+	detect: |file| Detection::magic(file, b"AFS\0", 0),
+	identify: |file| HeaderInfo {entry_count: file.get_u32_at(4).map(|x| x as usize), ..Default::default()},
+	decode: |file, opts| {
 		let count = file.read_u32(4)? as usize;
 		if count >= 0xFFFF {
-			return Err("impossibly large entry count".into());
+			return Err(Error::bad_field(format!("impossibly large entry count {count}")).at(4));
 		}
+		opts.check_entries(count).map_err(|e| e.at(4))?;
 		let mut entry_ranges = Vec::with_capacity(count);
 		let mut entries = Vec::with_capacity(count);
 		let mut end = 0;
@@ -22,14 +24,15 @@ pub const ENTRY_AFS: Decoder<Archive> = Decoder {
 		end = end.next_multiple_of(0x800);
 		let info_present = file.len() > end;
 		for i in 0..count {
+			opts.poll(Progress::Entries {done: i, total: count})?;
 			let name;
 			let timestamp;
 			if info_present {
 				let pos = end as usize + i * 48;
 				let mut name_buf = [0u8; 32];
-				file.read_chunk_exact(&mut name_buf, pos).map_err(|_| "could not read entry name")?;
+				file.read_chunk_exact(&mut name_buf, pos).map_err(|e| Error {msg: "could not read entry name".into(), ..e})?;
 				let len = name_buf.iter().position(|x| *x == 0).unwrap_or(32);
-				name = String::from_utf8(name_buf[0..len].to_vec()).map_err(|_| "entry name is not valid UTF-8")?;
+				name = String::from_utf8(name_buf[0..len].to_vec()).map_err(|_| Error::corrupt("entry name is not valid UTF-8").at(pos))?;
 
 				let year = file.read_u16(pos + 32)?;
 				let month = file.read_u16(pos + 34)?;
@@ -43,7 +46,7 @@ pub const ENTRY_AFS: Decoder<Archive> = Decoder {
 				timestamp = None;
 			}
 			entries.push(ArchiveEntry {
-				data: file.subfile(entry_ranges[i].0, entry_ranges[i].1).unwrap(),
+				data: file.subfile(entry_ranges[i].0, entry_ranges[i].1)?,
 				name,
 				timestamp
 			});
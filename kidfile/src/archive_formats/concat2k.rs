@@ -1,4 +1,4 @@
-use crate::{Certainty, Decoder};
+use crate::{file_data::FileData, Decoder, Detection, Error, HeaderInfo, Progress};
 use super::{Archive, ArchiveEntry};
 
 // it's annoying that this has to exist, but here we go
@@ -6,56 +6,84 @@ use super::{Archive, ArchiveEntry};
 // here we simply use the heuristic of checking for TIM2 and OGDT signatures every 2048 bytes
 
 const ALIGNMENT: usize = 2048;
+// read this much at once instead of seeking to every boundary, must be a multiple of the alignment
+const SCAN_CHUNK: usize = 32 * ALIGNMENT;
+// detection only scans this far, so that it stays cheap on big files. first entries are rarely this large,
+// but when there's more file past it that wasn't scanned, detection can't rule it out. those files get a low score
+// instead, so they are only decoded (which scans everything) once the likelier formats have failed
+const DETECT_SCAN_LIMIT: usize = 4 * 1024 * 1024;
 
 pub const ENTRY_CONCAT2K: Decoder<Archive> = Decoder {
 	id: "concat2k",
 	desc: "Not an actual format, just concatenated images aligned to 2048 bytes",
-	detect: |file| Certainty::possible_if(file.len() > ALIGNMENT * 2 && !file.starts_with(b"\0\0\x01\xBA") && {
-		let mut boundary = ALIGNMENT;
-		loop {
-			let mut check_buf = [0u8; 8];
-			if file.read_chunk_exact(&mut check_buf, boundary).is_err() {
-				break false;
-			}
-			if check_signature(&check_buf) {
-				break true;
-			}
-			boundary += ALIGNMENT;
+	detect: |file| {
+		if file.len() <= ALIGNMENT * 2 {
+			return Detection::impossible("not larger than two blocks");
+		}
+		if file.starts_with(b"\0\0\x01\xBA") {
+			return Detection::impossible("MPEG program stream");
 		}
-	}),
-	decode: |file| {
+		match find_signature(file, ALIGNMENT, DETECT_SCAN_LIMIT) {
+			Some(boundary) => Detection::possible(format!("image signature at boundary {boundary:#X}")).with_score(30),
+			None if file.len() > DETECT_SCAN_LIMIT => Detection::possible(format!("no image signature in the first {DETECT_SCAN_LIMIT:#X} bytes, the rest was not scanned")).with_score(5),
+			None => Detection::impossible("no image signature at any 2048 byte boundary")
+		}
+	},
+	// counting the entries means scanning the whole file
+	identify: |_| HeaderInfo::default(),
+	decode: |file, opts| {
 		let mut cur_entry_start = 0;
-		let mut boundary = ALIGNMENT;
+		let mut scan_pos = ALIGNMENT;
 		let mut entries = Vec::new();
 		loop {
-			let mut check_buf = [0u8; 8];
-			if file.read_chunk_exact(&mut check_buf, boundary).is_err() {
+			opts.poll(Progress::Bytes {done: scan_pos, total: file.len()})?;
+			if let Some(boundary) = find_signature(file, scan_pos, scan_pos + SCAN_CHUNK) {
+				opts.check_entries(entries.len() + 1).map_err(|e| e.at(boundary))?;
 				let name = entries.len().to_string();
 				entries.push(ArchiveEntry {
 					name: name.clone(),
-					data: file.subfile(cur_entry_start, file.len() - cur_entry_start).unwrap(),
+					data: file.subfile(cur_entry_start, boundary - cur_entry_start)?,
 					timestamp: None
 				});
-				if entries.len() > 1 {
-					return Ok(Archive {format: "concat2k", entries: entries.into()});
-				} else {
-					return Err("could not find multiple entries".into());
-				}
-			}
-			if check_signature(&check_buf) {
+				cur_entry_start = boundary;
+				scan_pos = boundary + ALIGNMENT;
+			} else if scan_pos + SCAN_CHUNK < file.len() {
+				scan_pos += SCAN_CHUNK;
+			} else {
 				let name = entries.len().to_string();
 				entries.push(ArchiveEntry {
 					name: name.clone(),
-					data: file.subfile(cur_entry_start, boundary - cur_entry_start).unwrap(),
+					data: file.subfile(cur_entry_start, file.len() - cur_entry_start)?,
 					timestamp: None
 				});
-				cur_entry_start = boundary;
+				if entries.len() > 1 {
+					return Ok(Archive {format: "concat2k", entries: entries.into()});
+				} else {
+					return Err(Error::bad_magic("could not find multiple entries"));
+				}
 			}
-			boundary += ALIGNMENT;
 		}
 	}
 };
 
+/// Finds the first boundary from `start` (which must be aligned) that has an image signature, looking only before `limit`.
+fn find_signature(file: &mut FileData, start: usize, limit: usize) -> Option<usize> {
+	let end = file.len().min(limit);
+	let mut chunk = vec![0u8; SCAN_CHUNK];
+	let mut chunk_start = start;
+	while chunk_start + 8 <= end {
+		let chunk_len = SCAN_CHUNK.min(end - chunk_start);
+		file.read_chunk_exact(&mut chunk[..chunk_len], chunk_start).ok()?;
+		for boundary in (0..chunk_len - 7).step_by(ALIGNMENT) {
+			if check_signature(&chunk[boundary..boundary + 8]) {
+				return Some(chunk_start + boundary);
+			}
+		}
+		chunk_start += chunk_len;
+	}
+	None
+}
+
 fn check_signature(buf: &[u8]) -> bool {
 	for sig in [b"ogdt", b"TIM2"] {
 		if buf.starts_with(sig) || buf[4..].starts_with(sig) {
@@ -63,4 +91,34 @@ fn check_signature(buf: &[u8]) -> bool {
 		}
 	}
 	false
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{Certainty, DecodeOptions};
+
+	#[test]
+	fn signature_past_scan_limit() {
+		let mut buf = vec![0u8; DETECT_SCAN_LIMIT + ALIGNMENT * 4];
+		let far = DETECT_SCAN_LIMIT + ALIGNMENT * 2;
+		buf[far..far + 4].copy_from_slice(b"TIM2");
+		let mut file = FileData::Memory {buf: buf.into()};
+		let detection = (ENTRY_CONCAT2K.detect)(&mut file);
+		assert_eq!(detection.certainty, Certainty::Possible);
+		assert!(detection.score < 30);
+		let archive = (ENTRY_CONCAT2K.decode)(&mut file, &DecodeOptions::default()).unwrap();
+		assert_eq!(archive.entries.len(), 2);
+		assert_eq!(archive.entries[1].data.len(), ALIGNMENT * 2);
+	}
+
+	#[test]
+	fn signature_in_scan_limit() {
+		let mut buf = vec![0u8; ALIGNMENT * 4];
+		buf[ALIGNMENT * 2 + 4..ALIGNMENT * 2 + 8].copy_from_slice(b"ogdt");
+		let detection = (ENTRY_CONCAT2K.detect)(&mut FileData::Memory {buf: buf.into()});
+		assert_eq!((detection.certainty, detection.score), (Certainty::Possible, 30));
+		let detection = (ENTRY_CONCAT2K.detect)(&mut FileData::Memory {buf: vec![0u8; ALIGNMENT * 4].into()});
+		assert_eq!(detection.certainty, Certainty::Impossible);
+	}
+}
@@ -1,10 +1,12 @@
 use std::{fs::File, io::{Read, Seek, SeekFrom}};
-use crate::{file_data::FileData, Certainty, Decoder};
+use crate::{Decoder, Detection, Error, HeaderInfo, Progress};
 use super::{Archive, ArchiveEntry};
 
 // based on code at https://subversion.assembla.com/svn/transprojects/psx/infinity/tools/code/
 // no, i have no idea who made that
 
+const ENTRY_COUNT: usize = 0xEFC;
+
 pub const ENTRY_SLPS02669_DATABIN: Decoder<Archive> = Decoder {
 	id: "databin",
 	desc: "SLPS-02669 archive",
@@ -12,36 +14,41 @@ pub const ENTRY_SLPS02669_DATABIN: Decoder<Archive> = Decoder {
 		if let Some(p) = file.physical_path() {
 			if let Some(file_name) = p.file_name().map(|x| x.to_ascii_lowercase()) {
 				if file_name == "data.bin" && p.with_file_name("slps_026.69").exists() {
-					return Certainty::Certain;
+					return Detection::certain("data.bin next to slps_026.69");
 				}
 			}
 		}
-		Certainty::Impossible
+		Detection::impossible("not data.bin next to slps_026.69")
 	},
-	decode: |file| {
+	identify: |_| HeaderInfo {entry_count: Some(ENTRY_COUNT), ..Default::default()},
+	decode: |file, opts| {
 		if let Some(data_bin_path) = file.physical_path() {
 			if let Some(file_name) = data_bin_path.file_name().map(|x| x.to_ascii_lowercase()) {
 				if file_name == "data.bin" {
 					if let Ok(mut slps) = File::open(data_bin_path.with_file_name("slps_026.69")) {
-						slps.seek( SeekFrom::Start(0x523E8)).map_err(|_| "error while reading slps_026.69")?;
+						opts.check_entries(ENTRY_COUNT)?;
+						slps.seek( SeekFrom::Start(0x523E8)).map_err(|_| Error::io("error while reading slps_026.69"))?;
 						let mut entry_name = [0u8; 255];
 						let mut entries = Vec::new();
-						for _ in 0..0xEFC {
+						for i in 0..ENTRY_COUNT {
+							opts.poll(Progress::Entries {done: i, total: ENTRY_COUNT})?;
 							let mut name_pos = 0u32;
 							let mut sector = 0u32;
 							let mut size = 0u32;
-							slps.read_exact(bytemuck::bytes_of_mut(&mut name_pos)).map_err(|_| "error while reading slps_026.69")?;
-							slps.read_exact(bytemuck::bytes_of_mut(&mut sector)).map_err(|_| "error while reading slps_026.69")?;
-							slps.read_exact(bytemuck::bytes_of_mut(&mut size)).map_err(|_| "error while reading slps_026.69")?;
+							slps.read_exact(bytemuck::bytes_of_mut(&mut name_pos)).map_err(|_| Error::io("error while reading slps_026.69"))?;
+							slps.read_exact(bytemuck::bytes_of_mut(&mut sector)).map_err(|_| Error::io("error while reading slps_026.69"))?;
+							slps.read_exact(bytemuck::bytes_of_mut(&mut size)).map_err(|_| Error::io("error while reading slps_026.69"))?;
 
-							let pos_bak = slps.stream_position().map_err(|_| "error while reading slps_026.69")?;
-							slps.seek(SeekFrom::Start(name_pos as u64 - 0x8000F800)).map_err(|_| "error while reading slps_026.69")?;
-							slps.read_exact(&mut entry_name).map_err(|_| "error while reading slps_026.69")?;
-							slps.seek(SeekFrom::Start(pos_bak)).map_err(|_| "error while reading slps_026.69")?;
+							let pos_bak = slps.stream_position().map_err(|_| Error::io("error while reading slps_026.69"))?;
+							let name_offset = (name_pos as u64).checked_sub(0x8000F800).ok_or_else(|| Error::corrupt("entry name pointer in slps_026.69 is out of range"))?;
+							slps.seek(SeekFrom::Start(name_offset)).map_err(|_| Error::io("error while reading slps_026.69"))?;
+							slps.read_exact(&mut entry_name).map_err(|_| Error::io("error while reading slps_026.69"))?;
+							slps.seek(SeekFrom::Start(pos_bak)).map_err(|_| Error::io("error while reading slps_026.69"))?;
 
 							entries.push(ArchiveEntry {
-								name: std::str::from_utf8(&entry_name).map_err(|_| "error while reading entry name from data.bin")?.into(),
-								data: FileData::Stream {path: data_bin_path.clone(), file: None, start: sector as usize * 2048, size: size as usize},
+								name: std::str::from_utf8(&entry_name).map_err(|_| Error::corrupt("entry name from slps_026.69 is not valid UTF-8"))?.into(),
+								// shares the handle or mapping of data.bin
+								data: file.subfile(sector as usize * 2048, size as usize)?,
 								timestamp: None
 							});
 						}
@@ -50,11 +57,11 @@ pub const ENTRY_SLPS02669_DATABIN: Decoder<Archive> = Decoder {
 							entries: entries.into()
 						});
 					} else {
-						return Err("data.bin must be accompanied by slps_026.69".into());
+						return Err(Error::io("data.bin must be accompanied by slps_026.69"));
 					}
 				}
 			}
 		}
-		Err("not a data.bin file".into())
+		Err(Error::unsupported("not a data.bin file"))
 	}
 };
\ No newline at end of file
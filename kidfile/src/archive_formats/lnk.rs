@@ -1,34 +1,124 @@
-use crate::{Certainty, Decoder};
+use crate::{data_formats::decompress_lnd, DecodeOptions, Decoder, Detection, Error, HeaderInfo, Progress};
 use super::{Archive, ArchiveEntry};
 
+// compressed entries have a 16-byte header of "lnd\0", something, the unpacked size, and something else
+const LND_HEADER_SIZE: usize = 16;
+
+// entries are decompressed when read, with the options the archive was opened with
+fn decompress_entry(buf: &[u8], unpacked_size: usize, opts: &DecodeOptions) -> Result<Box<[u8]>, Error> {
+	// small entries finish before decompress_lnd first polls
+	opts.check_cancelled()?;
+	let mut out = decompress_lnd(buf, unpacked_size, opts).map_err(|e| Error {offset: e.offset.map(|x| x + LND_HEADER_SIZE), ..e})?;
+	if out.len() < unpacked_size {
+		return Err(Error::size_mismatch(format!("wrong unpacked size after lnd decompression, expected {unpacked_size}, got {}", out.len())));
+	}
+	out.truncate(unpacked_size);
+	Ok(out.into_boxed_slice())
+}
+
 pub const ENTRY_LNK: Decoder<Archive> = Decoder {
 	id: "lnk",
 	desc: "KID PC archive",
-	detect: |file| Certainty::certain_if(file.starts_with(b"LNK\0")),
-	decode: |file| {
+	detect: |file| Detection::magic(file, b"LNK\0", 0),
+	identify: |file| HeaderInfo {entry_count: file.get_u32_at(4).map(|x| x as usize), ..Default::default()},
+	decode: |file, opts| {
 		let count = file.read_u32(4)? as usize;
 		if count >= 0xFFFF {
-			return Err("impossibly large entry count".into());
+			return Err(Error::bad_field(format!("impossibly large entry count {count}")).at(4));
 		}
+		opts.check_entries(count).map_err(|e| e.at(4))?;
 		let mut entries = Vec::with_capacity(count);
 		let mut index_ptr = 16;
 		let data_section_start = 16 + count * 32;
-		for _ in 0..count {
+		for i in 0..count {
+			opts.poll(Progress::Entries {done: i, total: count})?;
 			let offset = file.read_u32(index_ptr)?;
 			let mut len = file.read_u32(index_ptr + 4)?;
 			let is_compressed = len & 1 != 0;
 			len >>= 1;
+			let entry_start = data_section_start + offset as usize;
 			let mut name_buf = [0u8; 24];
-			file.read_chunk_exact(&mut name_buf, index_ptr + 8).map_err(|_| "could not read entry name")?;
-			let name_len = name_buf.iter().position(|x| *x == 0).unwrap_or(32);
-			let name = String::from_utf8(name_buf[0..name_len].to_vec()).map_err(|_| "entry name is not valid UTF-8")?;
-			entries.push(ArchiveEntry {
-				name: name.clone(),
-				data: file.subfile(data_section_start + offset as usize, len as usize).unwrap(),
-				timestamp: None
-			});
+			file.read_chunk_exact(&mut name_buf, index_ptr + 8).map_err(|e| Error {msg: "could not read entry name".into(), ..e})?;
+			let name_len = name_buf.iter().position(|x| *x == 0).unwrap_or(name_buf.len());
+			let name = String::from_utf8(name_buf[0..name_len].to_vec()).map_err(|_| Error::corrupt("entry name is not valid UTF-8").at(index_ptr + 8))?;
+			let data = if is_compressed && file.starts_with_at(b"lnd\0", entry_start) && len as usize >= LND_HEADER_SIZE {
+				let unpacked_size = file.read_u32(entry_start + 8)? as usize;
+				opts.check_output(unpacked_size).map_err(|e| e.at(entry_start + 8))?;
+				let opts = opts.clone();
+				file.subfile(entry_start + LND_HEADER_SIZE, len as usize - LND_HEADER_SIZE)?
+					.into_compressed(unpacked_size, move |buf, size| decompress_entry(buf, size, &opts))?
+			} else if is_compressed {
+				// the packed bytes would look like a valid entry, so reading it fails instead
+				file.subfile(entry_start, len as usize)?
+					.into_compressed(len as usize, |_, _| Err(Error::corrupt("entry is marked as compressed, but has no lnd header")))?
+			} else {
+				file.subfile(entry_start, len as usize)?
+			};
+			entries.push(ArchiveEntry {name, data, timestamp: None});
 			index_ptr += 32;
 		}
 		Ok(Archive {format: "lnk", entries: entries.into()})
 	}
-};
\ No newline at end of file
+};
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{file_data::FileData, CancelToken, ErrorKind};
+
+	// one entry of each kind: stored, lnd compressed, and marked compressed without the lnd header
+	fn test_archive() -> Vec<u8> {
+		let stored = b"stored entry".to_vec();
+		// a run of 10 'a's then 3 literal bytes
+		let mut compressed = b"lnd\0\0\0\0\0".to_vec();
+		compressed.extend(13u32.to_le_bytes());
+		compressed.extend([0; 4]);
+		compressed.extend([0xC0 | 8, b'a', 2, b'x', b'y', b'z']);
+		let headerless = b"not lnd data".to_vec();
+		let entries = [("stored", &stored, false), ("compressed", &compressed, true), ("headerless", &headerless, true)];
+		let mut out = b"LNK\0".to_vec();
+		out.extend((entries.len() as u32).to_le_bytes());
+		out.extend([0; 8]);
+		let mut offset = 0;
+		for (name, data, is_compressed) in entries {
+			out.extend((offset as u32).to_le_bytes());
+			out.extend(((data.len() as u32) << 1 | is_compressed as u32).to_le_bytes());
+			let mut name_buf = [0u8; 24];
+			name_buf[..name.len()].copy_from_slice(name.as_bytes());
+			out.extend(name_buf);
+			offset += data.len();
+		}
+		for (_, data, _) in entries {
+			out.extend(data.iter());
+		}
+		out
+	}
+
+	fn open(opts: &DecodeOptions) -> Archive {
+		(ENTRY_LNK.decode)(&mut FileData::Memory {buf: test_archive().into()}, opts).unwrap()
+	}
+
+	#[test]
+	fn compressed_entries() {
+		let mut archive = open(&DecodeOptions::default());
+		let names = archive.entries.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+		assert_eq!(names, ["stored", "compressed", "headerless"]);
+		assert_eq!(archive.entries[0].data.read().unwrap(), b"stored entry");
+		assert_eq!(archive.entries[1].data.len(), 13);
+		assert_eq!(archive.entries[1].data.read().unwrap(), b"aaaaaaaaaaxyz");
+		let error = archive.entries[2].data.read().err().unwrap();
+		assert_eq!(error.kind, ErrorKind::Corrupt);
+	}
+
+	#[test]
+	fn entries_keep_options() {
+		let opts = DecodeOptions {max_output_bytes: 12, ..Default::default()};
+		let error = (ENTRY_LNK.decode)(&mut FileData::Memory {buf: test_archive().into()}, &opts).err().unwrap();
+		assert_eq!(error.kind, ErrorKind::LimitExceeded);
+		// cancelling after opening stops the decompression of entries read later
+		let cancel = CancelToken::new();
+		let mut archive = open(&DecodeOptions::default().with_cancel(cancel.clone()));
+		cancel.cancel();
+		assert_eq!(archive.entries[1].data.read().err().unwrap().kind, ErrorKind::Cancelled);
+	}
+}
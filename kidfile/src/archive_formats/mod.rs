@@ -18,7 +18,7 @@ pub struct Archive {
 	pub entries: Box<[ArchiveEntry]>
 }
 
-pub const ARCHIVE_DECODERS: LazyLock<Vec<Decoder<Archive>>> = LazyLock::new(|| [
+pub static ARCHIVE_DECODERS: LazyLock<Vec<Decoder<Archive>>> = LazyLock::new(|| [
 	afs::ENTRY_AFS,
 	lnk::ENTRY_LNK,
 	concat2k::ENTRY_CONCAT2K,
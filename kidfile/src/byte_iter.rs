@@ -1,5 +1,3 @@
-use std::mem::MaybeUninit;
-
 pub trait ByteIter: Iterator<Item = u8> + ExactSizeIterator {
 	fn next_bytes<const LEN: usize>(&mut self) -> Option<[u8; LEN]>;
 	fn next_u16(&mut self) -> Option<u16>;
@@ -36,7 +34,7 @@ macro_rules! impl_for_types {
 impl<T: Iterator<Item = u8> + ExactSizeIterator> ByteIter for T {
 	fn next_bytes<const LEN: usize>(&mut self) -> Option<[u8; LEN]> {
 		if LEN <= self.len() {
-			let mut arr = unsafe {MaybeUninit::<[u8; LEN]>::uninit().assume_init()};
+			let mut arr = [0u8; LEN];
 			for (i, x) in self.take(LEN).enumerate() {
 				arr[i] = x;
 			}
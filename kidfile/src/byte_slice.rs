@@ -1,26 +1,28 @@
+use crate::{swizzle::unswizzle_psp, Error};
+
 pub trait ByteSlice {
 	fn starts_with_at(&self, needle: &[u8], offset: usize) -> bool;
-	fn read_bytes(&self, offset: usize, len: usize, name: &str) -> Result<&[u8], String>;
-	fn read_u8(&self, offset: usize) -> Result<u8, String>;
-	fn read_u16(&self, offset: usize) -> Result<u16, String>;
-	fn read_u32(&self, offset: usize) -> Result<u32, String>;
-	fn read_u64(&self, offset: usize) -> Result<u64, String>;
-	fn read_usize(&self, offset: usize) -> Result<usize, String>;
-	fn read_i8(&self, offset: usize) -> Result<i8, String>;
-	fn read_i16(&self, offset: usize) -> Result<i16, String>;
-	fn read_i32(&self, offset: usize) -> Result<i32, String>;
-	fn read_i64(&self, offset: usize) -> Result<i64, String>;
-	fn read_isize(&self, offset: usize) -> Result<isize, String>;
-	fn read_u8_be(&self, offset: usize) -> Result<u8, String>;
-	fn read_u16_be(&self, offset: usize) -> Result<u16, String>;
-	fn read_u32_be(&self, offset: usize) -> Result<u32, String>;
-	fn read_u64_be(&self, offset: usize) -> Result<u64, String>;
-	fn read_usize_be(&self, offset: usize) -> Result<usize, String>;
-	fn read_i8_be(&self, offset: usize) -> Result<i8, String>;
-	fn read_i16_be(&self, offset: usize) -> Result<i16, String>;
-	fn read_i32_be(&self, offset: usize) -> Result<i32, String>;
-	fn read_i64_be(&self, offset: usize) -> Result<i64, String>;
-	fn read_isize_be(&self, offset: usize) -> Result<isize, String>;
+	fn read_bytes(&self, offset: usize, len: usize, name: &str) -> Result<&[u8], Error>;
+	fn read_u8(&self, offset: usize) -> Result<u8, Error>;
+	fn read_u16(&self, offset: usize) -> Result<u16, Error>;
+	fn read_u32(&self, offset: usize) -> Result<u32, Error>;
+	fn read_u64(&self, offset: usize) -> Result<u64, Error>;
+	fn read_usize(&self, offset: usize) -> Result<usize, Error>;
+	fn read_i8(&self, offset: usize) -> Result<i8, Error>;
+	fn read_i16(&self, offset: usize) -> Result<i16, Error>;
+	fn read_i32(&self, offset: usize) -> Result<i32, Error>;
+	fn read_i64(&self, offset: usize) -> Result<i64, Error>;
+	fn read_isize(&self, offset: usize) -> Result<isize, Error>;
+	fn read_u8_be(&self, offset: usize) -> Result<u8, Error>;
+	fn read_u16_be(&self, offset: usize) -> Result<u16, Error>;
+	fn read_u32_be(&self, offset: usize) -> Result<u32, Error>;
+	fn read_u64_be(&self, offset: usize) -> Result<u64, Error>;
+	fn read_usize_be(&self, offset: usize) -> Result<usize, Error>;
+	fn read_i8_be(&self, offset: usize) -> Result<i8, Error>;
+	fn read_i16_be(&self, offset: usize) -> Result<i16, Error>;
+	fn read_i32_be(&self, offset: usize) -> Result<i32, Error>;
+	fn read_i64_be(&self, offset: usize) -> Result<i64, Error>;
+	fn read_isize_be(&self, offset: usize) -> Result<isize, Error>;
 	fn get_u8_at(&self, offset: usize) -> Option<u8>;
 	fn get_u16_at(&self, offset: usize) -> Option<u16>;
 	fn get_u32_at(&self, offset: usize) -> Option<u32>;
@@ -46,11 +48,11 @@ pub trait ByteSlice {
 
 macro_rules! impl_for_types {
 	($($t:ty),*) => {paste::paste! {$(
-		fn [<read_ $t>](&self, offset: usize) -> Result<$t, String> {
-			Ok($t::from_le_bytes(self.get(offset..offset + size_of::<$t>()).ok_or_else(#[cold] || format!("error reading field"))?.try_into().unwrap()))
+		fn [<read_ $t>](&self, offset: usize) -> Result<$t, Error> {
+			Ok($t::from_le_bytes(self.get(offset..offset + size_of::<$t>()).ok_or_else(|| Error::truncated(concat!("could not read ", stringify!($t))).at(offset))?.try_into().unwrap()))
 		}
-		fn [<read_ $t _be>](&self, offset: usize) -> Result<$t, String> {
-			Ok($t::from_be_bytes(self.get(offset..offset + size_of::<$t>()).ok_or_else(#[cold] || format!("error reading field"))?.try_into().unwrap()))
+		fn [<read_ $t _be>](&self, offset: usize) -> Result<$t, Error> {
+			Ok($t::from_be_bytes(self.get(offset..offset + size_of::<$t>()).ok_or_else(|| Error::truncated(concat!("could not read ", stringify!($t))).at(offset))?.try_into().unwrap()))
 		}
 		fn [<get_ $t _at>](&self, offset: usize) -> Option<$t> {
 			Some($t::from_le_bytes(self.get(offset..offset + size_of::<$t>())?.try_into().unwrap()))
@@ -66,26 +68,195 @@ impl ByteSlice for [u8] {
 		self.get(offset..offset + needle.len()).map_or(false, |x| x.starts_with(needle))
 	}
 
-	fn read_bytes(&self, offset: usize, len: usize, name: &str) -> Result<&[u8], String> {
-		Ok(self.get(offset..offset + len).ok_or_else(#[cold] || format!("error reading {name}"))?.try_into().unwrap())
+	fn read_bytes(&self, offset: usize, len: usize, name: &str) -> Result<&[u8], Error> {
+		Ok(self.get(offset..offset + len).ok_or_else(|| Error::truncated(format!("could not read {name}")).at(offset))?.try_into().unwrap())
 	}
 
 	impl_for_types!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
 
 	fn unswizzled_psp(&self, width_bytes: u32, height: u32) -> Vec<u8> {
-		let mut pixels = Vec::with_capacity(self.len());
-		let blocks_per_row = width_bytes / 16;
-		for y in 0..height {
-			for x in 0..width_bytes {
-				let block_idx_x = x / 16;
-				let block_idx_y = y / 8;
-				let x_in_block = x % 16;
-				let y_in_block = y % 8;
-				let block_start = (block_idx_y * blocks_per_row + block_idx_x) * (16 * 8);
-				let swizzled_idx = block_start + y_in_block * 16 + x_in_block;
-				pixels.push(self.get(swizzled_idx as usize).cloned().unwrap_or_default());
+		unswizzle_psp(self, width_bytes as usize, height as usize)
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endian {
+	Little,
+	Big
+}
+
+/// A value that can be a field of a [`binary_struct!`](crate::binary_struct).
+pub trait BinaryField: Copy + std::fmt::Debug {
+	const SIZE: usize;
+	/// `bytes` is always exactly `SIZE` long
+	fn from_bytes(bytes: &[u8], endian: Endian) -> Self;
+	/// how the value is shown in dumps
+	fn dump(&self) -> String {
+		format!("{self:?}")
+	}
+}
+
+macro_rules! impl_binary_field {
+	($($t:ty),*) => {$(
+		impl BinaryField for $t {
+			const SIZE: usize = size_of::<$t>();
+			fn from_bytes(bytes: &[u8], endian: Endian) -> Self {
+				match endian {
+					Endian::Little => <$t>::from_le_bytes(bytes.try_into().unwrap()),
+					Endian::Big => <$t>::from_be_bytes(bytes.try_into().unwrap())
+				}
+			}
+		}
+	)*}
+}
+
+impl_binary_field!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+// for magic numbers and fixed-size names
+impl<const N: usize> BinaryField for [u8; N] {
+	const SIZE: usize = N;
+	fn from_bytes(bytes: &[u8], _: Endian) -> Self {
+		bytes.try_into().unwrap()
+	}
+
+	fn dump(&self) -> String {
+		format!("\"{}\"", self.escape_ascii())
+	}
+}
+
+/// Reads one field of a [`binary_struct!`](crate::binary_struct) at `pos` in `buf`, naming it in the error if it's out of range.
+/// `shown_pos` is the offset given to the error, which differs when `buf` is a chunk read from further into a file.
+#[doc(hidden)]
+pub fn read_field<T: BinaryField>(buf: &[u8], pos: usize, shown_pos: usize, endian: Endian, desc: &str, name: &str) -> Result<T, Error> {
+	let bytes = buf.get(pos..).and_then(|x| x.get(..T::SIZE))
+		.ok_or_else(|| Error::truncated(format!("{desc}: field `{name}` out of range")).at(shown_pos))?;
+	Ok(T::from_bytes(bytes, endian))
+}
+
+/// Declares a struct read from fixed offsets, like a file header, naming each field in errors and dumps.
+/// Each field is written as `offset => name: type`, offsets being from the start of the struct.
+///
+/// ```
+/// use kidfile::binary_struct;
+///
+/// binary_struct! {
+///     struct ImageHeader("gim image block", Little) {
+///         8 => width: u16,
+///         10 => height: u16
+///     }
+/// }
+///
+/// let buf = [0, 0, 0, 0, 0, 0, 0, 0, 0x40, 0, 0x20, 0];
+/// let header = ImageHeader::parse(&buf, 0).unwrap();
+/// assert_eq!((header.width, header.height), (64, 32));
+/// assert!(ImageHeader::parse(&buf, 4).is_err());
+/// ```
+///
+/// The struct gets `parse` for byte slices, `read_from` for [`FileData`](crate::file_data::FileData),
+/// `fields` to list the parsed values, and a `Display` impl that dumps them.
+#[macro_export]
+macro_rules! binary_struct {
+	($(
+		$(#[$meta:meta])*
+		$vis:vis struct $name:ident($desc:literal, $endian:ident) {
+			$($(#[$field_meta:meta])* $offset:literal => $field:ident: $t:ty),* $(,)?
+		}
+	)*) => {$(
+		$(#[$meta])*
+		#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+		$vis struct $name {
+			$($(#[$field_meta])* pub $field: $t),*
+		}
+
+		#[allow(dead_code)]
+		impl $name {
+			pub const DESC: &'static str = $desc;
+			/// from the start of the struct to the end of its last field
+			pub const SIZE: usize = {
+				let mut size = 0;
+				$(if $offset + <$t as $crate::byte_slice::BinaryField>::SIZE > size {
+					size = $offset + <$t as $crate::byte_slice::BinaryField>::SIZE;
+				})*
+				size
+			};
+
+			pub fn parse(buf: &[u8], pos: usize) -> Result<Self, $crate::Error> {
+				Self::parse_shifted(buf, pos, 0)
+			}
+
+			fn parse_shifted(buf: &[u8], pos: usize, shift: usize) -> Result<Self, $crate::Error> {
+				Ok(Self {$(
+					$field: $crate::byte_slice::read_field(
+						buf, pos.saturating_add($offset), pos.saturating_add($offset).saturating_add(shift),
+						$crate::byte_slice::Endian::$endian, $desc, stringify!($field)
+					)?
+				),*})
+			}
+
+			/// Reads only the bytes the struct covers.
+			pub fn read_from(file: &mut $crate::file_data::FileData, pos: usize) -> Result<Self, $crate::Error> {
+				// whatever is past the end of the file is left to parse, so the error names the field
+				let mut buf = vec![0u8; Self::SIZE.min(file.len().saturating_sub(pos))];
+				file.read_chunk_exact(&mut buf, pos)?;
+				Self::parse_shifted(&buf, 0, pos)
+			}
+
+			/// Name, offset from the start of the struct and value of each field.
+			pub fn fields(&self) -> Vec<(&'static str, usize, String)> {
+				vec![$((stringify!($field), $offset, $crate::byte_slice::BinaryField::dump(&self.$field))),*]
+			}
+		}
+
+		impl std::fmt::Display for $name {
+			fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+				write!(f, "{}", $desc)?;
+				$(write!(f, "\n{:#06X} {}: {}", $offset, stringify!($field), $crate::byte_slice::BinaryField::dump(&self.$field))?;)*
+				Ok(())
 			}
 		}
-		pixels
+	)*}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{file_data::FileData, ErrorKind};
+
+	binary_struct! {
+		struct TestHeader("test header", Big) {
+			0 => magic: [u8; 4],
+			6 => count: u16,
+			8 => size: u32
+		}
 	}
-}
\ No newline at end of file
+
+	const BUF: [u8; 14] = [0xFF, 0xFF, b'T', b'E', b'S', b'T', 0xFF, 0xFF, 0x01, 0x02, 0, 0, 0x10, 0];
+
+	#[test]
+	fn parse_and_fields() {
+		assert_eq!(TestHeader::SIZE, 12);
+		let header = TestHeader::parse(&BUF, 2).unwrap();
+		assert_eq!(header, TestHeader {magic: *b"TEST", count: 0x0102, size: 0x1000});
+		assert_eq!(header.fields(), [
+			("magic", 0, "\"TEST\"".to_string()),
+			("count", 6, "258".to_string()),
+			("size", 8, "4096".to_string())
+		]);
+		assert_eq!(header.to_string(), "test header\n0x0000 magic: \"TEST\"\n0x0006 count: 258\n0x0008 size: 4096");
+		let mut file = FileData::Memory {buf: BUF.into()};
+		assert_eq!(TestHeader::read_from(&mut file, 2).unwrap(), header);
+	}
+
+	#[test]
+	fn truncated() {
+		// the error names the first field out of range, at its offset in the whole buffer or file
+		let err = TestHeader::parse(&BUF, 4).unwrap_err();
+		assert_eq!(err.kind, ErrorKind::Truncated);
+		assert!(err.msg.contains("test header") && err.msg.contains("`size`"));
+		assert_eq!(err.offset, Some(12));
+		let mut file = FileData::Memory {buf: BUF.into()};
+		let err = TestHeader::read_from(&mut file, 8).unwrap_err();
+		assert!(err.msg.contains("`count`"));
+		assert_eq!(err.offset, Some(14));
+		assert!(TestHeader::parse(&BUF, usize::MAX).is_err());
+	}
+}
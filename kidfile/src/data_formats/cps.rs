@@ -1,40 +1,47 @@
-use crate::{file_data::FileData, Certainty, Decoder};
+use crate::{file_data::FileData, options::POLL_INTERVAL, DecodeOptions, Decoder, Detection, Error, HeaderInfo, Progress};
 
 // based on Never7 PS2 decompilation
 
 pub const ENTRY_CPS: Decoder<Box<[u8]>> = Decoder {
 	id: "cps",
 	desc: "KID compression format",
-	detect: |buf| Certainty::certain_if(buf.starts_with_at(b"ogdt", 4) || buf.starts_with_at(b"TIM2", 4)),
+	detect: |buf| Detection::any_magic(buf, &[b"ogdt", b"TIM2"], 4),
+	identify: |buf| HeaderInfo {unpacked_size: buf.get_u32_at_be(0).map(|x| x as usize >> 8), ..Default::default()},
 	decode
 };
 
-fn decode(data: &mut FileData) -> Result<Box<[u8]>, String> {
-	let expected_size = data.get_u32_at_be(0).unwrap() as usize >> 8;
+fn decode(data: &mut FileData, opts: &DecodeOptions) -> Result<Box<[u8]>, Error> {
+	let expected_size = data.read_u32_be(0)? as usize >> 8;
+	opts.check_output(expected_size).map_err(|e| e.at(0))?;
 
-	let buf = &data.read()[3..];
+	let buf = &data.read()?[3..];
 	let mut in_cursor = 0;
 	let mut out = vec![0u8; expected_size];
 	let mut out_cursor = 0;
+	let mut next_poll = POLL_INTERVAL;
 	while in_cursor < buf.len() {
+		if in_cursor >= next_poll {
+			opts.poll(Progress::Bytes {done: in_cursor, total: buf.len()})?;
+			next_poll = in_cursor + POLL_INTERVAL;
+		}
 		let cur_byte = buf[in_cursor] as usize;
 		if cur_byte & 0x80 != 0 { // backreference
 			if in_cursor + 1 >= buf.len() {
-				return Err("incomplete backreference".into());
+				return Err(Error::truncated("incomplete backreference").at(3 + in_cursor));
 			}
 			let backref_offset = 1 + ((cur_byte & 3) << 8 | buf[in_cursor + 1] as usize);
 			if backref_offset > out_cursor {
-				return Err("invalid backreference offset".into());
+				return Err(Error::corrupt("invalid backreference offset").at(3 + in_cursor));
 			}
 			let mut backref_addr = out_cursor - backref_offset;
 			let backref_len = ((cur_byte & 0x7C) >> 2) + 3;
 			in_cursor += 2;
 			if backref_addr + backref_len > expected_size {
-				return Err("invalid backreference length".into());
+				return Err(Error::corrupt("invalid backreference length").at(3 + in_cursor));
 			}
 			if out_cursor + backref_len > expected_size {
 				//break;
-				return Err(format!("expected {expected_size} bytes when decompressing, got more from backreference"));
+				return Err(Error::size_mismatch(format!("expected {expected_size} bytes when decompressing, got more from backreference")).at(3 + in_cursor));
 			}
 			for _ in 0..backref_len {
 				out[out_cursor] = out[backref_addr];
@@ -52,7 +59,7 @@ fn decode(data: &mut FileData) -> Result<Box<[u8]>, String> {
 				if cur_byte == 0 {
 					break;
 				} else {
-					return Err(format!("expected {expected_size} bytes when decompressing, got more from raw chunk"));
+					return Err(Error::size_mismatch(format!("expected {expected_size} bytes when decompressing, got more from raw chunk")).at(3 + in_cursor));
 				}
 			}
 			for _ in 0..chunk_len {
@@ -65,6 +72,6 @@ fn decode(data: &mut FileData) -> Result<Box<[u8]>, String> {
 	if out_cursor == expected_size {
 		Ok(out.into())
 	} else {
-		Err(format!("expected {expected_size} bytes when decompressing, got only {out_cursor}"))
+		Err(Error::size_mismatch(format!("expected {expected_size} bytes when decompressing, got only {out_cursor}")))
 	}
 }
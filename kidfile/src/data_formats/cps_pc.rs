@@ -1,28 +1,33 @@
-use crate::{byte_slice::ByteSlice, file_data::FileData, Certainty, Decoder};
+use crate::{byte_slice::ByteSlice, options::POLL_INTERVAL, DecodeOptions, Decoder, Detection, Error, HeaderInfo, Progress};
 
 pub const ENTRY_CPS_PC: Decoder<Box<[u8]>> = Decoder {
 	id: "cps_pc",
 	desc: "Old KID PC port obfuscated compression format",
-	detect: |buf| Certainty::certain_if(buf.starts_with(b"CPS\0")),
-	decode: |data| {
-		let buf = data.read();
+	detect: |buf| Detection::magic(buf, b"CPS\0", 0),
+	identify: |buf| HeaderInfo {unpacked_size: buf.get_u32_at(12).map(|x| x as usize), ..Default::default()},
+	decode: |data, opts| {
+		let buf = data.read()?;
 		let packed_size = buf.read_u32(4)? as usize;
 		let compression_type = buf.read_u16(10)?;
 		let unpacked_size = buf.read_u32(12)? as usize;
+		if packed_size < 16 || packed_size > buf.len() {
+			return Err(Error::bad_field(format!("packed size {packed_size} does not fit the file")).at(4));
+		}
 
 		// deobfuscate
 		let mut deobfuscated = Vec::with_capacity(packed_size - 4);
 		let key_off = buf.read_u32(packed_size - 4)?.wrapping_sub(0x7534682);
 		let mut key = buf.read_u32(key_off as usize)?.wrapping_add(key_off).wrapping_add(0x3786425);
-		for pos in 4..16 {
-			deobfuscated.push(buf[pos]);
-		}
+		deobfuscated.extend_from_slice(&buf[4..16]);
 		for pos in (16..packed_size).step_by(4) {
 			if pos == packed_size - 4 {
 				deobfuscated.push(0);
 				break;
 			}
-			let mut word = buf.get_u32_at(pos).unwrap();
+			if pos % POLL_INTERVAL == 0 {
+				opts.poll(Progress::Bytes {done: pos, total: packed_size})?;
+			}
+			let mut word = buf.read_u32(pos)?;
 			if pos != key_off as usize && key_off != 0 {
 				word = word.wrapping_sub(key.wrapping_add(packed_size as u32));
 			}
@@ -34,96 +39,88 @@ pub const ENTRY_CPS_PC: Decoder<Box<[u8]>> = Decoder {
 
 		// decompress (maybe)
 		if compression_type & 1 != 0 {
-			let mut out = Vec::with_capacity(unpacked_size);
-			let mut in_pos = 16;
-			while in_pos < deobfuscated.len() && out.len() < unpacked_size {
-				let ctl = deobfuscated[in_pos] as usize;
-				in_pos += 1;
-				if ctl & 0x80 != 0 {
-					if ctl & 0x40 != 0 {
-						let mut count = (ctl & 0x1F) + 2;
-						if ctl & 0x20 != 0 {
-							count += (deobfuscated[in_pos] as usize) << 5;
-							in_pos += 1;
-						}
-						let remaining_out = unpacked_size - out.len();
-						if count > remaining_out {
-							count = remaining_out;
-						}
-						let value = deobfuscated[in_pos];
-						in_pos += 1;
-						for _ in 0..count {
-							out.push(value);
-						}
-					} else {
-						let offset = ((ctl & 3) << 8) + deobfuscated[in_pos] as usize + 1;
-						in_pos += 1;
-						let count = ((ctl >> 2) & 0xF) + 2;
-						let origin = out.len() - offset;
-						for i in 0..count {
-							out.push(out[origin + i]);
-						}
-					}
-				} else if ctl & 0x40 != 0 {
-					let mut size = (ctl & 0x3F) + 2;
-					let remaining_in = deobfuscated.len() - in_pos;
-					if size > remaining_in {
-						size = remaining_in;
-					}
-					let remaining_out = unpacked_size - out.len();
-					if size > remaining_out {
-						size = remaining_out;
-					}
-					let repetitions = deobfuscated[in_pos] as usize + 1;
-					in_pos += 1;
-					for _ in 0..repetitions {
-						if out.len() + size > unpacked_size {
-							let len = unpacked_size - out.len();
-							for _ in 0..len {
-								out.push(deobfuscated[in_pos]);
-								in_pos += 1;
-							}
-							break;
-						}
-						for i in 0..size {
-							out.push(deobfuscated[in_pos + i]);
-						}
-					}
-					in_pos += size;
-				} else {
-					let mut count = (ctl & 0x1F) + 1;
-					if ctl & 0x20 != 0 {
-						count += (deobfuscated[in_pos] as usize) << 5;
-						in_pos += 1;
-					}
-					let remaining_in = deobfuscated.len() - in_pos;
-					if count > remaining_in {
-						count = remaining_in;
-					}
-					let remaining_out = unpacked_size - out.len();
-					if count > remaining_out {
-						count = remaining_out;
-					}
-					for _ in 0..count {
-						out.push(deobfuscated[in_pos]);
-						in_pos += 1;
-					}
-				}
-			}
+			opts.check_output(unpacked_size).map_err(|e| e.at(12))?;
+			let out = decompress_lnd(deobfuscated.get(16..).unwrap_or_default(), unpacked_size, opts).map_err(|e| Error {offset: e.offset.map(|x| x + 20), ..e})?;
 			if out.len() == unpacked_size {
 				Ok(out.into_boxed_slice())
 			} else {
-				Err(format!("wrong unpacked size after lnd decompression, expected {unpacked_size}, got {}", out.len()))
+				Err(Error::size_mismatch(format!("wrong unpacked size after lnd decompression, expected {unpacked_size}, got {}", out.len())))
 			}
 		} else if compression_type & 2 != 0 {
-			Err("compression type not supported".into())
+			Err(Error::unsupported(format!("compression type {compression_type:#X} not supported")).at(10))
 		} else {
 			if deobfuscated.len() >= unpacked_size {
 				// for some reason, sometimes the result is a little too big, and yet we're not allowed to trim it
 				Ok(deobfuscated.into_boxed_slice())
 			} else {
-				Err(format!("wrong unpacked size after deobfuscation, expected {unpacked_size}, got {}", deobfuscated.len()))
+				Err(Error::size_mismatch(format!("wrong unpacked size after deobfuscation, expected {unpacked_size}, got {}", deobfuscated.len())))
 			}
 		}
 	}
 };
+
+/// The compression used by CPS and by compressed LNK entries. Stops once `unpacked_size` bytes are produced,
+/// the output can still be shorter if the input runs out, or slightly longer from a final backreference.
+pub(crate) fn decompress_lnd(inp: &[u8], unpacked_size: usize, opts: &DecodeOptions) -> Result<Vec<u8>, Error> {
+	let next = |pos: usize| inp.get(pos).map(|x| *x as usize).ok_or_else(|| Error::truncated("compressed data ended in the middle of a command").at(pos));
+	// the size comes from a header, don't trust it with a huge allocation up front
+	let mut out = Vec::with_capacity(unpacked_size.min(inp.len().saturating_mul(8)));
+	let mut in_pos = 0;
+	let mut next_poll = POLL_INTERVAL;
+	while in_pos < inp.len() && out.len() < unpacked_size {
+		if in_pos >= next_poll {
+			opts.poll(Progress::Bytes {done: in_pos, total: inp.len()})?;
+			next_poll = in_pos + POLL_INTERVAL;
+		}
+		let ctl = inp[in_pos] as usize;
+		in_pos += 1;
+		if ctl & 0x80 != 0 {
+			if ctl & 0x40 != 0 {
+				let mut count = (ctl & 0x1F) + 2;
+				if ctl & 0x20 != 0 {
+					count += next(in_pos)? << 5;
+					in_pos += 1;
+				}
+				let count = count.min(unpacked_size - out.len());
+				let value = next(in_pos)? as u8;
+				in_pos += 1;
+				out.resize(out.len() + count, value);
+			} else {
+				let offset = ((ctl & 3) << 8) + next(in_pos)? + 1;
+				in_pos += 1;
+				let count = ((ctl >> 2) & 0xF) + 2;
+				if offset > out.len() {
+					return Err(Error::corrupt("backreference before start of output").at(in_pos - 2));
+				}
+				let origin = out.len() - offset;
+				for i in 0..count {
+					out.push(out[origin + i]);
+				}
+			}
+		} else if ctl & 0x40 != 0 {
+			let size = ((ctl & 0x3F) + 2).min(inp.len() - in_pos).min(unpacked_size - out.len());
+			let repetitions = next(in_pos)? + 1;
+			in_pos += 1;
+			let pattern = inp.get(in_pos..in_pos + size).ok_or_else(|| Error::truncated("compressed data ended in the middle of a pattern").at(in_pos))?;
+			for _ in 0..repetitions {
+				if out.len() + size > unpacked_size {
+					let len = unpacked_size - out.len();
+					out.extend_from_slice(&pattern[..len]);
+					break;
+				}
+				out.extend_from_slice(pattern);
+			}
+			in_pos += size;
+		} else {
+			let mut count = (ctl & 0x1F) + 1;
+			if ctl & 0x20 != 0 {
+				count += next(in_pos)? << 5;
+				in_pos += 1;
+			}
+			let count = count.min(inp.len() - in_pos).min(unpacked_size - out.len());
+			out.extend_from_slice(&inp[in_pos..in_pos + count]);
+			in_pos += count;
+		}
+	}
+	Ok(out)
+}
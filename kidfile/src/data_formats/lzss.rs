@@ -1,9 +1,13 @@
-use crate::{file_data::FileData, Certainty, Decoder};
+use crate::{file_data::FileData, options::POLL_INTERVAL, DecodeOptions, Decoder, Detection, Error, HeaderInfo, Progress};
 
 pub const ENTRY_LZSS: Decoder<Box<[u8]>> = Decoder {
 	id: "lzss",
 	desc: "The common lzss.c from Haruhiko Okumura",
-	detect: |buf| Certainty::possible_if(decode_header(buf).is_some()),
+	detect: |buf| match decode_header(buf) {
+		Some(size) => detect_ratio(size, buf.len()),
+		None => Detection::impossible("implausible size header")
+	},
+	identify: |buf| HeaderInfo {unpacked_size: decode_header(buf), ..Default::default()},
 	decode
 };
 
@@ -16,20 +20,34 @@ fn decode_header(data: &mut FileData) -> Option<usize> {
 	}
 }
 
-fn decode(data: &mut FileData) -> Result<Box<[u8]>, String> {
+// lzss never expands data by more than 1/8, and rarely compresses it past 10:1
+pub(super) fn detect_ratio(size: usize, packed_size: usize) -> Detection {
+	let reason = format!("size header {size:#X} for {packed_size:#X} bytes of input");
+	if size * 8 >= packed_size * 7 && size <= packed_size * 10 {
+		Detection::possible(reason).with_score(40)
+	} else {
+		Detection::possible(reason).with_score(15)
+	}
+}
+
+fn decode(data: &mut FileData, opts: &DecodeOptions) -> Result<Box<[u8]>, Error> {
 	if let Some(expected_size) = decode_header(data) {
+		opts.check_output(expected_size).map_err(|e| e.at(0))?;
 		let is_size_unknown = expected_size == data.len();
-		match decompress_lzss(&data.read()[4..], expected_size, is_size_unknown) {
-			Ok(decompressed) => Ok(decompressed),
-			Err(Some(actual_size)) => Err(format!("expected {expected_size} bytes when decompressing, got only {actual_size}")),
-			Err(None) => Err(format!("expected {expected_size} bytes when decompressing, got more"))
-		}
+		decompress_lzss(&data.read()?[4..], expected_size, is_size_unknown, opts)
 	} else {
-		Err(String::new())
+		Err(Error::bad_field("implausible size header").at(0))
 	}
 }
 
-fn decompress_lzss(inp: &[u8], expected_size: usize, growable: bool) -> Result<Box<[u8]>, Option<usize>> {
+fn decompress_lzss(inp: &[u8], expected_size: usize, growable: bool, opts: &DecodeOptions) -> Result<Box<[u8]>, Error> {
+	// a size header that's only the input size says nothing, so the output can grow up to the limit
+	let limit = if growable {opts.max_output_bytes} else {expected_size};
+	let too_long = || if growable {
+		Error::limit_exceeded(format!("output is over the limit of {limit} bytes"))
+	} else {
+		Error::size_mismatch(format!("expected {expected_size} bytes when decompressing, got more"))
+	};
 	let mut out = Vec::with_capacity(expected_size);
 	let mut src = inp.iter();
 	let mut flags = 0;
@@ -38,7 +56,13 @@ fn decompress_lzss(inp: &[u8], expected_size: usize, growable: bool) -> Result<B
 	const THRESHOLD: usize = 2;
 	let mut text_buf = [0u8; N + F - 1];
 	let mut r = N - F;
+	let mut next_poll = POLL_INTERVAL;
 	loop {
+		let done = inp.len() - src.len();
+		if done >= next_poll {
+			opts.poll(Progress::Bytes {done, total: inp.len()})?;
+			next_poll = done + POLL_INTERVAL;
+		}
 		flags >>= 1;
 		if flags & 0x100 == 0 {
 			if let Some(c) = src.next() {
@@ -49,8 +73,8 @@ fn decompress_lzss(inp: &[u8], expected_size: usize, growable: bool) -> Result<B
 		}
 		if flags & 1 != 0 {
 			if let Some(c) = src.next().cloned() {
-				if !growable && out.len() >= expected_size {
-					return Err(None);
+				if out.len() >= limit {
+					return Err(too_long());
 				}
 				out.push(c);
 				text_buf[r] = c;
@@ -61,8 +85,8 @@ fn decompress_lzss(inp: &[u8], expected_size: usize, growable: bool) -> Result<B
 			let j = (j as usize & 0x0F) + THRESHOLD;
 			for k in 0..=j {
 				let c = text_buf[(i + k) & (N - 1)];
-				if !growable && out.len() >= expected_size {
-					return Err(None);
+				if out.len() >= limit {
+					return Err(too_long());
 				}
 				out.push(c);
 				text_buf[r] = c;
@@ -75,6 +99,32 @@ fn decompress_lzss(inp: &[u8], expected_size: usize, growable: bool) -> Result<B
 	if growable || out.len() == expected_size {
 		Ok(out.into_boxed_slice())
 	} else {
-		Err(Some(out.len()))
+		Err(Error::size_mismatch(format!("expected {expected_size} bytes when decompressing, got only {}", out.len())))
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::ErrorKind;
+
+	// only literals, with a size header that's the size of the whole input
+	fn literals(len: usize) -> FileData {
+		let mut buf = (len as u32 + len as u32 / 8 + 4).to_le_bytes().to_vec();
+		for chunk in (0..len as u8).collect::<Vec<_>>().chunks(8) {
+			buf.push(0xFF);
+			buf.extend(chunk);
+		}
+		FileData::Memory {buf: buf.into()}
+	}
+
+	#[test]
+	fn growable_output_limit() {
+		let out = decode(&mut literals(64), &DecodeOptions::default()).unwrap();
+		assert_eq!(out, (0..64).collect());
+		let opts = DecodeOptions {max_output_bytes: 80, ..Default::default()};
+		assert_eq!(decode(&mut literals(64), &opts).unwrap().len(), 64);
+		let opts = DecodeOptions {max_output_bytes: 48, ..Default::default()};
+		assert_eq!(decode(&mut literals(64), &opts).unwrap_err().kind, ErrorKind::LimitExceeded);
+	}
+}
@@ -1,11 +1,15 @@
-use crate::{byte_iter::ByteIter, file_data::FileData, Certainty, Decoder};
+use crate::{byte_iter::ByteIter, file_data::FileData, options::POLL_INTERVAL, DecodeOptions, Decoder, Detection, Error, HeaderInfo, Progress};
 
 // thanks to BoilingTeapot for reverse engineering the compression
 
 pub const ENTRY_LZSS_BE: Decoder<Box<[u8]>> = Decoder {
 	id: "lzss-be",
 	desc: "LZSS-like used in N7 DC and 12R PS2",
-	detect: |buf| Certainty::possible_if(decode_header(buf).is_some()),
+	detect: |buf| match decode_header(buf) {
+		Some(size) => super::lzss::detect_ratio(size, buf.len()),
+		None => Detection::impossible("implausible size header")
+	},
+	identify: |buf| HeaderInfo {unpacked_size: decode_header(buf), ..Default::default()},
 	decode
 };
 
@@ -18,23 +22,28 @@ fn decode_header(data: &mut FileData) -> Option<usize> {
 	}
 }
 
-fn decode(data: &mut FileData) -> Result<Box<[u8]>, String> {
+fn decode(data: &mut FileData, opts: &DecodeOptions) -> Result<Box<[u8]>, Error> {
 	if let Some(expected_size) = decode_header(data) {
-		match decompress_lzss_be(&data.read()[4..], expected_size) {
-			Ok(decompressed) => Ok(decompressed),
-			Err(Some(actual_size)) => Err(format!("expected {expected_size} bytes when decompressing, got {actual_size}")),
-			Err(None) => Err(format!("error while decompressing"))
-		}
+		opts.check_output(expected_size).map_err(|e| e.at(0))?;
+		decompress_lzss_be(&data.read()?[4..], expected_size, opts)
 	} else {
-		Err(String::new())
+		Err(Error::bad_field("implausible size header").at(0))
 	}
 }
 
-fn decompress_lzss_be(inp: &[u8], expected_size: usize) -> Result<Box<[u8]>, Option<usize>> {
+fn decompress_lzss_be(inp: &[u8], expected_size: usize, opts: &DecodeOptions) -> Result<Box<[u8]>, Error> {
+	let too_short = |actual_size: usize| Error::size_mismatch(format!("expected {expected_size} bytes when decompressing, got {actual_size}"));
+	let bad_reference = || Error::corrupt("invalid reference while decompressing");
 	let mut out = Vec::with_capacity(expected_size);
 	let mut src = inp.iter().cloned();
+	let mut next_poll = POLL_INTERVAL;
 	while out.len() < expected_size {
-		let chunk_size = src.next_u16_be().ok_or(Some(out.len()))? as usize;
+		let done = inp.len() - src.len();
+		if done >= next_poll {
+			opts.poll(Progress::Bytes {done, total: inp.len()})?;
+			next_poll = done + POLL_INTERVAL;
+		}
+		let chunk_size = src.next_u16_be().ok_or_else(|| too_short(out.len()))? as usize;
 		let mut chunk = src.by_ref().take(chunk_size);
 		let chunk_out_start = out.len();
 		// the flags byte determines whether the next 8 tokens are literals or references
@@ -54,10 +63,10 @@ fn decompress_lzss_be(inp: &[u8], expected_size: usize) -> Result<Box<[u8]>, Opt
 					let ref_len = (ref_value as usize & 0b11111) + 3;
 					let start = out.len() as isize - ref_off as isize;
 					if ref_len > expected_size - out.len() {
-						return Err(None);
+						return Err(bad_reference());
 					}
 					for i in start..start + ref_len as isize {
-						out.push(*out.get(i.max(chunk_out_start as isize) as usize).ok_or(None)?);
+						out.push(*out.get(i.max(chunk_out_start as isize) as usize).ok_or_else(bad_reference)?);
 					}
 				}
 			}
@@ -66,6 +75,6 @@ fn decompress_lzss_be(inp: &[u8], expected_size: usize) -> Result<Box<[u8]>, Opt
 	if out.len() == expected_size {
 		Ok(out.into_boxed_slice())
 	} else {
-		Err(Some(out.len()))
+		Err(too_short(out.len()))
 	}
 }
\ No newline at end of file
@@ -6,7 +6,9 @@ mod cps;
 mod cps_pc;
 mod lzss_be;
 
-pub const DATA_DECODERS: LazyLock<Vec<Decoder<Box<[u8]>>>> = LazyLock::new(|| [
+pub(crate) use cps_pc::decompress_lnd;
+
+pub static DATA_DECODERS: LazyLock<Vec<Decoder<Box<[u8]>>>> = LazyLock::new(|| [
 	lzss::ENTRY_LZSS,
 	cps::ENTRY_CPS,
 	cps_pc::ENTRY_CPS_PC,
@@ -0,0 +1,137 @@
+use std::{borrow::Cow, fmt::Display};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+	/// the data ended before a field or section could be read
+	Truncated,
+	/// a signature that the decoder relies on is missing or wrong
+	BadMagic,
+	/// a header field has a value that makes no sense for the format
+	BadField,
+	/// the data looks valid, but uses a variant of the format that isn't handled
+	Unsupported,
+	/// the decoded data doesn't match the size declared by the file
+	SizeMismatch,
+	/// the data is internally inconsistent, like an invalid backreference or a broken embedded PNG
+	Corrupt,
+	/// reading from the underlying file failed
+	Io,
+	/// no decoder could do anything with the data
+	Unrecognized,
+	/// the data would go over one of the limits in [`DecodeOptions`](crate::DecodeOptions)
+	LimitExceeded,
+	/// the [`CancelToken`](crate::options::CancelToken) was triggered while decoding
+	Cancelled
+}
+
+impl Display for ErrorKind {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Truncated => write!(f, "truncated"),
+			Self::BadMagic => write!(f, "bad magic"),
+			Self::BadField => write!(f, "bad field"),
+			Self::Unsupported => write!(f, "unsupported"),
+			Self::SizeMismatch => write!(f, "size mismatch"),
+			Self::Corrupt => write!(f, "corrupt"),
+			Self::Io => write!(f, "i/o error"),
+			Self::Unrecognized => write!(f, "unrecognized"),
+			Self::LimitExceeded => write!(f, "limit exceeded"),
+			Self::Cancelled => write!(f, "cancelled")
+		}
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct Error {
+	pub kind: ErrorKind,
+	/// id of the decoder that produced the error, filled in by the decode pipeline
+	pub decoder: Option<&'static str>,
+	/// byte offset in the decoder's input where the problem was found, if known
+	pub offset: Option<usize>,
+	pub msg: Cow<'static, str>
+}
+
+impl Error {
+	pub fn new(kind: ErrorKind, msg: impl Into<Cow<'static, str>>) -> Self {
+		Self {kind, decoder: None, offset: None, msg: msg.into()}
+	}
+
+	#[cold]
+	pub fn truncated(msg: impl Into<Cow<'static, str>>) -> Self {
+		Self::new(ErrorKind::Truncated, msg)
+	}
+
+	#[cold]
+	pub fn bad_magic(msg: impl Into<Cow<'static, str>>) -> Self {
+		Self::new(ErrorKind::BadMagic, msg)
+	}
+
+	#[cold]
+	pub fn bad_field(msg: impl Into<Cow<'static, str>>) -> Self {
+		Self::new(ErrorKind::BadField, msg)
+	}
+
+	#[cold]
+	pub fn unsupported(msg: impl Into<Cow<'static, str>>) -> Self {
+		Self::new(ErrorKind::Unsupported, msg)
+	}
+
+	#[cold]
+	pub fn size_mismatch(msg: impl Into<Cow<'static, str>>) -> Self {
+		Self::new(ErrorKind::SizeMismatch, msg)
+	}
+
+	#[cold]
+	pub fn corrupt(msg: impl Into<Cow<'static, str>>) -> Self {
+		Self::new(ErrorKind::Corrupt, msg)
+	}
+
+	#[cold]
+	pub fn io(msg: impl Into<Cow<'static, str>>) -> Self {
+		Self::new(ErrorKind::Io, msg)
+	}
+
+	#[cold]
+	pub fn limit_exceeded(msg: impl Into<Cow<'static, str>>) -> Self {
+		Self::new(ErrorKind::LimitExceeded, msg)
+	}
+
+	#[cold]
+	pub fn cancelled() -> Self {
+		Self::new(ErrorKind::Cancelled, "decoding was cancelled")
+	}
+
+	pub fn at(mut self, offset: usize) -> Self {
+		self.offset = Some(offset);
+		self
+	}
+
+	/// sets the decoder id, unless an inner step already claimed the error
+	pub fn in_decoder(mut self, id: &'static str) -> Self {
+		self.decoder.get_or_insert(id);
+		self
+	}
+}
+
+impl Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match (self.decoder, self.msg.is_empty()) {
+			(Some(id), true) => write!(f, "unspecified error from {id}")?,
+			(Some(id), false) => write!(f, "error from {id}: {}", self.msg)?,
+			(None, true) => write!(f, "{}", self.kind)?,
+			(None, false) => write!(f, "{}", self.msg)?
+		}
+		if let Some(offset) = self.offset {
+			write!(f, " (at 0x{offset:X})")?;
+		}
+		Ok(())
+	}
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+	fn from(value: std::io::Error) -> Self {
+		Self::io(value.to_string())
+	}
+}
@@ -1,4 +1,83 @@
-use std::{ffi::OsString, fs::File, io::{BufReader, Read, Seek, SeekFrom}, path::PathBuf};
+use std::{collections::HashMap, fs::File, io::{self, Read, Seek, SeekFrom}, path::{Path, PathBuf}, sync::{Arc, LazyLock, Mutex, Weak}};
+use memmap2::Mmap;
+use crate::{patch::Patch, Error};
+
+/// Anything that can back a [`FileData::Source`], like a `Cursor` over an ISO already in memory.
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+pub type SharedSource = Arc<Mutex<dyn ReadSeek>>;
+
+/// An open file read only with positional reads, so any number of subfiles on any threads can share it.
+pub type SharedFile = Arc<File>;
+
+// handles still in use by some FileData, so opening the same path again reuses them
+static OPEN_FILES: LazyLock<Mutex<HashMap<PathBuf, Weak<File>>>> = LazyLock::new(Default::default);
+
+/// Opens a file, or gets the handle that's already open for it.
+pub fn open_shared(path: &Path) -> io::Result<SharedFile> {
+	let mut open_files = OPEN_FILES.lock().unwrap_or_else(|e| e.into_inner());
+	if let Some(file) = open_files.get(path).and_then(Weak::upgrade) {
+		return Ok(file);
+	}
+	let file = Arc::new(File::open(path)?);
+	open_files.retain(|_, x| x.strong_count() > 0);
+	open_files.insert(path.to_path_buf(), Arc::downgrade(&file));
+	Ok(file)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, out_buf: &mut [u8], pos: usize) -> io::Result<()> {
+	std::os::unix::fs::FileExt::read_exact_at(file, out_buf, pos as u64)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut out_buf: &mut [u8], mut pos: usize) -> io::Result<()> {
+	// seek_read moves the cursor too, but nothing here relies on it
+	while !out_buf.is_empty() {
+		match std::os::windows::fs::FileExt::seek_read(file, out_buf, pos as u64) {
+			Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+			Ok(n) => {
+				out_buf = &mut out_buf[n..];
+				pos += n;
+			}
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+			Err(e) => return Err(e)
+		}
+	}
+	Ok(())
+}
+
+fn read_file_at(path: &Path, file: &mut Option<SharedFile>, pos: usize, out_buf: &mut [u8]) -> io::Result<()> {
+	if file.is_none() {
+		*file = Some(open_shared(path)?);
+	}
+	read_exact_at(file.as_ref().unwrap(), out_buf, pos)
+}
+
+// reads smaller than this on a file go through a buffer of this size, so reading a header a field at a time isn't a syscall per field
+const READ_AHEAD: usize = 0x1000;
+
+/// What a [`FileData::Stream`] read past the end of its last small read, so the next ones can be served from memory.
+#[derive(Clone, Default)]
+pub struct ReadAhead {
+	pos: usize,
+	buf: Box<[u8]>
+}
+
+impl ReadAhead {
+	fn get(&self, pos: usize, len: usize) -> Option<&[u8]> {
+		self.buf.get(pos.checked_sub(self.pos)?..)?.get(..len)
+	}
+}
+
+/// Decompresses exactly the given number of bytes from the start of the compressed data, which may be less than all of it.
+/// Shared between clones of the data, so it can hold on to the options it was opened with.
+pub type Decompressor = Arc<dyn Fn(&[u8], usize) -> Result<Box<[u8]>, Error> + Send + Sync>;
+
+// smallest prefix decompressed for a partial read, enough for detection to not need more
+const MIN_PREFIX: usize = 0x1000;
 
 pub enum FileData {
 	Memory {
@@ -6,76 +85,204 @@ pub enum FileData {
 	},
 	MemoryCompressed {
 		buf: Box<[u8]>,
+		/// what has been decompressed so far, for reads that don't need all of it
+		prefix: Box<[u8]>,
 		full_size: usize,
-		decompress: fn(Box<[u8]>, usize) -> Box<[u8]>
+		decompress: Decompressor
 	},
 	Stream {
 		path: PathBuf,
-		file: Option<BufReader<File>>,
+		file: Option<SharedFile>,
 		start: usize,
-		size: usize
+		size: usize,
+		read_ahead: ReadAhead
 	},
 	StreamCompressed {
 		path: PathBuf,
-		file: Option<BufReader<File>>,
+		file: Option<SharedFile>,
 		start: usize,
 		size: usize,
 		full_size: usize,
-		decompress: fn(Box<[u8]>, usize) -> Box<[u8]>
+		decompress: Decompressor
+	},
+	/// A range of a seekable source that isn't a file on disk. Subfiles share the source instead of copying from it.
+	Source {
+		source: SharedSource,
+		start: usize,
+		size: usize
+	},
+	/// A range of a file on disk mapped into memory, so reading it borrows from the mapping and subfiles share it.
+	Mapped {
+		map: Arc<Mmap>,
+		path: PathBuf,
+		start: usize,
+		size: usize
+	},
+	/// Other data seen through a [`Patch`]. Reads get the patched bytes, the data underneath is never changed.
+	Patched {
+		base: Box<FileData>,
+		patch: Patch,
+		/// the whole patched contents, once [`FileData::read`] has needed them
+		view: Option<Box<[u8]>>
 	}
 }
 
 macro_rules! impl_byte_readers {
 	($($t:ty),*) => {paste::paste! {$(
-		pub fn [<read_ $t>](&mut self, offset: usize) -> Result<$t, String> {
-			let mut bytes = unsafe {std::mem::MaybeUninit::<[u8; size_of::<$t>()]>::uninit().assume_init()};
-			self.read_chunk_exact(&mut bytes, offset).map_err(#[cold] |_| format!("error reading field"))?;
+		pub fn [<read_ $t>](&mut self, offset: usize) -> Result<$t, Error> {
+			let mut bytes = [0u8; size_of::<$t>()];
+			self.read_chunk_exact(&mut bytes, offset).map_err(|e| Error {msg: concat!("could not read ", stringify!($t)).into(), ..e})?;
 			Ok($t::from_le_bytes(bytes))
 		}
-		pub fn [<read_ $t _be>](&mut self, offset: usize) -> Result<$t, String> {
-			let mut bytes = unsafe {std::mem::MaybeUninit::<[u8; size_of::<$t>()]>::uninit().assume_init()};
-			self.read_chunk_exact(&mut bytes, offset).map_err(#[cold] |_| format!("error reading field"))?;
+		pub fn [<read_ $t _be>](&mut self, offset: usize) -> Result<$t, Error> {
+			let mut bytes = [0u8; size_of::<$t>()];
+			self.read_chunk_exact(&mut bytes, offset).map_err(|e| Error {msg: concat!("could not read ", stringify!($t)).into(), ..e})?;
 			Ok($t::from_be_bytes(bytes))
 		}
 		pub fn [<get_ $t _at>](&mut self, offset: usize) -> Option<$t> {
-			let mut bytes = unsafe {std::mem::MaybeUninit::<[u8; size_of::<$t>()]>::uninit().assume_init()};
+			let mut bytes = [0u8; size_of::<$t>()];
 			self.read_chunk_exact(&mut bytes, offset).ok()?;
 			Some($t::from_le_bytes(bytes))
 		}
 		pub fn [<get_ $t _at_be>](&mut self, offset: usize) -> Option<$t> {
-			let mut bytes = unsafe {std::mem::MaybeUninit::<[u8; size_of::<$t>()]>::uninit().assume_init()};
+			let mut bytes = [0u8; size_of::<$t>()];
 			self.read_chunk_exact(&mut bytes, offset).ok()?;
 			Some($t::from_be_bytes(bytes))
 		}
 	)*}}
 }
 
+fn read_source(source: &SharedSource, pos: usize, out_buf: &mut [u8]) -> Result<(), Error> {
+	// every read seeks first, so a panic in another reader can't leave the source in a bad state
+	let mut source = source.lock().unwrap_or_else(|e| e.into_inner());
+	source.seek(SeekFrom::Start(pos as u64))?;
+	source.read_exact(out_buf)?;
+	Ok(())
+}
+
 impl FileData {
+	/// Wraps a whole source, finding its size by seeking to the end.
+	pub fn from_source(mut source: impl ReadSeek + 'static) -> Result<Self, Error> {
+		let size = source.seek(SeekFrom::End(0))? as usize;
+		Ok(Self::Source {source: Arc::new(Mutex::new(source)), start: 0, size})
+	}
+
+	/// Maps a whole file into memory, see [`FileData::Mapped`].
+	/// The file should not be modified by anything else while it's mapped, or reads will see the changes or fault.
+	pub fn mmap(path: impl AsRef<Path>) -> Result<Self, Error> {
+		let path = path.as_ref();
+		let file = File::open(path)?;
+		let map = unsafe {Mmap::map(&file)?};
+		let size = map.len();
+		Ok(Self::Mapped {map: Arc::new(map), path: path.to_path_buf(), start: 0, size})
+	}
+
+	/// Makes this data decompress with `decompress` as it's read, `full_size` being its size after that.
+	/// Reads of the start only decompress as far as they need. Files on disk stay unread until then, anything else is loaded now.
+	pub fn into_compressed(self, full_size: usize, decompress: impl Fn(&[u8], usize) -> Result<Box<[u8]>, Error> + Send + Sync + 'static) -> Result<Self, Error> {
+		let decompress: Decompressor = Arc::new(decompress);
+		match self {
+			Self::Stream {path, file, start, size, ..} => Ok(Self::StreamCompressed {path, file, start, size, full_size, decompress}),
+			mut other => Ok(Self::MemoryCompressed {buf: other.read()?.into(), prefix: Box::new([]), full_size, decompress})
+		}
+	}
+
+	/// Puts a patch over this data, on top of any it already has.
+	pub fn with_patch(mut self, patch: Patch) -> Self {
+		self.patch_mut().merge(patch);
+		self
+	}
+
+	/// Overwrites bytes in the patched view of the data, see [`FileData::Patched`].
+	pub fn patch_bytes(&mut self, offset: usize, bytes: &[u8]) -> Result<(), Error> {
+		if offset.saturating_add(bytes.len()) > self.len() {
+			return Err(Error::truncated("patch goes beyond file end").at(offset));
+		}
+		self.patch_mut().ranges.push((offset, bytes.into()));
+		Ok(())
+	}
+
+	/// Replaces the whole patched view of the data, including its size.
+	pub fn replace_contents(&mut self, buf: Box<[u8]>) {
+		self.patch_mut().merge(Patch {replacement: Some(buf), ranges: Vec::new()});
+	}
+
+	pub fn patch(&self) -> Option<&Patch> {
+		match self {
+			Self::Patched {patch, ..} => Some(patch),
+			_ => None
+		}
+	}
+
+	fn patch_mut(&mut self) -> &mut Patch {
+		if !matches!(self, Self::Patched {..}) {
+			let base = std::mem::replace(self, Self::Memory {buf: Box::new([])});
+			*self = Self::Patched {base: Box::new(base), patch: Patch::default(), view: None};
+		}
+		match self {
+			Self::Patched {patch, view, ..} => {
+				// the patch is about to change
+				*view = None;
+				patch
+			}
+			_ => unreachable!()
+		}
+	}
+
 	pub fn len(&self) -> usize {
 		match self {
 			Self::Memory {buf, ..} => buf.len(),
 			Self::MemoryCompressed {full_size, ..} => *full_size,
 			Self::Stream {size, ..} => *size,
-			Self::StreamCompressed {full_size, ..} => *full_size
+			Self::StreamCompressed {full_size, ..} => *full_size,
+			Self::Source {size, ..} => *size,
+			Self::Mapped {size, ..} => *size,
+			Self::Patched {base, patch, ..} => patch.len(base.len())
 		}
 	}
 
-	pub fn subfile(&mut self, sub_start: usize, sub_size: usize) -> Result<FileData, String> {
+	pub fn subfile(&mut self, sub_start: usize, sub_size: usize) -> Result<FileData, Error> {
 		match self {
-			Self::Stream {path, start, size, ..} => {
-				if sub_start + sub_size > *size {
-					return Err("subfile request is beyond file end".into());
+			Self::Stream {path, file, start, size, ..} => {
+				if sub_start.saturating_add(sub_size) > *size {
+					return Err(Error::truncated("subfile request is beyond file end").at(sub_start));
 				}
 				Ok(Self::Stream {
 					path: path.clone(),
-					file: None,
+					file: file.clone(),
+					start: *start + sub_start,
+					size: sub_size,
+					read_ahead: ReadAhead::default()
+				})
+			}
+			Self::Source {source, start, size} => {
+				if sub_start.saturating_add(sub_size) > *size {
+					return Err(Error::truncated("subfile request is beyond file end").at(sub_start));
+				}
+				Ok(Self::Source {
+					source: source.clone(),
+					start: *start + sub_start,
+					size: sub_size
+				})
+			}
+			Self::Mapped {map, path, start, size} => {
+				if sub_start.saturating_add(sub_size) > *size {
+					return Err(Error::truncated("subfile request is beyond file end").at(sub_start));
+				}
+				Ok(Self::Mapped {
+					map: map.clone(),
+					path: path.clone(),
 					start: *start + sub_start,
 					size: sub_size
 				})
 			}
 			_ => {
-				let mut buf = unsafe {Box::new_uninit_slice(sub_size).assume_init()};
-				self.read_chunk_exact(&mut buf, sub_start).map_err(|_| "subfile request is beyond file end")?;
+				// checked before allocating, a bogus size from a header shouldn't abort on allocation
+				if sub_start.saturating_add(sub_size) > self.len() {
+					return Err(Error::truncated("subfile request is beyond file end").at(sub_start));
+				}
+				let mut buf = vec![0u8; sub_size].into_boxed_slice();
+				self.read_chunk_exact(&mut buf, sub_start).map_err(|e| Error {msg: "subfile request is beyond file end".into(), ..e})?;
 				Ok(Self::Memory {buf})
 			}
 		}
@@ -83,21 +290,18 @@ impl FileData {
 
 	pub fn starts_with_at(&mut self, needle: &[u8], offset: usize) -> bool {
 		match self {
-			Self::Stream {path, file, start, size, ..} => {
-				if offset + needle.len() > *size {
-					return false;
-				}
-				if file.is_none() {
-					*file = Some(BufReader::new(File::open(path).unwrap()));
-				}
-				let file = file.as_mut().unwrap();
-				if file.seek(SeekFrom::Start((*start + offset) as u64)).is_err() {
+			Self::Source {source, start, size} => {
+				if offset.saturating_add(needle.len()) > *size {
 					return false;
 				}
 				let mut sig = vec![0u8; needle.len()];
-				file.read_exact(&mut sig).is_ok() && sig == needle
+				read_source(source, *start + offset, &mut sig).is_ok() && sig == needle
 			}
-			_ => self.read().get(offset..).map_or(false, |x| x.starts_with(needle))
+			Self::Stream {..} | Self::MemoryCompressed {..} | Self::StreamCompressed {..} | Self::Patched {..} => {
+				let mut sig = vec![0u8; needle.len()];
+				self.read_chunk_exact(&mut sig, offset).is_ok() && sig == needle
+			}
+			_ => self.read().ok().and_then(|x| x.get(offset..)).map_or(false, |x| x.starts_with(needle))
 		}
 	}
 
@@ -105,84 +309,126 @@ impl FileData {
 		self.starts_with_at(needle, 0)
 	}
 
-	pub fn read_chunk_exact(&mut self, out_buf: &mut [u8], chunk_start: usize) -> Result<(), ()> {
+	pub fn read_chunk_exact(&mut self, out_buf: &mut [u8], chunk_start: usize) -> Result<(), Error> {
 		match self {
-			Self::Memory {..} => {}
-			Self::MemoryCompressed {buf, full_size, decompress} => {
-				if chunk_start + out_buf.len() > *full_size {
-					return Err(());
+			Self::Memory {..} | Self::Mapped {..} => {}
+			Self::MemoryCompressed {buf, prefix, full_size, decompress} => {
+				let chunk_end = chunk_start.saturating_add(out_buf.len());
+				if chunk_end > *full_size {
+					return Err(Error::truncated("read beyond end of file").at(chunk_start));
 				}
-				*self = Self::Memory {buf: decompress(std::mem::take(buf), *full_size)};
+				if chunk_end > prefix.len() {
+					// grows geometrically, so reading through the data a bit at a time doesn't decompress it over and over
+					let prefix_size = chunk_end.max(prefix.len() * 2).max(MIN_PREFIX);
+					if prefix_size >= *full_size {
+						*self = Self::Memory {buf: decompress(buf, *full_size)?};
+						return self.read_chunk_exact(out_buf, chunk_start);
+					}
+					*prefix = decompress(buf, prefix_size)?;
+				}
+				out_buf.copy_from_slice(prefix.get(chunk_start..chunk_end).ok_or_else(|| Error::truncated("read beyond end of file").at(chunk_start))?);
+				return Ok(());
 			}
-			Self::Stream {path, file, start, size} => {
-				if chunk_start + out_buf.len() > *size {
-					return Err(());
+			Self::Stream {path, file, start, size, read_ahead} => {
+				if chunk_start.saturating_add(out_buf.len()) > *size {
+					return Err(Error::truncated("read beyond end of file").at(chunk_start));
 				}
-				if file.is_none() {
-					*file = Some(BufReader::new(File::open(path).unwrap()));
+				if out_buf.len() >= READ_AHEAD {
+					read_file_at(path, file, *start + chunk_start, out_buf)?;
+					return Ok(());
 				}
-				let file = file.as_mut().unwrap();
-				file.seek(SeekFrom::Start((*start + chunk_start) as u64)).map_err(|_| ())?;
-				file.read_exact(out_buf).map_err(|_| ())?;
+				if read_ahead.get(chunk_start, out_buf.len()).is_none() {
+					let mut buf = vec![0u8; READ_AHEAD.min(*size - chunk_start)].into_boxed_slice();
+					read_file_at(path, file, *start + chunk_start, &mut buf)?;
+					*read_ahead = ReadAhead {pos: chunk_start, buf};
+				}
+				out_buf.copy_from_slice(read_ahead.get(chunk_start, out_buf.len()).unwrap());
 				return Ok(());
 			}
 			Self::StreamCompressed {path, file, start, size, full_size, decompress} => {
-				if chunk_start + out_buf.len() > *full_size {
-					return Err(());
+				if chunk_start.saturating_add(out_buf.len()) > *full_size {
+					return Err(Error::truncated("read beyond end of file").at(chunk_start));
+				}
+				// the compressed data is kept in memory so partial reads can decompress more of it later
+				let mut compressed = vec![0u8; *size].into_boxed_slice();
+				read_file_at(path, file, *start, &mut compressed)?;
+				*self = Self::MemoryCompressed {buf: compressed, prefix: Box::new([]), full_size: *full_size, decompress: decompress.clone()};
+				return self.read_chunk_exact(out_buf, chunk_start);
+			}
+			Self::Source {source, start, size} => {
+				if chunk_start.saturating_add(out_buf.len()) > *size {
+					return Err(Error::truncated("read beyond end of file").at(chunk_start));
+				}
+				return read_source(source, *start + chunk_start, out_buf);
+			}
+			Self::Patched {base, patch, ..} => {
+				let chunk_end = chunk_start.saturating_add(out_buf.len());
+				if chunk_end > patch.len(base.len()) {
+					return Err(Error::truncated("read beyond end of file").at(chunk_start));
 				}
-				if file.is_none() {
-					*file = Some(BufReader::new(File::open(path).unwrap()));
+				if let Some(replacement) = &patch.replacement {
+					out_buf.copy_from_slice(&replacement[chunk_start..chunk_end]);
+				} else {
+					base.read_chunk_exact(out_buf, chunk_start)?;
 				}
-				let file = file.as_mut().unwrap();
-				file.seek(SeekFrom::Start(*start as u64)).map_err(|_| ())?;
-				let mut compressed = unsafe {Box::new_uninit_slice(*size).assume_init()};
-				file.read_exact(&mut compressed).map_err(|_| ())?;
-				*self = Self::Memory {buf: decompress(compressed, *full_size)};
+				patch.apply(out_buf, chunk_start);
+				return Ok(());
 			}
 		}
-		match self {
-			Self::Memory {buf, ..} => out_buf.copy_from_slice(&buf.get(chunk_start..chunk_start + out_buf.len()).ok_or(())?),
+		let buf = match self {
+			Self::Memory {buf, ..} => &buf[..],
+			Self::Mapped {map, start, size, ..} => &map[*start..*start + *size],
 			_ => unreachable!()
-		}
+		};
+		out_buf.copy_from_slice(buf.get(chunk_start..chunk_start.saturating_add(out_buf.len())).ok_or_else(|| Error::truncated("read beyond end of file").at(chunk_start))?);
 		Ok(())
 	}
 
-	pub fn read(&mut self) -> &[u8] {
+	/// Gets the whole contents, loading them into memory first unless they're there already or mapped.
+	pub fn read(&mut self) -> Result<&[u8], Error> {
 		match self {
-			Self::Memory {buf, ..} => return buf,
-			Self::MemoryCompressed {buf, full_size, decompress} => {
-				*self = Self::Memory {buf: decompress(std::mem::take(buf), *full_size)};
+			Self::Memory {..} | Self::Mapped {..} => {}
+			Self::MemoryCompressed {buf, full_size, decompress, ..} => {
+				*self = Self::Memory {buf: decompress(buf, *full_size)?};
 			}
-			Self::Stream {path, file, start, size} => {
-				if file.is_none() {
-					*file = Some(BufReader::new(File::open(path).unwrap()));
-				}
-				let file = file.as_mut().unwrap();
-				file.seek(SeekFrom::Start(*start as u64)).unwrap();
-				let mut buf = unsafe {Box::new_uninit_slice(*size).assume_init()};
-				file.read_exact(&mut buf).unwrap();
+			Self::Stream {path, file, start, size, ..} => {
+				let mut buf = vec![0u8; *size].into_boxed_slice();
+				read_file_at(path, file, *start, &mut buf).map_err(|_| Error::truncated("file is shorter than expected").at(*start))?;
 				*self = Self::Memory {buf};
 			}
 			Self::StreamCompressed {path, file, start, size, full_size, decompress} => {
-				if file.is_none() {
-					*file = Some(BufReader::new(File::open(path).unwrap()));
+				let mut compressed = vec![0u8; *size].into_boxed_slice();
+				read_file_at(path, file, *start, &mut compressed).map_err(|_| Error::truncated("file is shorter than expected").at(*start))?;
+				*self = Self::Memory {buf: decompress(&compressed, *full_size)?};
+			}
+			Self::Source {source, start, size} => {
+				let mut buf = vec![0u8; *size].into_boxed_slice();
+				read_source(source, *start, &mut buf).map_err(|_| Error::truncated("source is shorter than expected").at(*start))?;
+				*self = Self::Memory {buf};
+			}
+			Self::Patched {base, patch, view} => {
+				if view.is_none() {
+					let mut buf: Box<[u8]> = match &patch.replacement {
+						Some(replacement) => replacement.clone(),
+						None => base.read()?.into()
+					};
+					patch.apply(&mut buf, 0);
+					*view = Some(buf);
 				}
-				let file = file.as_mut().unwrap();
-				file.seek(SeekFrom::Start(*start as u64)).unwrap();
-				let mut compressed = unsafe {Box::new_uninit_slice(*size).assume_init()};
-				file.read_exact(&mut compressed).unwrap();
-				*self = Self::Memory {buf: decompress(compressed, *full_size)};
 			}
 		}
 		match self {
-			Self::Memory {buf, ..} => buf,
+			Self::Memory {buf, ..} => Ok(buf),
+			Self::Mapped {map, start, size, ..} => Ok(&map[*start..*start + *size]),
+			Self::Patched {view, ..} => Ok(view.as_ref().unwrap()),
 			_ => unreachable!()
 		}
 	}
 
 	pub fn physical_path(&self) -> Option<PathBuf> {
 		match self {
-			Self::Stream {path, ..} => Some(path.clone()),
+			Self::Stream {path, ..} | Self::Mapped {path, ..} => Some(path.clone()),
+			Self::Patched {base, ..} => base.physical_path(),
 			_ => None
 		}
 	}
@@ -194,25 +440,163 @@ impl Clone for FileData {
 	fn clone(&self) -> Self {
 		match self {
 			Self::Memory {buf} => Self::Memory {buf: buf.clone()},
-			Self::MemoryCompressed {buf, decompress, full_size} => Self::MemoryCompressed {
+			Self::MemoryCompressed {buf, prefix, decompress, full_size} => Self::MemoryCompressed {
 				buf: buf.clone(),
-				decompress: *decompress,
+				prefix: prefix.clone(),
+				decompress: decompress.clone(),
 				full_size: *full_size
 			},
-			Self::Stream {path, start, size, ..} => Self::Stream {
+			Self::Stream {path, file, start, size, read_ahead} => Self::Stream {
 				path: path.clone(),
-				file: None,
+				file: file.clone(),
 				start: *start,
-				size: *size
+				size: *size,
+				read_ahead: read_ahead.clone()
 			},
-			Self::StreamCompressed {path, start, size, decompress, full_size, ..} => Self::StreamCompressed {
+			Self::StreamCompressed {path, file, start, size, decompress, full_size} => Self::StreamCompressed {
 				path: path.clone(),
-				file: None,
+				file: file.clone(),
 				start: *start,
 				size: *size,
-				decompress: *decompress,
+				decompress: decompress.clone(),
 				full_size: *full_size
+			},
+			Self::Source {source, start, size} => Self::Source {
+				source: source.clone(),
+				start: *start,
+				size: *size
+			},
+			Self::Mapped {map, path, start, size} => Self::Mapped {
+				map: map.clone(),
+				path: path.clone(),
+				start: *start,
+				size: *size
+			},
+			Self::Patched {base, patch, view} => Self::Patched {
+				base: base.clone(),
+				patch: patch.clone(),
+				view: view.clone()
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// every byte stands for two, so it's easy to tell how far the data was decompressed
+	fn double(buf: &[u8], size: usize) -> Result<Box<[u8]>, Error> {
+		Ok(buf.iter().flat_map(|&x| [x, x ^ 0xFF]).take(size).collect())
+	}
+
+	fn expected(compressed: &[u8], start: usize, len: usize) -> Vec<u8> {
+		double(compressed, start + len).unwrap()[start..].to_vec()
+	}
+
+	// reads the data and a subfile of it the same ways as a copy in memory, which must give the same results
+	fn check_like_memory(data: &mut FileData, contents: &[u8]) {
+		let mut memory = FileData::Memory {buf: contents.into()};
+		assert_eq!(data.len(), memory.len());
+		let (sub_start, sub_size) = (contents.len() / 3, contents.len() / 2);
+		let mut sub = data.subfile(sub_start, sub_size).unwrap();
+		let mut memory_sub = memory.subfile(sub_start, sub_size).unwrap();
+		assert!(data.subfile(sub_start, contents.len()).is_err());
+		for (data, memory) in [(&mut *data, &mut memory), (&mut sub, &mut memory_sub)] {
+			let len = memory.len();
+			for offset in [0, 1, len / 2, len - 4] {
+				assert_eq!(data.read_u32(offset).unwrap(), memory.read_u32(offset).unwrap());
 			}
+			assert!(data.starts_with_at(&memory.read().unwrap()[len - 3..], len - 3));
+			// past the end fails the same way for both
+			let mut buf = [0u8; 8];
+			let err = data.read_chunk_exact(&mut buf, len - 4).unwrap_err();
+			assert_eq!(err.kind, memory.read_chunk_exact(&mut buf, len - 4).unwrap_err().kind);
+			assert!(data.read_chunk_exact(&mut buf, usize::MAX).is_err());
+			assert!(!data.starts_with_at(&[0; 8], len - 4));
+			assert_eq!(data.read().unwrap(), memory.read().unwrap());
+		}
+	}
+
+	#[test]
+	fn source_like_memory() {
+		let contents = (0..0x3000).map(|x| (x * 31 % 253) as u8).collect::<Vec<_>>();
+		let mut data = FileData::from_source(std::io::Cursor::new(contents.clone())).unwrap();
+		assert!(matches!(data, FileData::Source {..}));
+		check_like_memory(&mut data, &contents);
+	}
+
+	#[test]
+	fn mapped_like_memory() {
+		let contents = (0..0x3000).map(|x| (x * 29 % 241) as u8).collect::<Vec<_>>();
+		let path = std::env::temp_dir().join(format!("kidfile-test-mapped-{}.bin", std::process::id()));
+		std::fs::write(&path, &contents).unwrap();
+		let mut data = FileData::mmap(&path).unwrap();
+		assert!(matches!(data, FileData::Mapped {..}));
+		assert_eq!(data.physical_path(), Some(path.clone()));
+		check_like_memory(&mut data, &contents);
+		// reading never copies mapped data into memory
+		assert!(matches!(data, FileData::Mapped {..}));
+		drop(data);
+		std::fs::remove_file(path).unwrap();
+	}
+
+	#[test]
+	fn stream_compressed_partial_reads() {
+		let compressed = (0..MIN_PREFIX * 4).map(|x| (x * 13 % 256) as u8).collect::<Vec<_>>();
+		let path = std::env::temp_dir().join(format!("kidfile-test-{}.bin", std::process::id()));
+		std::fs::write(&path, [&[0xAAu8; 16][..], &compressed].concat()).unwrap();
+		let full_size = compressed.len() * 2;
+		let mut data = FileData::Stream {path: path.clone(), file: None, start: 16, size: compressed.len(), read_ahead: ReadAhead::default()}.into_compressed(full_size, double).unwrap();
+		let mut buf = [0u8; 16];
+		// a read at the start only decompresses a prefix
+		data.read_chunk_exact(&mut buf, 4).unwrap();
+		assert_eq!(buf[..], expected(&compressed, 4, 16));
+		assert!(matches!(&data, FileData::MemoryCompressed {prefix, ..} if prefix.len() < full_size));
+		// past the prefix, it decompresses further
+		data.read_chunk_exact(&mut buf, MIN_PREFIX * 3 - 8).unwrap();
+		assert_eq!(buf[..], expected(&compressed, MIN_PREFIX * 3 - 8, 16));
+		assert!(matches!(&data, FileData::MemoryCompressed {prefix, ..} if prefix.len() >= MIN_PREFIX * 3 + 8 && prefix.len() < full_size));
+		// up to the end, everything
+		data.read_chunk_exact(&mut buf, full_size - 16).unwrap();
+		assert_eq!(buf[..], expected(&compressed, full_size - 16, 16));
+		assert!(matches!(&data, FileData::Memory {..}));
+		assert!(data.read_chunk_exact(&mut buf, full_size - 8).is_err());
+		assert_eq!(data.read().unwrap(), &double(&compressed, full_size).unwrap()[..]);
+		drop(data);
+		std::fs::remove_file(path).unwrap();
+	}
+
+	#[test]
+	fn stream_subfiles_share_handle() {
+		let contents = (0..READ_AHEAD * 6).map(|x| (x * 7 % 251) as u8).collect::<Vec<_>>();
+		let path = std::env::temp_dir().join(format!("kidfile-test-shared-{}.bin", std::process::id()));
+		std::fs::write(&path, &contents).unwrap();
+		let mut data = FileData::Stream {path: path.clone(), file: None, start: 0, size: contents.len(), read_ahead: ReadAhead::default()};
+		assert_eq!(data.read_u8(1).unwrap(), contents[1]);
+		let ranges = [(100, READ_AHEAD * 3), (READ_AHEAD * 3 + 5, READ_AHEAD * 2)];
+		let subfiles = ranges.map(|(start, size)| (start, size, data.subfile(start, size).unwrap()));
+		let subfiles = std::thread::scope(|scope| {
+			subfiles.map(|(start, size, mut sub)| {
+				let contents = &contents;
+				scope.spawn(move || {
+					// small reads within and across read ahead windows, then one bigger than a window
+					for offset in (0..size - 8).step_by(37) {
+						assert_eq!(sub.read_u64(offset).unwrap(), u64::from_le_bytes(contents[start + offset..][..8].try_into().unwrap()));
+					}
+					let mut buf = vec![0u8; READ_AHEAD + 1];
+					sub.read_chunk_exact(&mut buf, size - READ_AHEAD - 1).unwrap();
+					assert_eq!(buf, contents[start + size - READ_AHEAD - 1..start + size]);
+					assert!(sub.read_u32(size - 2).is_err());
+					sub
+				})
+			}).map(|x| x.join().unwrap())
+		});
+		let shared = open_shared(&path).unwrap();
+		for sub in subfiles.iter().chain([&data]) {
+			assert!(matches!(sub, FileData::Stream {file: Some(file), ..} if Arc::ptr_eq(file, &shared)));
 		}
+		drop((data, subfiles, shared));
+		std::fs::remove_file(path).unwrap();
 	}
-}
\ No newline at end of file
+}
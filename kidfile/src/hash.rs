@@ -0,0 +1,266 @@
+use std::{collections::{HashMap, HashSet}, fmt::Display, path::Path};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use crate::{file_data::FileData, image::Frame, options::POLL_INTERVAL, Archive, DecodeOptions, Error, Progress};
+
+// read this much at a time, so hashing a file on disk doesn't load all of it
+const HASH_CHUNK: usize = 16 * POLL_INTERVAL;
+
+/// CRC32, MD5 and SHA-1 of the same data, computed together in one pass.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Hashes {
+	pub crc32: u32,
+	pub md5: [u8; 16],
+	pub sha1: [u8; 20]
+}
+
+struct Hasher {
+	crc32: crc32fast::Hasher,
+	md5: Md5,
+	sha1: Sha1
+}
+
+impl Hasher {
+	fn new() -> Self {
+		Self {crc32: crc32fast::Hasher::new(), md5: Md5::new(), sha1: Sha1::new()}
+	}
+
+	fn update(&mut self, buf: &[u8]) {
+		self.crc32.update(buf);
+		self.md5.update(buf);
+		self.sha1.update(buf);
+	}
+
+	fn finish(self) -> Hashes {
+		Hashes {crc32: self.crc32.finalize(), md5: self.md5.finalize().into(), sha1: self.sha1.finalize().into()}
+	}
+}
+
+impl Hashes {
+	pub fn of_bytes(buf: &[u8]) -> Self {
+		let mut hasher = Hasher::new();
+		hasher.update(buf);
+		hasher.finish()
+	}
+
+	/// Hashes the data a chunk at a time, reporting progress to the options as it goes.
+	pub fn of_file(data: &mut FileData, opts: &DecodeOptions) -> Result<Self, Error> {
+		let len = data.len();
+		let mut hasher = Hasher::new();
+		let mut chunk = vec![0u8; HASH_CHUNK.min(len)];
+		let mut pos = 0;
+		while pos < len {
+			opts.poll(Progress::Bytes {done: pos, total: len})?;
+			let chunk = &mut chunk[..HASH_CHUNK.min(len - pos)];
+			data.read_chunk_exact(chunk, pos)?;
+			hasher.update(chunk);
+			pos += chunk.len();
+		}
+		Ok(hasher.finish())
+	}
+
+	/// Hashes the decoded pixels as RGBA, after the width and height as little-endian u32s,
+	/// so the same image gives the same hashes no matter what format it came from.
+	pub fn of_frame(frame: &Frame) -> Self {
+		let mut hasher = Hasher::new();
+		hasher.update(&frame.width.to_le_bytes());
+		hasher.update(&frame.height.to_le_bytes());
+		hasher.update(frame.as_rgba_bytes());
+		hasher.finish()
+	}
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+impl Display for Hashes {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "crc32 {:08x}, md5 {}, sha1 {}", self.crc32, to_hex(&self.md5), to_hex(&self.sha1))
+	}
+}
+
+/// One of the hashes in [`Hashes`], used as a key in a [`KnownFileDb`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HashKey {
+	Crc32(u32),
+	Md5([u8; 16]),
+	Sha1([u8; 20])
+}
+
+impl HashKey {
+	/// Parses a hash in hex, telling which kind it is by its length.
+	pub fn from_hex(hex: &str) -> Option<Self> {
+		fn parse<const N: usize>(hex: &str) -> Option<[u8; N]> {
+			// from_str_radix would take a leading '+'
+			if !hex.bytes().all(|x| x.is_ascii_hexdigit()) {
+				return None;
+			}
+			let mut out = [0u8; N];
+			for (i, x) in out.iter_mut().enumerate() {
+				*x = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+			}
+			Some(out)
+		}
+		match hex.len() {
+			8 => Some(Self::Crc32(u32::from_be_bytes(parse(hex)?))),
+			32 => Some(Self::Md5(parse(hex)?)),
+			40 => Some(Self::Sha1(parse(hex)?)),
+			_ => None
+		}
+	}
+}
+
+/// Where a known file comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KnownFile {
+	pub game: String,
+	/// path inside the game's files, with `/` between directories
+	pub path: String
+}
+
+impl KnownFile {
+	pub fn file_name(&self) -> &str {
+		self.path.rsplit(['/', '\\']).next().unwrap_or(&self.path)
+	}
+}
+
+/// Hashes of files whose origin is known, for naming archive entries that have no names and checking dumps.
+///
+/// Loaded from text with one file per line, as `hash<TAB>game<TAB>path`.
+/// The hash is CRC32, MD5 or SHA-1 in hex, whichever the line has. Empty lines and lines starting with `#` are skipped.
+#[derive(Clone, Debug, Default)]
+pub struct KnownFileDb {
+	files: HashMap<HashKey, KnownFile>
+}
+
+impl KnownFileDb {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn parse(text: &str) -> Result<Self, Error> {
+		let mut db = Self::new();
+		for (i, line) in text.lines().enumerate() {
+			let line = line.trim_end_matches('\r');
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let mut fields = line.splitn(3, '\t');
+			let (Some(hash), Some(game), Some(path)) = (fields.next(), fields.next(), fields.next()) else {
+				return Err(Error::bad_field(format!("line {} should have a hash, a game and a path separated by tabs", i + 1)));
+			};
+			let key = HashKey::from_hex(hash).ok_or_else(|| Error::bad_field(format!("line {} has an invalid hash '{hash}'", i + 1)))?;
+			db.insert(key, KnownFile {game: game.into(), path: path.into()});
+		}
+		Ok(db)
+	}
+
+	pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+		let text = std::fs::read_to_string(path)?;
+		Self::parse(&text)
+	}
+
+	pub fn insert(&mut self, key: HashKey, file: KnownFile) {
+		self.files.insert(key, file);
+	}
+
+	pub fn len(&self) -> usize {
+		self.files.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.files.is_empty()
+	}
+
+	/// Finds a file by its strongest hash that's in the database.
+	pub fn lookup(&self, hashes: &Hashes) -> Option<&KnownFile> {
+		self.files.get(&HashKey::Sha1(hashes.sha1))
+			.or_else(|| self.files.get(&HashKey::Md5(hashes.md5)))
+			.or_else(|| self.files.get(&HashKey::Crc32(hashes.crc32)))
+	}
+
+	/// Hashes the data and looks it up, so a match means the file is an unmodified original.
+	pub fn verify(&self, data: &mut FileData, opts: &DecodeOptions) -> Result<Option<&KnownFile>, Error> {
+		Ok(self.lookup(&Hashes::of_file(data, opts)?))
+	}
+
+	/// Gives archive entries the names of the known files they match, returning how many were renamed.
+	/// Entries keep their names if the known one is already taken, since entries are looked up by name.
+	pub fn name_entries(&self, archive: &mut Archive, opts: &DecodeOptions) -> Result<usize, Error> {
+		let mut names = archive.entries.iter().map(|x| x.name.clone()).collect::<HashSet<_>>();
+		let mut renamed = 0;
+		for entry in archive.entries.iter_mut() {
+			if let Some(known) = self.verify(&mut entry.data, opts)? && names.insert(known.file_name().into()) {
+				entry.name = known.file_name().into();
+				renamed += 1;
+			}
+		}
+		Ok(renamed)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn hex(s: &str) -> Vec<u8> {
+		(0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+	}
+
+	#[test]
+	fn known_vectors() {
+		let empty = Hashes::of_bytes(b"");
+		assert_eq!(empty.crc32, 0);
+		assert_eq!(empty.md5[..], hex("d41d8cd98f00b204e9800998ecf8427e"));
+		assert_eq!(empty.sha1[..], hex("da39a3ee5e6b4b0d3255bfef95601890afd80709"));
+		let abc = Hashes::of_bytes(b"abc");
+		assert_eq!(abc.crc32, 0x352441c2);
+		assert_eq!(abc.md5[..], hex("900150983cd24fb0d6963f7d28e17f72"));
+		assert_eq!(abc.sha1[..], hex("a9993e364706816aba3e25717850c26c9cd0d89d"));
+	}
+
+	#[test]
+	fn of_file_matches_of_bytes() {
+		let buf = (0..HASH_CHUNK * 2 + 17).map(|x| x as u8).collect::<Vec<_>>();
+		let mut data = FileData::Memory {buf: buf.clone().into()};
+		assert_eq!(Hashes::of_file(&mut data, &DecodeOptions::default()).unwrap(), Hashes::of_bytes(&buf));
+	}
+
+	#[test]
+	fn hash_key_from_hex() {
+		let abc = Hashes::of_bytes(b"abc");
+		assert_eq!(HashKey::from_hex("352441c2"), Some(HashKey::Crc32(0x352441c2)));
+		assert_eq!(HashKey::from_hex("352441C2"), Some(HashKey::Crc32(0x352441c2)));
+		assert_eq!(HashKey::from_hex("900150983cd24fb0d6963f7d28e17f72"), Some(HashKey::Md5(abc.md5)));
+		assert_eq!(HashKey::from_hex("a9993e364706816aba3e25717850c26c9cd0d89d"), Some(HashKey::Sha1(abc.sha1)));
+		assert_eq!(HashKey::from_hex(""), None);
+		assert_eq!(HashKey::from_hex("352441c"), None);
+		assert_eq!(HashKey::from_hex("352441cg"), None);
+		assert_eq!(HashKey::from_hex("+52441c2"), None);
+		// 8 bytes but not 8 characters
+		assert_eq!(HashKey::from_hex("3524é1c"), None);
+	}
+
+	#[test]
+	fn parse_db() {
+		let abc = Hashes::of_bytes(b"abc");
+		let db = KnownFileDb::parse("# comment\n\n352441c2\tgame\tdir/a.bin\r\na9993e364706816aba3e25717850c26c9cd0d89d\tother\tb.bin\twith tab\n").unwrap();
+		assert_eq!(db.len(), 2);
+		let found = db.lookup(&abc).unwrap();
+		assert_eq!(found.game, "other");
+		assert_eq!(found.path, "b.bin\twith tab");
+		assert_eq!(db.lookup(&Hashes::of_bytes(b"")), None);
+		let crc_only = KnownFileDb::parse("352441c2\tgame\tdir/a.bin").unwrap();
+		assert_eq!(crc_only.lookup(&abc).unwrap().file_name(), "a.bin");
+	}
+
+	#[test]
+	fn parse_db_malformed() {
+		assert!(KnownFileDb::parse("352441c2\tgame").is_err());
+		assert!(KnownFileDb::parse("352441c2 game dir/a.bin").is_err());
+		assert!(KnownFileDb::parse("# ok\nnothex!!\tgame\ta.bin").is_err());
+		assert!(KnownFileDb::parse("352441c2\tgame\ta.bin\n12345\tgame\tb.bin").is_err());
+		assert!(KnownFileDb::parse("").unwrap().is_empty());
+	}
+}
@@ -1,5 +1,6 @@
-use std::{fmt::Display, mem::MaybeUninit};
+use std::{borrow::Cow, fmt::Display, fs::File, io::{BufWriter, Write}, path::{Path, PathBuf}, time::Duration};
 use bytemuck::{Pod, Zeroable};
+use crate::{quantize, swizzle::untwiddle_dc, DecodeOptions, Error};
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum PixelFormat {
@@ -37,6 +38,23 @@ pub enum PixelFormat {
 	Gray4
 }
 
+impl PixelFormat {
+	/// Reads a palette stored like this clut format's, or None if it isn't one of the RGB clut formats.
+	pub fn read_clut(self, clut: &[u8]) -> Option<Box<[Pixel]>> {
+		use PixelFormat::*;
+		let bits = if matches!(self, RgbaClut4 | RgbxClut4 | RgbClut4 | BgraClut4 | BgrxClut4 | BgrClut4) {4} else {8};
+		Some(match self {
+			RgbaClut8 | RgbaClut4 => read_palette(clut, bits, 4, |c| Pixel {r: c[0], g: c[1], b: c[2], a: c[3]}),
+			RgbxClut8 | RgbxClut4 => read_palette(clut, bits, 4, |c| Pixel {r: c[0], g: c[1], b: c[2], a: 255}),
+			RgbClut8 | RgbClut4 => read_palette(clut, bits, 3, |c| Pixel {r: c[0], g: c[1], b: c[2], a: 255}),
+			BgraClut8 | BgraClut4 => read_palette(clut, bits, 4, |c| Pixel {r: c[2], g: c[1], b: c[0], a: c[3]}),
+			BgrxClut8 | BgrxClut4 => read_palette(clut, bits, 4, |c| Pixel {r: c[2], g: c[1], b: c[0], a: 255}),
+			BgrClut8 | BgrClut4 => read_palette(clut, bits, 3, |c| Pixel {r: c[2], g: c[1], b: c[0], a: 255}),
+			_ => return None
+		})
+	}
+}
+
 impl<'a> From<&png::Info<'a>> for PixelFormat {
 	fn from(info: &png::Info) -> Self {
 		match (info.color_type, info.bit_depth) {
@@ -44,7 +62,8 @@ impl<'a> From<&png::Info<'a>> for PixelFormat {
 			(png::ColorType::Indexed, png::BitDepth::Eight) => PixelFormat::BgraClut8,
 			(png::ColorType::Rgb, _) => PixelFormat::Bgr,
 			(png::ColorType::Rgba, _) => PixelFormat::Bgra,
-			_ => unreachable!()
+			(png::ColorType::Indexed, _) => PixelFormat::BgraClut8,
+			(png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha, _) => PixelFormat::Gray8
 		}
 	}
 }
@@ -89,7 +108,7 @@ impl Display for PixelFormat {
 }
 
 #[repr(C)]
-#[derive(Zeroable, Pod, Clone, Copy, Default)]
+#[derive(Zeroable, Pod, Clone, Copy, Default, PartialEq, Eq)]
 pub struct Pixel {
 	pub r: u8,
 	pub g: u8,
@@ -97,11 +116,138 @@ pub struct Pixel {
 	pub a: u8
 }
 
+/// The palette indices a frame was looked up from, so it can be written back out without losing the palette.
+#[derive(Clone)]
+pub struct Indexed {
+	/// 4 or 8
+	pub bits: u8,
+	/// one for each pixel of the frame, in the same order
+	pub indices: Box<[u8]>,
+	/// every palette the indices can be looked up in, the first is the one the format uses by default
+	pub palettes: Vec<Box<[Pixel]>>,
+	/// the palette the frame's pixels are looked up in
+	pub selected: usize
+}
+
+impl Indexed {
+	pub fn palette(&self) -> &[Pixel] {
+		&self.palettes[self.selected]
+	}
+
+	/// Whether frames with these indices have their pixels looked up the same way.
+	pub fn same_palettes(&self, o: &Indexed) -> bool {
+		self.bits == o.bits && self.selected == o.selected && self.palettes == o.palettes
+	}
+}
+
+/// Where a frame goes in a bigger picture and how it was stored, for formats that say so.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Placement {
+	/// position of the frame's top left corner on the canvas
+	pub origin: Option<(i32, i32)>,
+	/// size of the whole picture the frame is part of
+	pub canvas_size: Option<(u32, u32)>,
+	/// size the pixels were stored at before being cropped to the frame, for formats that pad or align it
+	pub stored_size: Option<(u32, u32)>
+}
+
+impl Placement {
+	pub fn is_empty(&self) -> bool {
+		*self == Self::default()
+	}
+}
+
+impl Display for Placement {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let mut parts = Vec::new();
+		if let Some((x, y)) = self.origin {
+			parts.push(format!("at {x},{y}"));
+		}
+		if let Some((w, h)) = self.canvas_size {
+			parts.push(format!("on {w}x{h} canvas"));
+		}
+		if let Some((w, h)) = self.stored_size {
+			parts.push(format!("stored as {w}x{h}"));
+		}
+		write!(f, "{}", parts.join(", "))
+	}
+}
+
+/// How a format stores alpha, so it can be normalized when decoding and restored when encoding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlphaMode {
+	/// straight 0..255, also for formats without alpha
+	#[default]
+	Full,
+	/// 0..128 like the PS2's GS, where 128 is opaque, anything above it can't be restored
+	Ps2,
+	/// 0..127, widened by shifting and copying the lowest bit, so 127 is opaque
+	SevenBit,
+	/// only fully transparent or opaque, stored as 0 or 1
+	OneBit,
+	/// 0..255 with the color already multiplied by it
+	Premultiplied
+}
+
+impl AlphaMode {
+	/// Converts a pixel as the format stores it to straight 0..255 alpha.
+	pub fn normalize(self, p: Pixel) -> Pixel {
+		match self {
+			Self::Full => p,
+			Self::Ps2 => Pixel {a: (p.a as u16 * 255 / 128).min(255) as u8, ..p},
+			Self::SevenBit => Pixel {a: p.a << 1 | (p.a & 1), ..p},
+			Self::OneBit => Pixel {a: if p.a != 0 {255} else {0}, ..p},
+			Self::Premultiplied => {
+				let unmultiply = |c: u8| if p.a == 0 {0} else {((c as u32 * 255 + p.a as u32 / 2) / p.a as u32).min(255) as u8};
+				Pixel {r: unmultiply(p.r), g: unmultiply(p.g), b: unmultiply(p.b), a: p.a}
+			}
+		}
+	}
+
+	/// Converts a straight 0..255 pixel back to how the format stores it, the exact opposite of [`AlphaMode::normalize`].
+	pub fn denormalize(self, p: Pixel) -> Pixel {
+		match self {
+			Self::Full => p,
+			// rounding up undoes the rounding down of normalizing
+			Self::Ps2 => Pixel {a: ((p.a as u16 * 128 + 254) / 255) as u8, ..p},
+			Self::SevenBit => Pixel {a: p.a >> 1, ..p},
+			Self::OneBit => Pixel {a: if p.a >= 128 {1} else {0}, ..p},
+			Self::Premultiplied => {
+				let multiply = |c: u8| ((c as u32 * p.a as u32 + 127) / 255) as u8;
+				Pixel {r: multiply(p.r), g: multiply(p.g), b: multiply(p.b), a: p.a}
+			}
+		}
+	}
+
+	/// The closest pixel that stays the same after being stored in this mode and read back.
+	pub fn snap(self, p: Pixel) -> Pixel {
+		self.normalize(self.denormalize(p))
+	}
+}
+
+impl Display for AlphaMode {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", match self {
+			Self::Full => "full alpha",
+			Self::Ps2 => "PS2 alpha",
+			Self::SevenBit => "7-bit alpha",
+			Self::OneBit => "1-bit alpha",
+			Self::Premultiplied => "premultiplied alpha"
+		})
+	}
+}
+
+#[derive(Clone)]
 pub struct Frame {
 	pub width: u32,
 	pub height: u32,
 	pub og_fmt: PixelFormat,
-	pub pixels: Box<[Pixel]>
+	pub pixels: Box<[Pixel]>,
+	/// only for frames that were decoded from palette indices
+	pub indexed: Option<Indexed>,
+	pub placement: Placement,
+	/// how the format stored alpha, the pixels and palettes always have it normalized to straight 0..255
+	pub alpha_mode: AlphaMode
 }
 
 fn bits_2_to_8(x: u8) -> u8 {
@@ -126,333 +272,302 @@ fn bits_6_to_8(x: u8) -> u8 {
 	x << 2 | (x & 1) << 1 | (x & 1)
 }
 
-pub fn bit_twiddle(x: usize) -> usize {
-	(x & 1) | (x & 2) << 1 | (x & 4) << 2 | (x & 8) << 3 | (x & 16) << 4 | (x & 32) << 5 | (x & 64) << 6 | (x & 128) << 7 | (x & 256) << 8 | (x & 512) << 9
+// checks that the buffer has enough data for the frame and trims it to that
+fn pixel_data(width: u32, height: u32, bits_per_pixel: usize, buf: &[u8]) -> Result<&[u8], Error> {
+	let needed_size = (width as usize).checked_mul(height as usize)
+		.and_then(|x| x.checked_mul(bits_per_pixel))
+		.ok_or_else(|| Error::bad_field(format!("impossible frame size {width}x{height}")))?.div_ceil(8);
+	buf.get(..needed_size).ok_or_else(|| Error::truncated(format!("{width}x{height} frame needs {needed_size} bytes of pixel data, only {} available", buf.len())))
+}
+
+// the palette can be cut short, indices past its end are only an error when looking them up
+fn read_palette(clut: &[u8], bits: u8, entry_size: usize, to_pixel: impl Fn(&[u8]) -> Pixel) -> Box<[Pixel]> {
+	clut.chunks_exact(entry_size).take(1 << bits).map(to_pixel).collect()
+}
+
+fn look_up(indices: &[u8], palette: &[Pixel]) -> Result<Box<[Pixel]>, Error> {
+	indices.iter().map(|&x| palette.get(x as usize).copied().ok_or_else(|| Error::bad_field(format!("pixel index {x} is outside of the palette")))).collect()
+}
+
+// resizes any per-pixel buffer the way frames are resized
+fn resized_buf<T: Copy>(buf: &[T], width: u32, height: u32, w: u32, h: u32, fill: T) -> Vec<T> {
+	let mut out = if w == width {
+		buf.to_vec()
+	} else {
+		let mut out = Vec::with_capacity((w * h) as usize);
+		let common_w = width.min(w);
+		let common_h = height.min(h);
+		for y in 0..common_h {
+			out.extend_from_slice(&buf[(y * width) as usize..(y * width + common_w) as usize]);
+			for _ in common_w..w {
+				out.push(fill);
+			}
+		}
+		out
+	};
+	out.resize((w * h) as usize, fill);
+	out
+}
+
+// low nibble first, like every 4-bit format handled here
+fn nibbles(buf: &[u8]) -> impl Iterator<Item = u8> + '_ {
+	buf.iter().flat_map(|&x| [x & 0xF, x >> 4])
 }
 
+pub use crate::swizzle::bit_twiddle;
+pub use crate::quantize::QuantizeOptions;
+
 impl Frame {
 	pub fn empty(width: u32, height: u32, og_fmt: PixelFormat) -> Self {
 		Self {
-			width, height, og_fmt,
-			pixels: vec![Pixel {r: 0, g: 0, b: 0, a: 0}; (width * height) as usize].into()
+			width, height, og_fmt, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
+			pixels: vec![Pixel {r: 0, g: 0, b: 0, a: 0}; width as usize * height as usize].into()
 		}
 	}
 
-	pub fn from_rgba(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height * 4;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
+	/// A frame that is filled with the first color of `o`'s palette, or transparent if it has none,
+	/// so frames decoded like `o` can be pasted into it without losing their indices.
+	pub fn empty_like(width: u32, height: u32, o: &Frame) -> Self {
+		let indexed = o.indexed.as_ref().map(|x| Indexed {
+			bits: x.bits,
+			indices: vec![0; width as usize * height as usize].into(),
+			palettes: x.palettes.clone(),
+			selected: x.selected
+		});
+		let fill = indexed.as_ref().and_then(|x| x.palette().first().copied()).unwrap_or_default();
 		Self {
-			width, height, og_fmt: PixelFormat::Rgba,
-			pixels: bytemuck::cast_slice(buf).into()
+			width, height, og_fmt: o.og_fmt, indexed, placement: Placement::default(), alpha_mode: o.alpha_mode,
+			pixels: vec![fill; width as usize * height as usize].into()
 		}
 	}
 
-	pub fn from_rgba5551(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height * 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Rgba5551,
+	/// Looks the indices up in the palette, keeping both in the frame.
+	pub fn from_indexed(width: u32, height: u32, og_fmt: PixelFormat, bits: u8, mut indices: Box<[u8]>, palette: Box<[Pixel]>) -> Result<Self, Error> {
+		let pixel_count = width as usize * height as usize;
+		if indices.len() < pixel_count {
+			return Err(Error::truncated(format!("{width}x{height} frame needs {pixel_count} indices, only {} available", indices.len())));
+		}
+		if indices.len() > pixel_count {
+			indices = indices[..pixel_count].into();
+		}
+		let pixels = look_up(&indices, &palette)?;
+		Ok(Self {
+			width, height, og_fmt, pixels,
+			indexed: Some(Indexed {bits, indices, palettes: vec![palette], selected: 0}),
+			placement: Placement::default(),
+			alpha_mode: AlphaMode::Full
+		})
+	}
+
+	pub fn from_rgba(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 32, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Rgba, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
+			pixels: bytemuck::cast_slice(buf).into()
+		})
+	}
+
+	pub fn from_rgba5551(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 16, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Rgba5551, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::OneBit,
 			pixels: buf.chunks_exact(2).map(|x| Pixel {
 				r: bits_5_to_8(x[0]),
 				g: bits_5_to_8(x[0] >> 5 | x[1] << 3),
 				b: bits_5_to_8(x[1] >> 3),
 				a: if x[1] & 0x80 != 0 {0xFF} else {0}
 			}).collect()
-		}
+		})
 	}
 
-	pub fn from_bgra5551(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height * 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Bgra5551,
+	pub fn from_bgra5551(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 16, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Bgra5551, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::OneBit,
 			pixels: buf.chunks_exact(2).map(|x| Pixel {
 				r: bits_5_to_8(x[1] >> 3),
 				g: bits_5_to_8(x[0] >> 5 | x[1] << 3),
 				b: bits_5_to_8(x[0]),
 				a: if x[1] & 0x80 != 0 {0xFF} else {0}
 			}).collect()
-		}
+		})
 	}
 
-	pub fn from_rgba4444(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height * 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Rgba4444,
+	pub fn from_rgba4444(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 16, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Rgba4444, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
 			pixels: buf.chunks_exact(2).map(|x| Pixel {
 				r: bits_4_to_8(x[0]),
 				g: bits_4_to_8(x[0] >> 4),
 				b: bits_4_to_8(x[1]),
 				a: bits_4_to_8(x[1] >> 4)
 			}).collect()
-		}
+		})
 	}
 
-	pub fn from_bgra4444(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height * 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Bgra4444,
+	pub fn from_bgra4444(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 16, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Bgra4444, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
 			pixels: buf.chunks_exact(2).map(|x| Pixel {
 				r: bits_4_to_8(x[1]),
 				g: bits_4_to_8(x[0] >> 4),
 				b: bits_4_to_8(x[0]),
 				a: bits_4_to_8(x[1] >> 4)
 			}).collect()
-		}
+		})
 	}
 
-	pub fn from_rgb16(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height * 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Rgb565,
+	pub fn from_rgb16(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 16, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Rgb565, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
 			pixels: buf.chunks_exact(2).map(|x| Pixel {
 				r: bits_5_to_8(x[0]),
 				g: bits_6_to_8(x[0] >> 5 | x[1] << 3),
 				b: bits_5_to_8(x[1] >> 3),
 				a: 0xFF
 			}).collect()
-		}
+		})
 	}
 
-	pub fn from_bgr565(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height * 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Bgr565,
+	pub fn from_bgr565(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 16, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Bgr565, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
 			pixels: buf.chunks_exact(2).map(|x| Pixel {
 				r: bits_5_to_8(x[1] >> 3),
 				g: bits_6_to_8(x[0] >> 5 | x[1] << 3),
 				b: bits_5_to_8(x[0]),
 				a: 0xFF
 			}).collect()
-		}
+		})
 	}
 
-	pub fn from_rgba_clut8(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Self {
-		let needed_size = width * height;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::RgbaClut8,
-			pixels: buf.iter().cloned().map(|x| Pixel {r: clut[x as usize * 4 + 0], g: clut[x as usize * 4 + 1], b: clut[x as usize * 4 + 2], a: clut[x as usize * 4 + 3]}).collect()
-		}
+	pub fn from_rgba_clut8(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 8, buf)?;
+		Self::from_indexed(width, height, PixelFormat::RgbaClut8, 8, buf.into(), read_palette(clut, 8, 4, |c| Pixel {r: c[0], g: c[1], b: c[2], a: c[3]}))
 	}
 
-	pub fn from_rgba_clut4(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Self {
-		let needed_size = width * height / 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::RgbaClut4,
-			pixels: buf.iter().cloned().map(|x| [
-				Pixel {r: clut[(x as usize & 0xF) * 4 + 0], g: clut[(x as usize & 0xF) * 4 + 1], b: clut[(x as usize & 0xF) * 4 + 2], a: clut[(x as usize & 0xF) * 4 + 3]},
-				Pixel {r: clut[(x as usize >> 4) * 4 + 0], g: clut[(x as usize >> 4) * 4 + 1], b: clut[(x as usize >> 4) * 4 + 2], a: clut[(x as usize >> 4) * 4 + 3]}
-			]).flatten().collect()
-		}
+	pub fn from_rgba_clut4(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 4, buf)?;
+		Self::from_indexed(width, height, PixelFormat::RgbaClut4, 4, nibbles(buf).take(width as usize * height as usize).collect(), read_palette(clut, 4, 4, |c| Pixel {r: c[0], g: c[1], b: c[2], a: c[3]}))
 	}
 
-	pub fn from_bgra(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height * 4;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Bgra,
+	pub fn from_bgra(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 32, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Bgra, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
 			pixels: buf.chunks_exact(4).map(|x| Pixel {r: x[2], g: x[1], b: x[0], a: x[3]}).collect()
-		}
+		})
 	}
 
-	pub fn from_bgra_clut8(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Self {
-		let needed_size = width * height;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::BgraClut8,
-			pixels: buf.iter().cloned().map(|x| Pixel {r: clut[x as usize * 4 + 2], g: clut[x as usize * 4 + 1], b: clut[x as usize * 4 + 0], a: clut[x as usize * 4 + 3]}).collect()
-		}
+	pub fn from_bgra_clut8(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 8, buf)?;
+		Self::from_indexed(width, height, PixelFormat::BgraClut8, 8, buf.into(), read_palette(clut, 8, 4, |c| Pixel {r: c[2], g: c[1], b: c[0], a: c[3]}))
 	}
 
-	pub fn from_bgra_clut4(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Self {
-		let needed_size = width * height / 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::BgraClut4,
-			pixels: buf.iter().cloned().map(|x| [
-				Pixel {r: clut[(x as usize & 0xF) * 4 + 2], g: clut[(x as usize & 0xF) * 4 + 1], b: clut[(x as usize & 0xF) * 4 + 0], a: clut[(x as usize & 0xF) * 4 + 3]},
-				Pixel {r: clut[(x as usize >> 4) * 4 + 2], g: clut[(x as usize >> 4) * 4 + 1], b: clut[(x as usize >> 4) * 4 + 0], a: clut[(x as usize >> 4) * 4 + 3]}
-			]).flatten().collect()
-		}
+	pub fn from_bgra_clut4(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 4, buf)?;
+		Self::from_indexed(width, height, PixelFormat::BgraClut4, 4, nibbles(buf).take(width as usize * height as usize).collect(), read_palette(clut, 4, 4, |c| Pixel {r: c[2], g: c[1], b: c[0], a: c[3]}))
 	}
 
-	pub fn from_rgbx(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height * 4;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Rgbx,
+	pub fn from_rgbx(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 32, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Rgbx, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
 			pixels: buf.chunks_exact(4).map(|x| Pixel {r: x[0], g: x[1], b: x[2], a: 255}).collect()
-		}
+		})
 	}
 
-	pub fn from_rgbx_clut8(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Self {
-		let needed_size = width * height;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::RgbxClut8,
-			pixels: buf.iter().cloned().map(|x| Pixel {r: clut[x as usize * 4 + 0], g: clut[x as usize * 4 + 1], b: clut[x as usize * 4 + 2], a: 255}).collect()
-		}
+	pub fn from_rgbx_clut8(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 8, buf)?;
+		Self::from_indexed(width, height, PixelFormat::RgbxClut8, 8, buf.into(), read_palette(clut, 8, 4, |c| Pixel {r: c[0], g: c[1], b: c[2], a: 255}))
 	}
 
-	pub fn from_rgbx_clut4(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Self {
-		let needed_size = width * height / 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::RgbxClut4,
-			pixels: buf.iter().cloned().map(|x| [
-				Pixel {r: clut[(x as usize & 0xF) * 4 + 0], g: clut[(x as usize & 0xF) * 4 + 1], b: clut[(x as usize & 0xF) * 4 + 2], a: 255},
-				Pixel {r: clut[(x as usize >> 4) * 4 + 0], g: clut[(x as usize >> 4) * 4 + 1], b: clut[(x as usize >> 4) * 4 + 2], a: 255}
-			]).flatten().collect()
-		}
+	pub fn from_rgbx_clut4(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 4, buf)?;
+		Self::from_indexed(width, height, PixelFormat::RgbxClut4, 4, nibbles(buf).take(width as usize * height as usize).collect(), read_palette(clut, 4, 4, |c| Pixel {r: c[0], g: c[1], b: c[2], a: 255}))
 	}
 
-	pub fn from_bgrx(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height * 4;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Bgrx,
+	pub fn from_bgrx(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 32, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Bgrx, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
 			pixels: buf.chunks_exact(4).map(|x| Pixel {r: x[2], g: x[1], b: x[0], a: 255}).collect()
-		}
+		})
 	}
 
-	pub fn from_bgrx_clut8(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Self {
-		let needed_size = width * height;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::BgrxClut8,
-			pixels: buf.iter().cloned().map(|x| Pixel {r: clut[x as usize * 4 + 2], g: clut[x as usize * 4 + 1], b: clut[x as usize * 4 + 0], a: 255}).collect()
-		}
+	pub fn from_bgrx_clut8(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 8, buf)?;
+		Self::from_indexed(width, height, PixelFormat::BgrxClut8, 8, buf.into(), read_palette(clut, 8, 4, |c| Pixel {r: c[2], g: c[1], b: c[0], a: 255}))
 	}
 
-	pub fn from_bgrx_clut4(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Self {
-		let needed_size = width * height / 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::BgrxClut4,
-			pixels: buf.iter().cloned().map(|x| [
-				Pixel {r: clut[(x as usize & 0xF) * 4 + 2], g: clut[(x as usize & 0xF) * 4 + 1], b: clut[(x as usize & 0xF) * 4 + 0], a: 255},
-				Pixel {r: clut[(x as usize >> 4) * 4 + 2], g: clut[(x as usize >> 4) * 4 + 1], b: clut[(x as usize >> 4) * 4 + 0], a: 255}
-			]).flatten().collect()
-		}
+	pub fn from_bgrx_clut4(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 4, buf)?;
+		Self::from_indexed(width, height, PixelFormat::BgrxClut4, 4, nibbles(buf).take(width as usize * height as usize).collect(), read_palette(clut, 4, 4, |c| Pixel {r: c[2], g: c[1], b: c[0], a: 255}))
 	}
 
-	pub fn from_rgb(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height * 3;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Rgb,
+	pub fn from_rgb(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 24, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Rgb, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
 			pixels: buf.chunks_exact(3).map(|x| Pixel {r: x[0], g: x[1], b: x[2], a: 255}).collect()
-		}
+		})
 	}
 
-	pub fn from_rgb_clut8(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Self {
-		let needed_size = width * height;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::RgbClut8,
-			pixels: buf.iter().cloned().map(|x| Pixel {r: clut[x as usize * 3 + 0], g: clut[x as usize * 3 + 1], b: clut[x as usize * 3 + 2], a: 255}).collect()
-		}
+	pub fn from_rgb_clut8(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 8, buf)?;
+		Self::from_indexed(width, height, PixelFormat::RgbClut8, 8, buf.into(), read_palette(clut, 8, 3, |c| Pixel {r: c[0], g: c[1], b: c[2], a: 255}))
 	}
 
-	pub fn from_rgb_clut4(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Self {
-		let needed_size = width * height / 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::RgbClut4,
-			pixels: buf.iter().cloned().map(|x| [
-				Pixel {r: clut[(x as usize & 0xF) * 3 + 0], g: clut[(x as usize & 0xF) * 3 + 1], b: clut[(x as usize & 0xF) * 3 + 2], a: 255},
-				Pixel {r: clut[(x as usize >> 4) * 3 + 0], g: clut[(x as usize >> 4) * 3 + 1], b: clut[(x as usize >> 4) * 3 + 2], a: 255}
-			]).flatten().collect()
-		}
+	pub fn from_rgb_clut4(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 4, buf)?;
+		Self::from_indexed(width, height, PixelFormat::RgbClut4, 4, nibbles(buf).take(width as usize * height as usize).collect(), read_palette(clut, 4, 3, |c| Pixel {r: c[0], g: c[1], b: c[2], a: 255}))
 	}
 
-	pub fn from_bgr(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height * 3;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Bgr,
+	pub fn from_bgr(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 24, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Bgr, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
 			pixels: buf.chunks_exact(3).map(|x| Pixel {r: x[2], g: x[1], b: x[0], a: 255}).collect()
-		}
+		})
 	}
 
-	pub fn from_bgr_clut8(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Self {
-		let needed_size = width * height;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::BgrClut8,
-			pixels: buf.iter().cloned().map(|x| Pixel {r: clut[x as usize * 3 + 2], g: clut[x as usize * 3 + 1], b: clut[x as usize * 3 + 0], a: 255}).collect()
-		}
+	pub fn from_bgr_clut8(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 8, buf)?;
+		Self::from_indexed(width, height, PixelFormat::BgrClut8, 8, buf.into(), read_palette(clut, 8, 3, |c| Pixel {r: c[2], g: c[1], b: c[0], a: 255}))
 	}
 
-	pub fn from_bgr_clut4(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Self {
-		let needed_size = width * height / 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::BgrClut4,
-			pixels: buf.iter().cloned().map(|x| [
-				Pixel {r: clut[(x as usize & 0xF) * 3 + 2], g: clut[(x as usize & 0xF) * 3 + 1], b: clut[(x as usize & 0xF) * 3 + 0], a: 255},
-				Pixel {r: clut[(x as usize >> 4) * 3 + 2], g: clut[(x as usize >> 4) * 3 + 1], b: clut[(x as usize >> 4) * 3 + 0], a: 255}
-			]).flatten().collect()
-		}
+	pub fn from_bgr_clut4(width: u32, height: u32, clut: &[u8], buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 4, buf)?;
+		Self::from_indexed(width, height, PixelFormat::BgrClut4, 4, nibbles(buf).take(width as usize * height as usize).collect(), read_palette(clut, 4, 3, |c| Pixel {r: c[2], g: c[1], b: c[0], a: 255}))
 	}
 
-	pub fn from_gray8(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Gray8,
+	pub fn from_gray8(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 8, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Gray8, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
 			pixels: buf.iter().map(|&x| Pixel {
 				r: bits_3_to_8(x >> 5),
 				g: bits_3_to_8(x >> 2),
 				b: bits_2_to_8(x),
 				a: 255
 			}).collect()
-		}
+		})
 	}
 
-	pub fn from_gray4(width: u32, height: u32, buf: &[u8]) -> Self {
-		let needed_size = width * height / 2;
-		assert!(buf.len() as u32 >= needed_size);
-		let buf = &buf[0..needed_size as usize];
-		Self {
-			width, height, og_fmt: PixelFormat::Bgra,
+	pub fn from_gray4(width: u32, height: u32, buf: &[u8]) -> Result<Self, Error> {
+		let buf = pixel_data(width, height, 4, buf)?;
+		Ok(Self {
+			width, height, og_fmt: PixelFormat::Bgra, indexed: None, placement: Placement::default(), alpha_mode: AlphaMode::Full,
 			pixels: buf.iter().map(|&x| {
 				let a = bits_4_to_8(x);
 				let b = bits_4_to_8(x >> 4);
 				[Pixel {r: a, g: a, b: a, a: 255}, Pixel {r: b, g: b, b: b, a: 255}]
-			}).flatten().collect()
-		}
+			}).flatten().take(width as usize * height as usize).collect()
+		})
 	}
 
 	pub fn with_og_fmt(mut self, og_fmt: PixelFormat) -> Self {
@@ -460,13 +575,63 @@ impl Frame {
 		self
 	}
 
-	pub fn with_double_alpha(mut self) -> Self {
-		for p in &mut self.pixels {
-			p.a = (p.a as u16 * 255 / 128).min(255) as u8;
+	pub fn with_placement(mut self, placement: Placement) -> Self {
+		self.placement = placement;
+		self
+	}
+
+	/// Marks the frame as having been read with alpha stored like `alpha_mode`, normalizing its pixels and palettes.
+	pub fn with_alpha_mode(mut self, alpha_mode: AlphaMode) -> Self {
+		let palettes = self.indexed.iter_mut().flat_map(|x| x.palettes.iter_mut());
+		for p in self.pixels.iter_mut().chain(palettes.flat_map(|x| x.iter_mut())) {
+			*p = alpha_mode.normalize(*p);
 		}
+		self.alpha_mode = alpha_mode;
 		self
 	}
 
+	/// The pixels with alpha stored the way the format stores it.
+	pub fn raw_pixels(&self) -> Cow<'_, [Pixel]> {
+		match self.alpha_mode {
+			AlphaMode::Full => Cow::Borrowed(&self.pixels),
+			x => Cow::Owned(self.pixels.iter().map(|&p| x.denormalize(p)).collect())
+		}
+	}
+
+	/// The selected palette with alpha stored the way the format stores it.
+	pub fn raw_palette(&self) -> Option<Vec<Pixel>> {
+		self.indexed.as_ref().map(|x| x.palette().iter().map(|&p| self.alpha_mode.denormalize(p)).collect())
+	}
+
+	/// Adds alternate palettes the frame's indices can be looked up in with [`Frame::select_palette`], if it has indices.
+	pub fn with_palettes(mut self, palettes: impl IntoIterator<Item = Box<[Pixel]>>) -> Self {
+		if let Some(indexed) = &mut self.indexed {
+			indexed.palettes.extend(palettes);
+		}
+		self
+	}
+
+	/// Looks the frame's indices up in another of its palettes.
+	pub fn select_palette(&mut self, palette_idx: usize) -> Result<(), Error> {
+		let indexed = self.indexed.as_mut().ok_or_else(|| Error::unsupported("frame has no palette"))?;
+		let palette = indexed.palettes.get(palette_idx)
+			.ok_or_else(|| Error::bad_field(format!("palette {palette_idx} does not exist, the frame has {}", indexed.palettes.len())))?;
+		self.pixels = look_up(&indexed.indices, palette)?;
+		indexed.selected = palette_idx;
+		Ok(())
+	}
+
+	/// Picks a palette for the frame with median cut and looks every pixel up in it, to write it to an indexed format.
+	/// The palette only has colors that survive being stored in the frame's alpha mode.
+	pub fn quantized(&self, opts: &QuantizeOptions) -> Result<Self, Error> {
+		let palette = quantize::palette(&self.pixels, self.alpha_mode, opts)?;
+		let indices = quantize::indices(&self.pixels, self.width, &palette, self.alpha_mode, opts)?;
+		let og_fmt = if opts.bits == 4 {PixelFormat::RgbaClut4} else {PixelFormat::RgbaClut8};
+		let mut frame = Self::from_indexed(self.width, self.height, og_fmt, opts.bits, indices.into(), palette.into())?.with_placement(self.placement);
+		frame.alpha_mode = self.alpha_mode;
+		Ok(frame)
+	}
+
 	pub fn row(&self, y: u32) -> &[Pixel] {
 		let w = self.width as usize;
 		&self.pixels[y as usize * w..y as usize * w + w]
@@ -478,24 +643,15 @@ impl Frame {
 	}
 
 	pub fn resize(&mut self, w: u32, h: u32) {
-		let mut pixels = if w == self.width {
-			self.pixels.to_vec()
-		} else {
-			let mut pixels = Vec::with_capacity((w * h) as usize);
-			let common_w = self.width.min(w);
-			let common_h = self.height.min(h);
-			for y in 0..common_h {
-				for x in 0..common_w {
-					pixels.push(self.pixels[(y * self.width + x) as usize]);
-				}
-				for _ in common_w..w {
-					pixels.push(Pixel::zeroed());
-				}
+		// no index means transparent, so the indices can only be kept when cropping
+		if w <= self.width && h <= self.height {
+			if let Some(indexed) = &mut self.indexed {
+				indexed.indices = resized_buf(&indexed.indices, self.width, self.height, w, h, 0).into();
 			}
-			pixels
-		};
-		pixels.resize((w * h) as usize, Pixel::zeroed());
-		self.pixels = pixels.into_boxed_slice();
+		} else {
+			self.indexed = None;
+		}
+		self.pixels = resized_buf(&self.pixels, self.width, self.height, w, h, Pixel::zeroed()).into();
 		self.width = w;
 		self.height = h;
 	}
@@ -505,8 +661,9 @@ impl Frame {
 		self
 	}
 
+	/// Frames with no pixels are returned as they are.
 	pub fn crushed_down(mut self, w: u32, h: u32) -> Self {
-		if w == self.width && h == self.height {
+		if (w == self.width && h == self.height) || self.width == 0 || self.height == 0 {
 			return self;
 		}
 		let mut pixels = self.pixels.into_vec();
@@ -527,32 +684,45 @@ impl Frame {
 		}
 		pixels.resize((w * h) as usize, Pixel::zeroed());
 		self.pixels = pixels.into();
+		self.indexed = None;
 		self.width = w;
 		self.height = h;
 		self
 	}
 
-	pub fn twiddled_dc(mut self) -> Self {
-		let mut pixels = Vec::with_capacity(self.pixels.len());
-		for y in 0..self.width as usize {
-			let twiddled_y = bit_twiddle(y);
-			for x in 0..self.height as usize {
-				pixels.push(self.pixels[twiddled_y | bit_twiddle(x) << 1]);
+	pub fn twiddled_dc(mut self) -> Result<Self, Error> {
+		self.pixels = untwiddle_dc(&self.pixels, self.width as usize, self.height as usize)?.into();
+		if let Some(indexed) = &mut self.indexed {
+			indexed.indices = untwiddle_dc(&indexed.indices, self.width as usize, self.height as usize)?.into();
+		}
+		Ok(self)
+	}
+
+	/// A copy of the `w`x`h` part of the frame at `x`,`y`, which has to be inside of it.
+	pub fn cropped(&self, x: u32, y: u32, w: u32, h: u32) -> Self {
+		let mut out = Self::empty_like(w, h, self);
+		let (x, w) = (x as usize, w as usize);
+		for row_y in 0..h {
+			out.row_mut(row_y).copy_from_slice(&self.row(y + row_y)[x..x + w]);
+		}
+		if let (Some(dst), Some(src)) = (&mut out.indexed, &self.indexed) {
+			for (dst_row, src_y) in dst.indices.chunks_exact_mut(w.max(1)).zip(y as usize..) {
+				let src_start = src_y * self.width as usize + x;
+				dst_row.copy_from_slice(&src.indices[src_start..src_start + w]);
 			}
 		}
-		self.pixels = pixels.into();
-		std::mem::swap(&mut self.width, &mut self.height);
-		self
+		out
 	}
 
 	pub fn paste(&mut self, x: u32, y: u32, o: &Frame) {
-		let end_x = (x + o.width).min(self.width);
-		let end_y = (y + o.height).min(self.height);
+		let end_x = x.saturating_add(o.width).min(self.width);
+		let end_y = y.saturating_add(o.height).min(self.height);
 		if end_x > x && end_y > y {
 			for row_y in y..end_y {
 				self.row_mut(row_y)[x as usize..end_x as usize].copy_from_slice(&o.row(row_y - y)[..(end_x - x) as usize]);
 			}
 		}
+		self.paste_indices(x, y, o);
 	}
 
 	pub fn paste_resizing(&mut self, x: u32, y: u32, o: &Frame) {
@@ -562,6 +732,25 @@ impl Frame {
 		for oy in 0..o.height {
 			self.row_mut(y + oy)[x as usize..(x + o.width) as usize].copy_from_slice(o.row(oy));
 		}
+		self.paste_indices(x, y, o);
+	}
+
+	// the indices only stay valid if the pasted frame was looked up in the same palettes
+	fn paste_indices(&mut self, x: u32, y: u32, o: &Frame) {
+		if let (Some(dst), Some(src)) = (&mut self.indexed, &o.indexed) && dst.same_palettes(src) {
+			let end_x = x.saturating_add(o.width).min(self.width);
+			let end_y = y.saturating_add(o.height).min(self.height);
+			if end_x > x {
+				let len = (end_x - x) as usize;
+				for row_y in y..end_y {
+					let dst_start = (row_y * self.width + x) as usize;
+					let src_start = ((row_y - y) * o.width) as usize;
+					dst.indices[dst_start..dst_start + len].copy_from_slice(&src.indices[src_start..src_start + len]);
+				}
+			}
+		} else {
+			self.indexed = None;
+		}
 	}
 
 	pub fn as_rgba_bytes(&self) -> &[u8] {
@@ -569,6 +758,511 @@ impl Frame {
 	}
 }
 
+/// Timing for images whose frames are an animation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Animation {
+	/// how long each frame is shown, in the same order as the frames
+	pub durations: Box<[Duration]>,
+	/// whether it starts over after the last frame
+	pub looping: bool
+}
+
+impl Animation {
+	/// For animations that don't say how long their frames are.
+	pub const DEFAULT_DURATION: Duration = Duration::from_millis(100);
+
+	pub fn uniform(frame_count: usize, duration: Duration, looping: bool) -> Self {
+		Self {durations: vec![duration; frame_count].into(), looping}
+	}
+
+	pub fn duration(&self, frame_idx: usize) -> Duration {
+		self.durations.get(frame_idx).copied().unwrap_or(Self::DEFAULT_DURATION)
+	}
+}
+
+/// Where a frame is in an atlas.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AtlasRect {
+	pub x: u32,
+	pub y: u32,
+	pub width: u32,
+	pub height: u32
+}
+
+impl Display for AtlasRect {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}x{} at {},{}", self.width, self.height, self.x, self.y)
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtlasLayout {
+	/// cells as big as the biggest frame, `columns` to a row, or about as many columns as rows if 0
+	Grid {columns: u32},
+	/// rows of frames from the tallest down, each at most `max_width` wide, or about as wide as the atlas is tall if 0
+	Shelves {max_width: u32}
+}
+
+impl Default for AtlasLayout {
+	fn default() -> Self {
+		Self::Grid {columns: 0}
+	}
+}
+
+/// Every frame of an image packed into one, see [`Image::to_atlas`] and [`Image::from_atlas`].
+pub struct Atlas {
+	pub frame: Frame,
+	/// one for each frame, in the same order
+	pub rects: Box<[AtlasRect]>
+}
+
+impl Atlas {
+	/// The size of the atlas and the rects, as `{"width": 64, "height": 32, "frames": [{"x": 0, "y": 0, "width": 32, "height": 32}, ...]}`.
+	pub fn rects_json(&self) -> String {
+		let frames = self.rects.iter()
+			.map(|x| format!("\t\t{{\"x\": {}, \"y\": {}, \"width\": {}, \"height\": {}}}", x.x, x.y, x.width, x.height))
+			.collect::<Vec<_>>();
+		format!("{{\n\t\"width\": {},\n\t\"height\": {},\n\t\"frames\": [\n{}\n\t]\n}}\n", self.frame.width, self.frame.height, frames.join(",\n"))
+	}
+
+	/// One rect on each line after a `x,y,width,height` header.
+	pub fn rects_csv(&self) -> String {
+		let mut out = String::from("x,y,width,height\n");
+		for x in &self.rects {
+			out += &format!("{},{},{},{}\n", x.x, x.y, x.width, x.height);
+		}
+		out
+	}
+
+	/// Reads rects written by [`Atlas::rects_csv`], skipping the header and empty lines.
+	pub fn parse_rects_csv(text: &str) -> Result<Box<[AtlasRect]>, Error> {
+		let mut rects = Vec::new();
+		for (i, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with(|c: char| c.is_alphabetic()) {
+				continue;
+			}
+			let fields = line.split(',').map(|x| x.trim().parse::<u32>()).collect::<Result<Vec<_>, _>>()
+				.map_err(|e| Error::bad_field(format!("line {}: {e}", i + 1)))?;
+			let &[x, y, width, height] = fields.as_slice() else {
+				return Err(Error::bad_field(format!("line {} has {} fields, expected 4", i + 1, fields.len())));
+			};
+			rects.push(AtlasRect {x, y, width, height});
+		}
+		Ok(rects.into())
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+	#[default]
+	Png,
+	Bmp,
+	Tga,
+	/// always lossless
+	WebP,
+	Qoi,
+	/// 256 colors and 1-bit alpha for each frame
+	Gif
+}
+
+impl ExportFormat {
+	pub fn extension(self) -> &'static str {
+		match self {
+			Self::Png => "png",
+			Self::Bmp => "bmp",
+			Self::Tga => "tga",
+			Self::WebP => "webp",
+			Self::Qoi => "qoi",
+			Self::Gif => "gif"
+		}
+	}
+
+	fn image_format(self) -> ::image::ImageFormat {
+		match self {
+			Self::Png => ::image::ImageFormat::Png,
+			Self::Bmp => ::image::ImageFormat::Bmp,
+			Self::Tga => ::image::ImageFormat::Tga,
+			Self::WebP => ::image::ImageFormat::WebP,
+			Self::Qoi => ::image::ImageFormat::Qoi,
+			Self::Gif => ::image::ImageFormat::Gif
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportLayout {
+	/// the first frame is written to the path and the others get their index before the extension, like `name.1.png`
+	#[default]
+	FilePerFrame,
+	/// every frame in one image, see [`Image::to_atlas`]
+	SpriteSheet(AtlasLayout),
+	/// one animated file, only for PNG and GIF
+	Animated
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExportOptions {
+	pub format: ExportFormat,
+	pub layout: ExportLayout,
+	/// write frames that have palette indices as indexed PNGs with their selected palette, instead of truecolor. Animations can't be indexed
+	pub indexed: bool
+}
+
+// frames after the first get their index before the extension
+fn frame_path(path: &Path, frame_idx: usize) -> PathBuf {
+	if frame_idx == 0 {
+		return path.into();
+	}
+	let mut name = path.file_stem().unwrap_or_default().to_os_string();
+	name.push(format!(".{frame_idx}"));
+	if let Some(ext) = path.extension() {
+		name.push(".");
+		name.push(ext);
+	}
+	path.with_file_name(name)
+}
+
+fn write_indexed_png(out: impl Write, width: u32, height: u32, indexed: &Indexed) -> Result<(), Error> {
+	let png_error = |e: png::EncodingError| Error::io(format!("could not write indexed PNG: {e}"));
+	let palette = indexed.palette();
+	if palette.len() > 256 {
+		return Err(Error::unsupported(format!("palette has {} colors, an indexed PNG can only have 256", palette.len())));
+	}
+	// a PNG palette can't have more colors than its indices reach, so 4-bit indices with a bigger one are written as 8-bit
+	let four_bit = indexed.bits == 4 && palette.len() <= 16;
+	let mut encoder = png::Encoder::new(out, width, height);
+	encoder.set_color(png::ColorType::Indexed);
+	encoder.set_depth(if four_bit {png::BitDepth::Four} else {png::BitDepth::Eight});
+	encoder.set_palette(palette.iter().flat_map(|x| [x.r, x.g, x.b]).collect::<Vec<_>>());
+	encoder.set_trns(palette.iter().map(|x| x.a).collect::<Vec<_>>());
+	let mut writer = encoder.write_header().map_err(png_error)?;
+	if four_bit {
+		// PNG packs the first pixel in the high nibble, and each row starts on a new byte
+		let packed = indexed.indices.chunks(width.max(1) as usize)
+			.flat_map(|row| row.chunks(2).map(|x| x[0] << 4 | x.get(1).copied().unwrap_or_default() & 0xF))
+			.collect::<Vec<_>>();
+		writer.write_image_data(&packed).map_err(png_error)?;
+	} else {
+		writer.write_image_data(&indexed.indices).map_err(png_error)?;
+	}
+	writer.finish().map_err(png_error)
+}
+
+fn write_frame(frame: &Frame, path: &Path, opts: &ExportOptions) -> Result<(), Error> {
+	if opts.indexed && opts.format == ExportFormat::Png && let Some(indexed) = &frame.indexed {
+		return write_indexed_png(BufWriter::new(File::create(path)?), frame.width, frame.height, indexed);
+	}
+	::image::save_buffer_with_format(path, frame.as_rgba_bytes(), frame.width, frame.height, ::image::ExtendedColorType::Rgba8, opts.format.image_format())
+		.map_err(|e| Error::io(format!("could not write {}: {e}", opts.format.extension().to_uppercase())))
+}
+
 pub struct Image {
-	pub frames: Box<[Frame]>
+	pub frames: Box<[Frame]>,
+	/// only for images known to be animations, others can still be played with [`Image::animation_or_default`]
+	pub animation: Option<Animation>
+}
+
+impl Image {
+	pub fn with_animation(mut self, animation: Animation) -> Self {
+		self.animation = Some(animation);
+		self
+	}
+
+	/// The image's timing, or every frame shown for [`Animation::DEFAULT_DURATION`] in a loop.
+	pub fn animation_or_default(&self) -> Cow<'_, Animation> {
+		match &self.animation {
+			Some(x) => Cow::Borrowed(x),
+			None => Cow::Owned(Animation::uniform(self.frames.len(), Animation::DEFAULT_DURATION, true))
+		}
+	}
+
+	// animation formats need every frame to be the same size, so they are put on a canvas that fits them all at their origins.
+	// the origins come from the file, so the canvas is held to the default frame limits like a decoded frame
+	fn canvas_frames(&self) -> Result<(u32, u32, Vec<Cow<'_, Frame>>), Error> {
+		let origin = |frame: &Frame| frame.placement.origin.map_or((0, 0), |(x, y)| (x.max(0) as u32, y.max(0) as u32));
+		let too_big = || Error::limit_exceeded("frame origins put the canvas past u32::MAX pixels");
+		let mut width = 0u32;
+		let mut height = 0u32;
+		for frame in &self.frames {
+			width = width.max(origin(frame).0.checked_add(frame.width).ok_or_else(too_big)?);
+			height = height.max(origin(frame).1.checked_add(frame.height).ok_or_else(too_big)?);
+		}
+		DecodeOptions::default().check_frame(width, height)?;
+		let frames = self.frames.iter().map(|frame| if frame.width == width && frame.height == height {
+			Cow::Borrowed(frame)
+		} else {
+			let mut canvas = Frame::empty(width, height, frame.og_fmt);
+			canvas.paste(origin(frame).0, origin(frame).1, frame);
+			Cow::Owned(canvas)
+		}).collect();
+		Ok((width, height, frames))
+	}
+
+	/// Packs every frame into one, keeping the palette indices if all of them have the same palettes.
+	/// Fails if the atlas would be more than `u32::MAX` pixels across.
+	pub fn to_atlas(&self, layout: AtlasLayout) -> Result<Atlas, Error> {
+		let too_big = || Error::limit_exceeded(format!("{} frames do not fit in one atlas", self.frames.len()));
+		let count = u32::try_from(self.frames.len()).map_err(|_| too_big())?;
+		let widest = self.frames.iter().map(|x| x.width).max().unwrap_or(0);
+		let tallest = self.frames.iter().map(|x| x.height).max().unwrap_or(0);
+		let mut rects = vec![AtlasRect::default(); self.frames.len()];
+		let (width, height) = match layout {
+			AtlasLayout::Grid {columns} => {
+				let columns = if columns == 0 {(count as f64).sqrt().ceil() as u32} else {columns}.clamp(1, count.max(1));
+				for (i, (rect, frame)) in rects.iter_mut().zip(&self.frames).enumerate() {
+					*rect = AtlasRect {x: i as u32 % columns * widest, y: i as u32 / columns * tallest, width: frame.width, height: frame.height};
+				}
+				// the cells are all smaller than the atlas, so only its size can overflow
+				(widest.checked_mul(columns).ok_or_else(too_big)?, tallest.checked_mul(count.div_ceil(columns)).ok_or_else(too_big)?)
+			}
+			AtlasLayout::Shelves {max_width} => {
+				let max_width = if max_width == 0 {
+					let area = self.frames.iter().map(|x| x.width as f64 * x.height as f64).sum::<f64>();
+					area.sqrt().ceil() as u32
+				} else {
+					max_width
+				}.max(widest);
+				let mut order = (0..self.frames.len()).collect::<Vec<_>>();
+				order.sort_by_key(|&i| std::cmp::Reverse(self.frames[i].height));
+				let (mut x, mut y, mut shelf_height, mut width) = (0u32, 0u32, 0, 0);
+				for i in order {
+					let frame = &self.frames[i];
+					if x > 0 && x.checked_add(frame.width).is_none_or(|x| x > max_width) {
+						x = 0;
+						y = y.checked_add(shelf_height).ok_or_else(too_big)?;
+						shelf_height = 0;
+					}
+					rects[i] = AtlasRect {x, y, width: frame.width, height: frame.height};
+					x = x.checked_add(frame.width).ok_or_else(too_big)?;
+					width = width.max(x);
+					shelf_height = shelf_height.max(frame.height);
+				}
+				(width, y.checked_add(shelf_height).ok_or_else(too_big)?)
+			}
+		};
+		let first = self.frames.first();
+		let same_palettes = first.is_some_and(|first| self.frames.iter().all(|frame| match (&frame.indexed, &first.indexed) {
+			(Some(x), Some(y)) => x.same_palettes(y),
+			_ => false
+		}));
+		let mut frame = match first {
+			Some(first) if same_palettes => Frame::empty_like(width, height, first),
+			_ => Frame::empty(width, height, first.map_or(PixelFormat::Rgba, |x| x.og_fmt))
+		};
+		for (rect, o) in rects.iter().zip(&self.frames) {
+			frame.paste(rect.x, rect.y, o);
+		}
+		Ok(Atlas {frame, rects: rects.into()})
+	}
+
+	/// Cuts every rect out of an atlas as a frame, in the same order.
+	pub fn from_atlas(frame: &Frame, rects: &[AtlasRect]) -> Result<Self, Error> {
+		if rects.is_empty() {
+			return Err(Error::bad_field("atlas has no rects"));
+		}
+		let frames = rects.iter().enumerate().map(|(i, rect)| {
+			let fits = rect.x.checked_add(rect.width).is_some_and(|x| x <= frame.width)
+				&& rect.y.checked_add(rect.height).is_some_and(|y| y <= frame.height);
+			if fits {
+				Ok(frame.cropped(rect.x, rect.y, rect.width, rect.height))
+			} else {
+				Err(Error::bad_field(format!("rect {i} ({rect}) is outside of the {}x{} atlas", frame.width, frame.height)))
+			}
+		}).collect::<Result<_, _>>()?;
+		Ok(Self {frames, animation: None})
+	}
+
+	/// Saves the image in the format and layout from the options, returning the paths of the files written.
+	/// The path is used as is, see [`ExportFormat::extension`] for the usual extensions.
+	pub fn export(&self, path: impl AsRef<Path>, opts: &ExportOptions) -> Result<Vec<PathBuf>, Error> {
+		let path = path.as_ref();
+		if self.frames.is_empty() {
+			return Err(Error::unsupported("no frames to export"));
+		}
+		match opts.layout {
+			ExportLayout::FilePerFrame => {
+				let mut paths = Vec::with_capacity(self.frames.len());
+				for (i, frame) in self.frames.iter().enumerate() {
+					let frame_path = frame_path(path, i);
+					write_frame(frame, &frame_path, opts)?;
+					paths.push(frame_path);
+				}
+				Ok(paths)
+			}
+			ExportLayout::SpriteSheet(layout) => {
+				write_frame(&self.to_atlas(layout)?.frame, path, opts)?;
+				Ok(vec![path.into()])
+			}
+			ExportLayout::Animated => {
+				if opts.indexed {
+					return Err(Error::unsupported("animations cannot be exported as indexed"));
+				}
+				match opts.format {
+					ExportFormat::Png => self.write_apng(BufWriter::new(File::create(path)?))?,
+					ExportFormat::Gif => self.write_gif(BufWriter::new(File::create(path)?))?,
+					x => return Err(Error::unsupported(format!("{} cannot be animated", x.extension().to_uppercase())))
+				}
+				Ok(vec![path.into()])
+			}
+		}
+	}
+
+	/// Writes every frame as an animated PNG.
+	pub fn write_apng(&self, out: impl Write) -> Result<(), Error> {
+		let (width, height, frames) = self.canvas_frames()?;
+		let animation = self.animation_or_default();
+		let png_error = |e: png::EncodingError| Error::io(format!("could not write APNG: {e}"));
+		let mut encoder = png::Encoder::new(out, width, height);
+		encoder.set_color(png::ColorType::Rgba);
+		encoder.set_depth(png::BitDepth::Eight);
+		// 0 plays means forever
+		encoder.set_animated(frames.len() as u32, if animation.looping {0} else {1}).map_err(png_error)?;
+		let mut writer = encoder.write_header().map_err(png_error)?;
+		for (i, frame) in frames.iter().enumerate() {
+			let delay_ms = animation.duration(i).as_millis().min(u16::MAX as u128) as u16;
+			writer.set_frame_delay(delay_ms, 1000).map_err(png_error)?;
+			writer.write_image_data(frame.as_rgba_bytes()).map_err(png_error)?;
+		}
+		writer.finish().map_err(png_error)
+	}
+
+	/// Writes every frame as an animated GIF, which reduces each to 256 colors and 1-bit alpha.
+	pub fn write_gif(&self, out: impl Write) -> Result<(), Error> {
+		use ::image::{codecs::gif::{GifEncoder, Repeat}, Delay, RgbaImage};
+		let (width, height, frames) = self.canvas_frames()?;
+		let animation = self.animation_or_default();
+		let gif_error = |e: ::image::ImageError| Error::io(format!("could not write GIF: {e}"));
+		let mut encoder = GifEncoder::new(out);
+		encoder.set_repeat(if animation.looping {Repeat::Infinite} else {Repeat::Finite(0)}).map_err(gif_error)?;
+		encoder.encode_frames(frames.iter().enumerate().map(|(i, frame)| {
+			let buf = RgbaImage::from_raw(width, height, frame.as_rgba_bytes().to_vec()).unwrap();
+			::image::Frame::from_parts(buf, 0, 0, Delay::from_saturating_duration(animation.duration(i)))
+		})).map_err(gif_error)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn alpha_mode_round_trip() {
+		let px = |a: u8| Pixel {r: 10, g: 20, b: 30, a};
+		for a in [0, 0x40, 0x7F, 0x80] {
+			assert!(AlphaMode::Ps2.denormalize(AlphaMode::Ps2.normalize(px(a))) == px(a), "{a:#x}");
+		}
+		assert_eq!(AlphaMode::Ps2.normalize(px(0x80)).a, 255);
+		for a in 0..0x80 {
+			assert!(AlphaMode::SevenBit.denormalize(AlphaMode::SevenBit.normalize(px(a))) == px(a), "{a:#x}");
+		}
+		assert_eq!(AlphaMode::SevenBit.normalize(px(0x7F)).a, 0xFF);
+		assert_eq!(AlphaMode::SevenBit.normalize(px(0x40)).a, 0x80);
+		for mode in [AlphaMode::Full, AlphaMode::Ps2, AlphaMode::SevenBit, AlphaMode::OneBit, AlphaMode::Premultiplied] {
+			for a in [0, 1, 0x7F, 0x80, 0xFF] {
+				let snapped = mode.snap(px(a));
+				assert!(mode.snap(snapped) == snapped, "{mode} {a:#x}");
+			}
+		}
+	}
+	fn test_image(indexed: bool) -> Image {
+		let palette = (0..16).map(|x| Pixel {r: x * 16, g: 255 - x * 16, b: x, a: 255}).collect::<Box<[Pixel]>>();
+		let frames = [(3, 5), (8, 2), (1, 1), (6, 6), (4, 7)].into_iter().enumerate().map(|(i, (w, h))| {
+			let indices = (0..w * h).map(|x| ((x + i as u32) % 16) as u8).collect();
+			Frame::from_indexed(w, h, PixelFormat::RgbaClut4, 4, indices, palette.clone()).unwrap()
+		});
+		let frames = if indexed {
+			frames.collect()
+		} else {
+			frames.map(|x| Frame::from_rgba(x.width, x.height, x.as_rgba_bytes()).unwrap()).collect()
+		};
+		Image {frames, animation: None}
+	}
+
+	#[test]
+	fn atlas_round_trip() {
+		for indexed in [false, true] {
+			let image = test_image(indexed);
+			for layout in [AtlasLayout::Grid {columns: 0}, AtlasLayout::Grid {columns: 2}, AtlasLayout::Shelves {max_width: 0}, AtlasLayout::Shelves {max_width: 9}] {
+				let atlas = image.to_atlas(layout).unwrap();
+				assert_eq!(atlas.frame.indexed.is_some(), indexed);
+				let rects = Atlas::parse_rects_csv(&atlas.rects_csv()).unwrap();
+				assert_eq!(rects, atlas.rects);
+				let back = Image::from_atlas(&atlas.frame, &rects).unwrap();
+				assert_eq!(back.frames.len(), image.frames.len());
+				for (a, b) in back.frames.iter().zip(&image.frames) {
+					assert_eq!((a.width, a.height), (b.width, b.height), "{layout:?}");
+					assert!(a.pixels == b.pixels, "{layout:?}");
+					assert_eq!(a.indexed.as_ref().map(|x| &x.indices), b.indexed.as_ref().map(|x| &x.indices));
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn atlas_too_big() {
+		let wide = Frame::empty(u32::MAX / 2 + 1, 0, PixelFormat::Rgba);
+		let image = Image {frames: vec![wide.clone(), wide].into(), animation: None};
+		assert!(image.to_atlas(AtlasLayout::Grid {columns: 2}).is_err());
+		assert!(image.to_atlas(AtlasLayout::Grid {columns: 1}).is_ok());
+		let outside = [AtlasRect {x: 1, y: 0, width: u32::MAX, height: 1}];
+		assert!(Image::from_atlas(&Frame::empty(4, 4, PixelFormat::Rgba), &outside).is_err());
+	}
+	#[test]
+	fn canvas_from_bad_origins() {
+		let far = Frame::empty(16, 16, PixelFormat::Rgba).with_placement(Placement {origin: Some((i32::MAX, 0)), ..Default::default()});
+		let image = Image {frames: vec![Frame::empty(16, 16, PixelFormat::Rgba), far].into(), animation: None};
+		assert_eq!(image.write_apng(std::io::sink()).unwrap_err().kind, crate::ErrorKind::LimitExceeded);
+		assert_eq!(image.write_gif(std::io::sink()).unwrap_err().kind, crate::ErrorKind::LimitExceeded);
+		let overflow = Frame {width: u32::MAX, ..Frame::empty(0, 1, PixelFormat::Rgba)}.with_placement(Placement {origin: Some((1, 0)), ..Default::default()});
+		let image = Image {frames: vec![overflow].into(), animation: None};
+		assert!(image.write_apng(std::io::sink()).is_err());
+		let moved = Frame::empty(4, 4, PixelFormat::Rgba).with_placement(Placement {origin: Some((4, 2)), ..Default::default()});
+		let image = Image {frames: vec![Frame::empty(4, 4, PixelFormat::Rgba), moved].into(), animation: None};
+		let (width, height, frames) = image.canvas_frames().unwrap();
+		assert_eq!((width, height, frames.len()), (8, 6, 2));
+	}
+	#[test]
+	fn crush_empty_frame() {
+		for (width, height) in [(0, 0), (0, 32), (32, 0)] {
+			let frame = Frame::empty(width, height, PixelFormat::Rgba).crushed_down(16, 16);
+			assert_eq!((frame.width, frame.height), (width, height));
+		}
+		let frame = Frame::empty(64, 64, PixelFormat::Rgba).crushed_down(32, 32);
+		assert_eq!((frame.width, frame.height, frame.pixels.len()), (32, 32, 32 * 32));
+	}
+	#[test]
+	fn indexed_png_depth() {
+		let decode = |buf: &[u8]| {
+			let mut reader = png::Decoder::new(buf).read_info().unwrap();
+			let mut out = vec![0u8; reader.output_buffer_size()];
+			let info = reader.next_frame(&mut out).unwrap();
+			(info.bit_depth, reader.info().palette.as_ref().unwrap().len() / 3, out)
+		};
+		let indices = (0..16).collect::<Box<[u8]>>();
+		let palette = (0..17).map(|x| Pixel {r: x, g: x, b: x, a: 255}).collect::<Box<[Pixel]>>();
+		let mut buf = Vec::new();
+		let frame = Frame::from_indexed(4, 4, PixelFormat::RgbaClut4, 4, indices.clone(), palette[..16].into()).unwrap();
+		write_indexed_png(&mut buf, 4, 4, frame.indexed.as_ref().unwrap()).unwrap();
+		let (depth, colors, out) = decode(&buf);
+		assert_eq!((depth, colors), (png::BitDepth::Four, 16));
+		assert_eq!(out, indices.chunks(2).map(|x| x[0] << 4 | x[1]).collect::<Vec<_>>());
+		// too many colors for 4 bits
+		buf.clear();
+		let frame = Frame::from_indexed(4, 4, PixelFormat::RgbaClut4, 4, indices.clone(), palette).unwrap();
+		write_indexed_png(&mut buf, 4, 4, frame.indexed.as_ref().unwrap()).unwrap();
+		let (depth, colors, out) = decode(&buf);
+		assert_eq!((depth, colors), (png::BitDepth::Eight, 17));
+		assert_eq!(out, &indices[..]);
+		let palette = (0..257).map(|x| Pixel {r: x as u8, g: 0, b: 0, a: 255}).collect::<Box<[Pixel]>>();
+		let frame = Frame::from_indexed(4, 4, PixelFormat::RgbaClut8, 8, indices, palette).unwrap();
+		assert!(write_indexed_png(std::io::sink(), 4, 4, frame.indexed.as_ref().unwrap()).is_err());
+	}
+	#[test]
+	fn indexed_animation() {
+		let path = std::env::temp_dir().join(format!("kidfile-test-animated-{}.png", std::process::id()));
+		let opts = ExportOptions {format: ExportFormat::Png, layout: ExportLayout::Animated, indexed: true};
+		assert_eq!(test_image(true).export(&path, &opts).unwrap_err().kind, crate::ErrorKind::Unsupported);
+		assert!(!path.exists());
+	}
 }
@@ -1,31 +1,87 @@
-use crate::{byte_slice::ByteSlice, image::{Frame, Image, PixelFormat}, Certainty, Decoder};
+use crate::{binary_struct, byte_slice::ByteSlice, image::{AlphaMode, Frame, Image, PixelFormat}, Decoder, Detection, Error, HeaderInfo, Progress};
+
+binary_struct! {
+	// one per frame in the index section, followed by its tile indices
+	struct BipFrameIndex("bip frame index", Little) {
+		0 => tile_count: u16,
+		2 => paletted: u16,
+		8 => width: u16,
+		10 => height: u16
+	}
+
+	// tile index of size 2, positions and sizes are in blocks
+	struct BipBlockTile("bip block tile index", Little) {
+		4 => x: u8,
+		5 => y: u8,
+		6 => x_blocks: u8,
+		7 => y_blocks: u8
+	}
+
+	// tile index of size 7
+	struct BipPngTile("bip png tile index", Little) {
+		8 => x: u16,
+		10 => y: u16,
+		20 => full_width: u16,
+		22 => full_height: u16,
+		// from the start of the pixel section
+		24 => data_offset: u32
+	}
+
+	struct BipPngFile("bip PNGFILE2 header", Little) {
+		// not counting the first 8 bytes
+		32 => size: u32,
+		116 => x_offset: u32,
+		120 => y_offset: u32
+	}
+}
 
 pub const ENTRY_BIP: Decoder<Image> = Decoder {
 	id: "bip",
 	desc: "Remember11 image format",
-	detect: |file| Certainty::certain_if(matches!(file.get_u32_at(0), Some(5 | 10))),
-	decode: |file| {
-		let bytes = file.read();
+	detect: |file| match file.get_u32_at(0) {
+		Some(x @ (5 | 10)) => Detection::certain(format!("header word count {x}")),
+		Some(x) => Detection::impossible(format!("header word count {x} is not 5 or 10")),
+		None => Detection::impossible("too small")
+	},
+	// only the size of the first frame, counting them means walking all the tile indices
+	identify: |file| {
+		let frame = file.get_u32_at(4).and_then(|x| BipFrameIndex::read_from(file, x as usize).ok());
+		HeaderInfo {
+			width: frame.map(|x| x.width as u32),
+			height: frame.map(|x| x.height as u32),
+			..Default::default()
+		}
+	},
+	decode: |file, opts| {
+		let bytes = file.read()?;
 		let mut is_remember11 = false;
 		'retry: loop {
 			let mut frames = Vec::new();
 			let header_end = bytes.read_u32(0)? as usize * 4;
+			if header_end < 8 {
+				return Err(Error::bad_field(format!("header of {header_end} bytes is too small")).at(0));
+			}
 			let mut palette_section = bytes.read_u32(header_end - 8)? as usize;
 			let pixel_section = bytes.read_u32(header_end - 4)? as usize;
 			let mut index_section = bytes.read_u32(4)? as usize;
 			let mut src_block_x_idx = 0;
 			let mut src_block_y_idx = 0;
 			loop {
-				let tile_count = bytes.read_u16(index_section)? as usize;
-				if tile_count == 0 {
+				opts.poll(Progress::Bytes {done: index_section, total: bytes.len()})?;
+				// the list of frames ends with a tile count of 0, without the rest of the index
+				if bytes.read_u16(index_section)? == 0 {
 					break;
 				}
-				let is_paletted = bytes.read_u16(index_section + 2)? as u32 != 0;
+				let frame_index = BipFrameIndex::parse(bytes, index_section)?;
+				let tile_count = frame_index.tile_count as usize;
+				let is_paletted = frame_index.paletted != 0;
 				let pixel_bytes = if is_paletted {1} else {4};
 				let pseudo_block_size = if is_paletted {32} else {16};
 				let real_block_size = if is_paletted {30} else {if is_remember11 {14} else {16}};
-				let og_full_width = bytes.read_u16(index_section + 8)? as u32;
-				let og_full_height = bytes.read_u16(index_section + 10)? as u32;
+				let og_full_width = frame_index.width as u32;
+				let og_full_height = frame_index.height as u32;
+				opts.check_frames(frames.len() + 1)?;
+				opts.check_frame(og_full_width, og_full_height).map_err(|e| e.at(index_section + 8))?;
 				let mut cur_frame: Option<Frame> = None;
 				index_section += 12;
 				let mut next_palette_section = 0;
@@ -34,23 +90,26 @@ pub const ENTRY_BIP: Decoder<Image> = Decoder {
 					match tile_size {
 						0 => break,
 						2 => { // RGBA clut8
-							let tile_x = bytes.read_u8(index_section + 4)? as u32 * real_block_size;
-							let tile_y = bytes.read_u8(index_section + 5)? as u32 * real_block_size;
-							let tile_x_blocks = bytes.read_u8(index_section + 6)? as u32;
-							let tile_y_blocks = bytes.read_u8(index_section + 7)? as u32;
+							let tile = BipBlockTile::parse(bytes, index_section)?;
+							let tile_x = tile.x as u32 * real_block_size;
+							let tile_y = tile.y as u32 * real_block_size;
+							let tile_x_blocks = tile.x_blocks as u32;
+							let tile_y_blocks = tile.y_blocks as u32;
 							// this is a heuristic, but it seems to work well: if the tile is past the borders beyond what's necessary, change the block size and retry
 							// this is because R11 non-paletted images have the repeated lines in each block (as described below), but E17 ones don't
 							if tile_x + tile_x_blocks * real_block_size >= og_full_width + real_block_size || tile_y + tile_y_blocks * real_block_size >= og_full_height + real_block_size {
 								if is_remember11 {
-									return Err("tile overflow".into());
+									return Err(Error::bad_field("tile overflow").at(index_section));
 								} else {
 									is_remember11 = true;
 									continue 'retry;
 								}
 							}
-							let palette = &bytes[palette_section..];
-							let frame = cur_frame.get_or_insert_with(|| {
-								Frame::empty(og_full_width, og_full_height, if is_paletted {PixelFormat::RgbaClut8} else {PixelFormat::Rgba})
+							let palette = bytes.get(palette_section..).unwrap_or_default();
+							let frame = cur_frame.get_or_insert_with(|| if is_paletted {
+								Frame::empty(og_full_width, og_full_height, PixelFormat::RgbaClut8)
+							} else {
+								Frame::empty(og_full_width, og_full_height, PixelFormat::Rgba).with_alpha_mode(AlphaMode::Ps2)
 							});
 							// this part is SUPER quirky. the source image is split into 30x30 blocks, but the first and last rows and columns are
 							// repeated so the blocks grow to 32x32. however, the blocks aren't stored sequentially, but rather as an image
@@ -71,9 +130,9 @@ pub const ENTRY_BIP: Decoder<Image> = Decoder {
 										break;
 									}
 									let row = if is_paletted {
-										Frame::from_rgba_clut8(real_block_size, 1, palette, &bytes[src_block_start..])
+										Frame::from_rgba_clut8(real_block_size, 1, palette, &bytes[src_block_start..])?
 									} else {
-										Frame::from_rgba(real_block_size, 1, &bytes[src_block_start..]).with_double_alpha()
+										Frame::from_rgba(real_block_size, 1, &bytes[src_block_start..])?.with_alpha_mode(AlphaMode::Ps2)
 									};
 									frame.paste(tile_x + dst_block_x_idx * real_block_size, dst_y, &row);
 									src_block_start += 512 * pixel_bytes;
@@ -94,28 +153,35 @@ pub const ENTRY_BIP: Decoder<Image> = Decoder {
 							}
 						}
 						7 => { // png (?)
-							let png_full_width = bytes.read_u16(index_section + 20)? as u32;
-							let png_full_height = bytes.read_u16(index_section + 22)? as u32;
-							let tile_x = bytes.read_u16(index_section + 8)? as u32;
-							let tile_y = bytes.read_u16(index_section + 10)? as u32;
-							let tile_pixel_data = pixel_section + bytes.read_u32(index_section + 24)? as usize;
+							let tile = BipPngTile::parse(bytes, index_section)?;
+							let png_full_width = tile.full_width as u32;
+							let png_full_height = tile.full_height as u32;
+							opts.check_frame(png_full_width, png_full_height).map_err(|e| e.at(index_section + 20))?;
+							let tile_x = tile.x as u32;
+							let tile_y = tile.y as u32;
+							let tile_pixel_data = pixel_section + tile.data_offset as usize;
 
-							let tile_data_end = tile_pixel_data + 8 + bytes.read_u32(tile_pixel_data + 32)? as usize;
-							let tile_x_off = bytes.read_u32(tile_pixel_data + 116)?;
-							let tile_y_off = bytes.read_u32(tile_pixel_data + 120)?;
+							let png_file = BipPngFile::parse(bytes, tile_pixel_data)?;
+							let tile_data_end = tile_pixel_data + 8 + png_file.size as usize;
+							let tile_x_off = png_file.x_offset;
+							let tile_y_off = png_file.y_offset;
 
-							let mut decoder = png::Decoder::new(&bytes[tile_pixel_data + 132..tile_data_end]);
+							let png_data = bytes.get(tile_pixel_data + 132..tile_data_end).ok_or_else(|| Error::truncated("PNGFILE2 data out of bounds").at(tile_pixel_data))?;
+							let mut decoder = png::Decoder::new(png_data);
 							decoder.set_transformations(png::Transformations::normalize_to_color8().union(png::Transformations::ALPHA));
-							let mut reader = decoder.read_info().map_err(|e| format!("in PNGFILE2 PNG info: {}", e))?;
+							let mut reader = decoder.read_info().map_err(|e| Error::corrupt(format!("in PNGFILE2 PNG info: {}", e)).at(tile_pixel_data + 132))?;
+							opts.check_frame(reader.info().width, reader.info().height).map_err(|e| e.at(tile_pixel_data + 132))?;
 							let mut bgra_buf = vec![0u8; reader.output_buffer_size()];
-							let info = reader.next_frame(bgra_buf.as_mut()).map_err(|e| format!("in PNGFILE2 PNG frame: {}", e))?;
-							assert_eq!(info.buffer_size(), (info.width * info.height * 4) as usize);
+							let info = reader.next_frame(bgra_buf.as_mut()).map_err(|e| Error::corrupt(format!("in PNGFILE2 PNG frame: {}", e)).at(tile_pixel_data + 132))?;
+							if info.buffer_size() != info.width as usize * info.height as usize * 4 {
+								return Err(Error::unsupported(format!("PNGFILE2 PNG has color type {:?}, expected RGBA", info.color_type)).at(tile_pixel_data + 132));
+							}
 
-							let frame = cur_frame.get_or_insert_with(|| Frame::empty(png_full_width, png_full_height, reader.info().into()));
-							let tile = Frame::from_bgra(info.width, info.height, &bgra_buf).with_double_alpha();
+							let frame = cur_frame.get_or_insert_with(|| Frame::empty(png_full_width, png_full_height, reader.info().into()).with_alpha_mode(AlphaMode::Ps2));
+							let tile = Frame::from_bgra(info.width, info.height, &bgra_buf)?.with_alpha_mode(AlphaMode::Ps2);
 							frame.paste(tile_x + tile_x_off, tile_y + tile_y_off, &tile);
 						}
-						_ => return Err(format!("unhandled bip tile index size {tile_size}"))
+						_ => return Err(Error::unsupported(format!("unhandled bip tile index size {tile_size}")).at(index_section))
 					}
 					index_section += tile_size * 4;
 				}
@@ -125,9 +191,9 @@ pub const ENTRY_BIP: Decoder<Image> = Decoder {
 				}
 			}
 			if frames.is_empty() {
-				return Err("no frames decoded".into());
+				return Err(Error::corrupt("no frames decoded"));
 			} else {
-				return Ok(Image {frames: frames.into_boxed_slice()});
+				return Ok(Image {frames: frames.into_boxed_slice(), animation: None});
 			}
 		}
 	}
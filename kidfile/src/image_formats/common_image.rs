@@ -1,38 +1,105 @@
-use crate::{file_data::FileData, image::{Frame, Image}, Certainty, Decoder};
+use std::{io::Cursor, time::Duration};
+use image::{codecs::{gif::GifDecoder, png::PngDecoder}, AnimationDecoder, ImageDecoder};
+use crate::{file_data::FileData, image::{Animation, Frame, Image}, DecodeOptions, Decoder, Detection, Error, HeaderInfo};
 
 pub const ENTRY_PNG: Decoder<Image> = Decoder {
 	id: "png",
 	desc: "PNG",
-	detect: |buf| Certainty::certain_if(buf.starts_with(b"\x89PNG\x0d\x0a\x1a\x0a")),
+	detect: |buf| Detection::magic(buf, b"\x89PNG\x0d\x0a\x1a\x0a", 0),
+	identify,
 	decode
 };
 
 pub const ENTRY_JPEG: Decoder<Image> = Decoder {
 	id: "jpeg",
 	desc: "JPEG",
-	detect: |buf| Certainty::certain_if(buf.starts_with(&[0xff, 0xd8, 0xff])),
+	detect: |buf| Detection::magic(buf, &[0xff, 0xd8, 0xff], 0),
+	identify,
 	decode
 };
 
 pub const ENTRY_BMP: Decoder<Image> = Decoder {
 	id: "bmp",
 	desc: "BMP",
-	detect: |buf| Certainty::certain_if(buf.starts_with(b"BM")),
+	detect: |buf| Detection::magic(buf, b"BM", 0),
+	identify,
 	decode
 };
 
 pub const ENTRY_GIF: Decoder<Image> = Decoder {
 	id: "gif",
 	desc: "GIF",
-	detect: |buf| Certainty::certain_if(buf.starts_with(b"GIF89a") || buf.starts_with(b"GIF87a")),
+	detect: |buf| Detection::any_magic(buf, &[b"GIF89a", b"GIF87a"], 0),
+	identify,
 	decode
 };
 
-fn decode(file: &mut FileData) -> Result<Image, String> {
-	let loaded = image::load_from_memory(file.read()).map_err(|e| e.to_string())?;
+// plenty for the headers of all of these, even a jpeg with some metadata before the frame header
+const IDENTIFY_PREFIX: usize = 64 * 1024;
+
+fn identify(file: &mut FileData) -> HeaderInfo {
+	let mut prefix = vec![0u8; file.len().min(IDENTIFY_PREFIX)];
+	if file.read_chunk_exact(&mut prefix, 0).is_err() {
+		return HeaderInfo::default();
+	}
+	let size = image::ImageReader::new(Cursor::new(prefix)).with_guessed_format().ok().and_then(|x| x.into_dimensions().ok());
+	HeaderInfo {width: size.map(|x| x.0), height: size.map(|x| x.1), frame_count: Some(1), ..Default::default()}
+}
+
+fn limits(opts: &DecodeOptions) -> image::Limits {
+	let mut limits = image::Limits::default();
+	limits.max_image_width = Some(opts.max_frame_width);
+	limits.max_image_height = Some(opts.max_frame_height);
+	limits.max_alloc = Some(opts.max_output_bytes as u64);
+	limits
+}
+
+fn map_error(e: image::ImageError) -> Error {
+	match e {
+		image::ImageError::Limits(e) => Error::limit_exceeded(e.to_string()),
+		e => Error::corrupt(e.to_string())
+	}
+}
+
+// neither format's decoder says how many times the animation plays, so they are assumed to loop like most do
+fn decode_animation<'a>(decoder: impl AnimationDecoder<'a>, opts: &DecodeOptions) -> Result<Image, Error> {
+	let mut frames = Vec::new();
+	let mut durations = Vec::new();
+	for frame in decoder.into_frames() {
+		opts.check_frames(frames.len() + 1)?;
+		let frame = frame.map_err(map_error)?;
+		durations.push(Duration::from(frame.delay()));
+		let buf = frame.into_buffer();
+		frames.push(Frame::from_rgba(buf.width(), buf.height(), &buf)?);
+	}
+	if frames.is_empty() {
+		return Err(Error::corrupt("animation has no frames"));
+	}
+	let animation = (frames.len() > 1).then(|| Animation {durations: durations.into(), looping: true});
+	Ok(Image {frames: frames.into(), animation})
+}
+
+fn decode(file: &mut FileData, opts: &DecodeOptions) -> Result<Image, Error> {
+	let buf = file.read()?;
+	if buf.starts_with(b"GIF") {
+		let mut decoder = GifDecoder::new(Cursor::new(buf)).map_err(map_error)?;
+		decoder.set_limits(limits(opts)).map_err(map_error)?;
+		return decode_animation(decoder, opts);
+	}
+	if buf.starts_with(b"\x89PNG") {
+		let mut decoder = PngDecoder::new(Cursor::new(buf)).map_err(map_error)?;
+		if decoder.is_apng().map_err(map_error)? {
+			decoder.set_limits(limits(opts)).map_err(map_error)?;
+			return decode_animation(decoder.apng().map_err(map_error)?, opts);
+		}
+	}
+	let mut reader = image::ImageReader::new(Cursor::new(buf)).with_guessed_format()?;
+	reader.limits(limits(opts));
+	let loaded = reader.decode().map_err(map_error)?;
 	Ok(Image {
 		frames: Box::new([
-			Frame::from_rgba(loaded.width() as u32, loaded.height() as u32, &loaded.to_rgba8())
-		])
+			Frame::from_rgba(loaded.width() as u32, loaded.height() as u32, &loaded.to_rgba8())?
+		]),
+		animation: None
 	})
 }
\ No newline at end of file
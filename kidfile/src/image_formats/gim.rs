@@ -1,10 +1,40 @@
 use std::borrow::Cow;
 
 use bytemuck::Zeroable;
-use crate::{byte_slice::ByteSlice, image::{Frame, Image, Pixel}, Certainty, Decoder};
+use crate::{binary_struct, byte_slice::ByteSlice, file_data::FileData, image::{Frame, Image, Pixel, Placement}, Decoder, Detection, Error, HeaderInfo, Progress};
 
 // https://www.psdevwiki.com/ps3/Graphic_Image_Map_(GIM)
 
+binary_struct! {
+	struct GimBlockHeader("gim block", Little) {
+		0 => id: u16,
+		// all offsets are from the start of the block
+		4 => end_offset: u32,
+		8 => next_offset: u32,
+		12 => data_offset: u32
+	}
+
+	// at the block's data offset
+	struct GimImageHeader("gim image block", Little) {
+		4 => format: u16,
+		6 => pixel_order: u16,
+		8 => width: u16,
+		10 => height: u16,
+		14 => width_alignment: u16,
+		// from the start of this header
+		28 => pixel_offset: u32
+	}
+
+	struct GimPaletteHeader("gim palette block", Little) {
+		4 => format: u16,
+		// in colors
+		8 => width: u16,
+		// both from the start of this header
+		28 => palette_offset: u32,
+		32 => palette_end: u32
+	}
+}
+
 struct GimBlock {
 	next: usize,
 	next_skipping_children: usize,
@@ -13,26 +43,69 @@ struct GimBlock {
 }
 
 impl GimBlock {
-	pub fn parse(buf: &[u8], pos: usize) -> Result<Self, String> {
-		Ok(Self {
-			next: pos + buf.read_u32(pos + 8)? as usize,
-			next_skipping_children: pos + buf.read_u32(pos + 4)? as usize,
-			id: buf.read_u16(pos)?,
-			data_start: pos + buf.read_u32(pos + 12)? as usize
-		})
+	pub fn parse(buf: &[u8], pos: usize) -> Result<Self, Error> {
+		let header = GimBlockHeader::parse(buf, pos)?;
+		let block = Self {
+			next: pos + header.next_offset as usize,
+			next_skipping_children: pos + header.end_offset as usize,
+			id: header.id,
+			data_start: pos + header.data_offset as usize
+		};
+		// a zero size would make the block walk loop forever
+		if block.next <= pos || block.next_skipping_children <= pos {
+			return Err(Error::bad_field("block does not advance").at(pos + 4));
+		}
+		Ok(block)
 	}
 }
 
+// block headers are small, but there could be a lot of them
+const IDENTIFY_BLOCK_LIMIT: usize = 64;
+
+fn identify(file: &mut FileData) -> HeaderInfo {
+	let mut info = HeaderInfo::default();
+	let mut frame_count = 0;
+	let mut pos = 16;
+	for _ in 0..IDENTIFY_BLOCK_LIMIT {
+		if pos >= file.len() {
+			info.frame_count = Some(frame_count);
+			break;
+		}
+		let Ok(block) = GimBlockHeader::read_from(file, pos) else {
+			break;
+		};
+		if block.id == 4 { // image block
+			frame_count += 1;
+			if info.width.is_none() {
+				if let Ok(image) = GimImageHeader::read_from(file, pos + block.data_offset as usize) {
+					info.width = Some(image.width as u32);
+					info.height = Some(image.height as u32);
+				}
+			}
+		}
+		if block.next_offset == 0 {
+			break;
+		}
+		pos += block.next_offset as usize;
+	}
+	info
+}
+
 pub const ENTRY_GIM: Decoder<Image> = Decoder {
 	id: "gim",
 	desc: "PlayStation Portable official image format",
-	detect: |file| Certainty::certain_if(file.starts_with(b"MIG\x2E00.1PSP\0")),
-	decode: |file| {
-		let buf = file.read();
+	detect: |file| Detection::magic(file, b"MIG\x2E00.1PSP\0", 0),
+	identify,
+	decode: |file, opts| {
+		let buf = file.read()?;
 		let mut frames = Vec::new();
 		let mut pos = 16;
 		let mut cur_palette: &[u8] = &[];
+		// the rest of the palettes in the same block, as alternates
+		let mut alt_palettes: &[u8] = &[];
+		let mut palette_size = 0;
 		while pos < buf.len() {
+			opts.poll(Progress::Bytes {done: pos, total: buf.len()})?;
 			let block = GimBlock::parse(buf, pos)?;
 			if block.id == 3 { // picture block (children: image, palette)
 				// search for a palette, so that we already have it set when getting to the image block
@@ -40,32 +113,48 @@ pub const ENTRY_GIM: Decoder<Image> = Decoder {
 				while child_pos < block.next_skipping_children {
 					let child_block = GimBlock::parse(buf, child_pos)?;
 					if child_block.id == 5 { // palette block
-						let palette_start = child_block.data_start + buf.read_u32(child_block.data_start + 28)? as usize;
-						cur_palette = &buf[palette_start..palette_start + 1024];
+						let header = GimPaletteHeader::parse(buf, child_block.data_start)?;
+						let palette_start = child_block.data_start + header.palette_offset as usize;
+						let palette_end = (child_block.data_start + header.palette_end as usize).min(buf.len());
+						cur_palette = buf.get(palette_start..(palette_start + 1024).min(buf.len())).unwrap_or_default();
+						// only RGBA8888 palettes are read, which have 4 bytes per color
+						palette_size = if header.format == 3 {header.width as usize * 4} else {0};
+						alt_palettes = if palette_size > 0 {
+							buf.get(palette_start + palette_size..palette_end).unwrap_or_default()
+						} else {
+							&[]
+						};
 						break;
 					}
 					child_pos = child_block.next;
 				}
 			} else if block.id == 4 { // image block
-				let format = buf.read_u16(block.data_start + 4)? as usize;
-				let swizzled = buf.read_u16(block.data_start + 6)? != 0;
-				let width = buf.read_u16(block.data_start + 8)? as u32;
-				let height = buf.read_u16(block.data_start + 10)? as u32;
-				let width_alignment = buf.read_u16(block.data_start + 14)? as u32;
+				let header = GimImageHeader::parse(buf, block.data_start)?;
+				let format = header.format;
+				let swizzled = header.pixel_order != 0;
+				let width = header.width as u32;
+				let height = header.height as u32;
+				let width_alignment = header.width_alignment as u32;
 				let format_bpp = match format {
 					0 => 16,
 					1 => 16,
 					3 => 32,
 					4 => 4,
 					5 => 8,
-					x => return Err(format!("unhandled pixel format {x:#X}"))
+					x => return Err(Error::unsupported(format!("unhandled pixel format {x:#X}")).at(block.data_start + 4))
 				};
-				let aligned_width = width.next_multiple_of(width_alignment * 8 / format_bpp);
-				let pixel_start = block.data_start + buf.read_u32(block.data_start + 28)? as usize;
+				let aligned_width = width.next_multiple_of((width_alignment * 8 / format_bpp).max(1));
+				opts.check_frames(frames.len() + 1)?;
+				opts.check_frame(aligned_width, height).map_err(|e| e.at(block.data_start + 8))?;
+				let pixel_start = block.data_start + header.pixel_offset as usize;
+				let pixels = buf.get(pixel_start..).ok_or_else(|| Error::truncated("pixel data is beyond file end").at(block.data_start + 28))?;
+				if pixels.len() < (aligned_width * format_bpp / 8) as usize * height as usize {
+					return Err(Error::truncated(format!("not enough pixel data for {aligned_width}x{height}")).at(pixel_start));
+				}
 				let pixel_data = if swizzled {
-					Cow::Owned(buf[pixel_start..].unswizzled_psp(aligned_width * format_bpp / 8, height))
+					Cow::Owned(pixels.unswizzled_psp(aligned_width * format_bpp / 8, height))
 				} else {
-					Cow::Borrowed(&buf[pixel_start..])
+					Cow::Borrowed(pixels)
 				};
 				let frame = match format {
 					0 => Frame::from_rgb16(aligned_width, height, &pixel_data),
@@ -73,16 +162,23 @@ pub const ENTRY_GIM: Decoder<Image> = Decoder {
 					3 => Frame::from_rgba(aligned_width, height, &pixel_data),
 					4 => Frame::from_rgba_clut4(aligned_width, height, cur_palette, &pixel_data),
 					5 => Frame::from_rgba_clut8(aligned_width, height, cur_palette, &pixel_data),
-					x => return Err(format!("unhandled pixel format {x:#X}"))
+					_ => unreachable!()
+				}?;
+				let placement = if aligned_width != width {
+					Placement {stored_size: Some((aligned_width, height)), ..Default::default()}
+				} else {
+					Placement::default()
 				};
-				frames.push(frame.resized(width, height));
+				let og_fmt = frame.og_fmt;
+				let frame = frame.with_palettes(alt_palettes.chunks_exact(palette_size.max(1)).filter_map(|x| og_fmt.read_clut(x)));
+				frames.push(frame.resized(width, height).with_placement(placement));
 			}
 			pos = block.next;
 		}
 		if frames.is_empty() {
-			Err("no frames were decoded successfully".into())
+			Err(Error::corrupt("no frames were decoded successfully"))
 		} else {
-			Ok(Image {frames: frames.into_boxed_slice()})
+			Ok(Image {frames: frames.into_boxed_slice(), animation: None})
 		}
 	}
 };
@@ -1,14 +1,54 @@
 use bytemuck::Zeroable;
 use zune_inflate::{DeflateDecoder, DeflateOptions};
-use crate::{byte_slice::ByteSlice, image::{Frame, Image, Pixel, PixelFormat}, Certainty, Decoder};
+use crate::{binary_struct, byte_slice::ByteSlice, file_data::FileData, image::{AlphaMode, Frame, Image, Pixel, PixelFormat}, swizzle::reorder_csm1_clut, Decoder, Detection, Error, HeaderInfo, Progress};
+
+binary_struct! {
+	// a TIM2 header followed by a PNGFILE3 header at 64, the inner file starts at 188
+	struct KlzEntryHeader("klz entry", Little) {
+		// the size of the entry starting from this field
+		16 => size_after_16: u32,
+		156 => fxt5_unpacked_size: u32,
+		164 => subformat: [u8; 4],
+		180 => fxt5_width: u32,
+		184 => fxt5_height: u32
+	}
+}
+
+// entries are only counted up to this, to keep identification cheap
+const IDENTIFY_ENTRY_LIMIT: usize = 64;
+
+fn identify(file: &mut FileData) -> HeaderInfo {
+	let (width, height) = match KlzEntryHeader::read_from(file, 0) {
+		Ok(x) if &x.subformat == b"FXT5" => (Some(x.fxt5_width), Some(x.fxt5_height)),
+		// otherwise it's a PNG, which has the size at the start of the IHDR chunk
+		_ => (file.get_u32_at_be(188 + 16), file.get_u32_at_be(188 + 20))
+	};
+	let mut frame_count = None;
+	let mut entry_start = 0;
+	for count in 1..=IDENTIFY_ENTRY_LIMIT {
+		let Ok(entry) = KlzEntryHeader::read_from(file, entry_start) else {
+			break;
+		};
+		entry_start += entry.size_after_16 as usize + 16;
+		if entry_start >= file.len() {
+			frame_count = Some(count);
+			break;
+		}
+	}
+	HeaderInfo {width, height, frame_count, ..Default::default()}
+}
 
 pub const ENTRY_KLZ: Decoder<Image> = Decoder {
 	id: "klz",
 	desc: "12Riven image format",
-	detect: |file| Certainty::certain_if(file.starts_with(b"TIM2") && file.starts_with_at(b"PNGFILE3", 0x40)),
-	decode: |file| {
+	detect: |file| match Detection::magic(file, b"TIM2", 0) {
+		x if x.is_match() => Detection::magic(file, b"PNGFILE3", 0x40),
+		x => x
+	},
+	identify,
+	decode: |file, opts| {
 		let mut frames = Vec::new();
-		let bytes = file.read();
+		let bytes = file.read()?;
 		let mut entry_start = 0; // KLZ files often have multiple entries, they seem to just be concatenated
 		while entry_start < bytes.len() {
 			// at address 16 in the TIM2 header, there is a 32-bit number equal to (file size - 16), ie, the size starting from this number itself
@@ -17,69 +57,70 @@ pub const ENTRY_KLZ: Decoder<Image> = Decoder {
 			// since the palette size is always 1024 bytes though, i just do a little math instead
 			// the PNGFILE3 header is at 64 and is 124 bytes long
 			// the inner file contents start at 188
-			let entry_size = bytes.read_u32(entry_start + 16)? as usize + 16;
+			let header = KlzEntryHeader::parse(bytes, entry_start)?;
+			let entry_size = header.size_after_16 as usize + 16;
+			if entry_size < 188 {
+				return Err(Error::bad_field(format!("entry size {entry_size} is smaller than the header")).at(entry_start + 16));
+			}
 			if bytes.len() < entry_start + entry_size {
-				return Err(format!("expected {} bytes, had only {}", entry_size, bytes.len() - entry_start));
+				return Err(Error::truncated(format!("expected {} bytes, had only {}", entry_size, bytes.len() - entry_start)).at(entry_start));
 			}
-			let subformat = bytes.read_bytes(entry_start + 164, 4, "subformat name")?;
+			opts.poll(Progress::Bytes {done: entry_start, total: bytes.len()})?;
+			opts.check_frames(frames.len() + 1)?;
+			let subformat = header.subformat;
 			if &subformat == b"GXT5" {
 				// this format is just a PNG with nothing special about it
 				let mut decoder = png::Decoder::new(&bytes[entry_start + 188..entry_start + entry_size]);
 				decoder.set_transformations(png::Transformations::normalize_to_color8().union(png::Transformations::ALPHA));
-				let mut reader = decoder.read_info().map_err(|e| format!("in GXT5 PNG info: {}", e))?;
+				let mut reader = decoder.read_info().map_err(|e| Error::corrupt(format!("in GXT5 PNG info: {}", e)).at(entry_start + 188))?;
+				opts.check_frame(reader.info().width, reader.info().height).map_err(|e| e.at(entry_start + 188))?;
 				let mut buf = vec![0u8; reader.output_buffer_size()];
-				let info = reader.next_frame(buf.as_mut()).map_err(|e| format!("in GXT5 PNG frame: {}", e))?;
-				assert_eq!(info.buffer_size(), (info.width * info.height * 4) as usize);
-				frames.push(Frame::from_rgba(info.width, info.height, &buf));
+				let info = reader.next_frame(buf.as_mut()).map_err(|e| Error::corrupt(format!("in GXT5 PNG frame: {}", e)).at(entry_start + 188))?;
+				if info.buffer_size() != info.width as usize * info.height as usize * 4 {
+					return Err(Error::unsupported(format!("GXT5 PNG has color type {:?}, expected RGBA", info.color_type)).at(entry_start + 188));
+				}
+				frames.push(Frame::from_rgba(info.width, info.height, &buf)?);
 			} else if &subformat == b"FXT5" {
 				// this is an 8-bit palette format, with 256x BGRA palette entries, where the palette needs to be shifted in a certain way because of PS2 hardware
-				let compressed_size = entry_size - 188 - 256 * 4;
-				let expected_size = bytes.read_u32(entry_start + 156)? as usize;
-				let width = bytes.read_u32(entry_start + 180)?;
-				let height = bytes.read_u32(entry_start + 184)?;
+				let compressed_size = entry_size.checked_sub(188 + 256 * 4).ok_or_else(|| Error::bad_field(format!("entry size {entry_size} is too small for FXT5")).at(entry_start + 16))?;
+				let expected_size = header.fxt5_unpacked_size as usize;
+				let width = header.fxt5_width;
+				let height = header.fxt5_height;
+				opts.check_frame(width, height).map_err(|e| e.at(entry_start + 180))?;
+				opts.check_output(expected_size).map_err(|e| e.at(entry_start + 156))?;
 				let palette_start = entry_start + 188 + compressed_size;
 				match DeflateDecoder::new_with_options(
-					&bytes.get(entry_start + 188..palette_start).ok_or("could not read FXT5 compressed pixel section")?,
-					DeflateOptions::default().set_limit(expected_size).set_size_hint(expected_size)
+					&bytes.get(entry_start + 188..palette_start).ok_or_else(|| Error::truncated("could not read FXT5 compressed pixel section").at(entry_start + 188))?,
+					DeflateOptions::default().set_limit(expected_size).set_size_hint(expected_size.min(compressed_size.saturating_mul(1032)))
 				).decode_zlib() {
 					Ok(pixel_bytes) => {
 						let mut palette = bytes.read_bytes(palette_start, 256 * 4, "FXT5 palette")?.to_vec();
 						let palette_pixels = bytemuck::cast_slice_mut::<u8, Pixel>(&mut palette);
-						// PS2 palette shift
-						for i in 0..8 {
-							for j in 0..8 {
-								let tmp = palette_pixels[8 + i * 32 + j];
-								palette_pixels[8 + i * 32 + j] = palette_pixels[16 + i * 32 + j];
-								palette_pixels[16 + i * 32 + j] = tmp;
-							}
-						}
-						frames.push(Frame::from_rgba_clut8(width, height, &palette, &pixel_bytes).with_double_alpha());
+						reorder_csm1_clut(palette_pixels);
+						frames.push(Frame::from_rgba_clut8(width, height, &palette, &pixel_bytes)?.with_alpha_mode(AlphaMode::Ps2));
 					}
-					Err(e) => return Err(format!("error decompressing FXT5 pixel section: {}", e))
+					Err(e) => return Err(Error::corrupt(format!("error decompressing FXT5 pixel section: {}", e)).at(entry_start + 188))
 				}
 			} else {
 				// apparently the raw PNGs don't always have GXT5, and instead just some garbage, but those seem to be specifically the BGRA images
 				let mut decoder = png::Decoder::new(&bytes[entry_start + 188..entry_start + entry_size]);
 				decoder.set_transformations(png::Transformations::normalize_to_color8().union(png::Transformations::ALPHA));
-				let mut reader = decoder.read_info().map_err(|e| format!("error reading BGRA PNG info: {e}"))?;
+				let mut reader = decoder.read_info().map_err(|e| Error::corrupt(format!("error reading BGRA PNG info: {e}")).at(entry_start + 188))?;
+				opts.check_frame(reader.info().width, reader.info().height).map_err(|e| e.at(entry_start + 188))?;
 				let mut buf = vec![0u8; reader.output_buffer_size()];
-				let info = reader.next_frame(&mut buf).map_err(|e| format!("error reading BGRA PNG frame: {e}"))?;
-				assert_eq!(info.buffer_size(), (info.width * info.height * 4) as usize);
-				for pixel in bytemuck::cast_slice_mut::<u8, [u8; 4]>(&mut buf) {
-					let tmp = pixel[0];
-					pixel[0] = pixel[2];
-					pixel[2] = tmp;
-					pixel[3] = pixel[3] << 1 | (pixel[3] & 1);
+				let info = reader.next_frame(&mut buf).map_err(|e| Error::corrupt(format!("error reading BGRA PNG frame: {e}")).at(entry_start + 188))?;
+				if info.buffer_size() != info.width as usize * info.height as usize * 4 {
+					return Err(Error::unsupported(format!("BGRA PNG has color type {:?}, expected RGBA", info.color_type)).at(entry_start + 188));
 				}
 				buf.truncate(info.buffer_size());
-				frames.push(Frame::from_rgba(info.width, info.height, &buf).with_og_fmt(reader.info().into()));
+				frames.push(Frame::from_bgra(info.width, info.height, &buf)?.with_og_fmt(reader.info().into()).with_alpha_mode(AlphaMode::SevenBit));
 			}
 			entry_start += entry_size;
 		}
 		if frames.is_empty() {
-			Err("no frames in image".into())
+			Err(Error::corrupt("no frames in image"))
 		} else {
-			Ok(Image {frames: frames.into_boxed_slice()})
+			Ok(Image {frames: frames.into_boxed_slice(), animation: None})
 		}
 	}
 };
\ No newline at end of file
@@ -12,7 +12,7 @@ mod pvr;
 mod tim;
 mod common_image;
 
-pub const IMAGE_DECODERS: LazyLock<Vec<Decoder<Image>>> = LazyLock::new(|| [
+pub static IMAGE_DECODERS: LazyLock<Vec<Decoder<Image>>> = LazyLock::new(|| [
 	prt::ENTRY_PRT,
 	tim2::ENTRY_TIM2,
 	ogdt::ENTRY_OGDT,
@@ -1,20 +1,25 @@
 use std::borrow::Cow;
 
-use crate::{byte_slice::ByteSlice, image::{Frame, Image, Pixel, PixelFormat}, Certainty, Decoder};
+use crate::{byte_slice::ByteSlice, file_data::FileData, image::{AlphaMode, Frame, Image, Pixel, PixelFormat}, swizzle::reorder_csm1_clut, Decoder, Detection, Error, HeaderInfo, Progress};
 
 // based on Never7 PS2 decompilation
 
 pub const ENTRY_OGDT: Decoder<Image> = Decoder {
 	id: "ogdt",
 	desc: "KID PS2 image format",
-	detect: |file| Certainty::certain_if(file.starts_with(b"ogdt")),
-	decode: |file| {
-		let buf = file.read();
-		let tile_width = buf.get_u16_at(8).ok_or("could not read width")? as usize;
-		let tile_height = buf.get_u16_at(10).ok_or("could not read height")? as usize;
-		let column_count = buf.get_u8_at(12).ok_or("could not read column count")? as usize;
-		let row_count = buf.get_u8_at(14).ok_or("could not read row count")? as usize;
+	detect: |file| Detection::magic(file, b"ogdt", 0),
+	identify: |file| {
+		let size = full_size(file);
+		HeaderInfo {width: size.map(|x| x.0), height: size.map(|x| x.1), frame_count: Some(1), ..Default::default()}
+	},
+	decode: |file, opts| {
+		let buf = file.read()?;
+		let tile_width = buf.read_u16(8)? as usize;
+		let tile_height = buf.read_u16(10)? as usize;
+		let column_count = buf.read_u8(12)? as usize;
+		let row_count = buf.read_u8(14)? as usize;
 		let frame_count = column_count * row_count;
+		opts.check_frame((tile_width * column_count) as u32, (tile_height * row_count) as u32).map_err(|e| e.at(8))?;
 		let (fmt, tile_size, clut) = match buf.get_u32_at(4) {
 			Some(0) => (
 				PixelFormat::Rgba,
@@ -37,57 +42,56 @@ pub const ENTRY_OGDT: Decoder<Image> = Decoder {
 			Some(0x13) => (
 				PixelFormat::RgbaClut8,
 				tile_width * tile_height,
-				Cow::Owned(fix_clut(buf.get(48 + frame_count * tile_width * tile_height..).ok_or("could not read clut8")?))
+				{
+					let mut clut = buf.get(48 + frame_count * tile_width * tile_height..).ok_or_else(|| Error::truncated("could not read clut8").at(48 + frame_count * tile_width * tile_height))?.to_vec();
+					clut.truncate(clut.len() / 4 * 4);
+					reorder_csm1_clut(bytemuck::cast_slice_mut::<u8, Pixel>(&mut clut));
+					Cow::Owned(clut)
+				}
 			),
 			Some(0x14) => (
 				PixelFormat::RgbaClut4,
 				tile_width * tile_height / 2,
-				Cow::Borrowed(buf.get(48 + frame_count * tile_width * tile_height / 2..).ok_or("could not read clut4")?)
+				Cow::Borrowed(buf.get(48 + frame_count * tile_width * tile_height / 2..).ok_or_else(|| Error::truncated("could not read clut4").at(48 + frame_count * tile_width * tile_height / 2))?)
 			),
-			Some(x) => return Err(format!("unknown format id 0x{x:X}")),
-			None => return Err("could not read format".into())
+			Some(x) => return Err(Error::unsupported(format!("unknown format id 0x{x:X}")).at(4)),
+			None => return Err(Error::truncated("could not read format").at(4))
 		};
-		let mut final_image = Frame::empty((tile_width * column_count) as u32, (tile_height * row_count) as u32, fmt);
+		if buf.len() < 32 + frame_count * tile_size {
+			return Err(Error::truncated(format!("{frame_count} tiles of {tile_width}x{tile_height} do not fit in the file")).at(32));
+		}
+		// created from the first tile, so that paletted tiles keep their indices
+		let mut final_image = None;
 		let mut tile_x = 0;
 		let mut tile_y = 0;
 		for index in 0..frame_count {
 			let tile_start = 32 + index * tile_size;
-			let frame_bytes = buf.get(tile_start..tile_start + tile_size).ok_or("could not read pixels")?;
+			let frame_bytes = buf.get(tile_start..tile_start + tile_size).ok_or_else(|| Error::truncated("could not read pixels").at(tile_start))?;
 			let tile = match fmt {
-				PixelFormat::Rgba => Frame::from_rgba(tile_width as u32, tile_height as u32, frame_bytes).with_double_alpha(),
-				PixelFormat::Rgb => Frame::from_rgb(tile_width as u32, tile_height as u32, frame_bytes),
-				PixelFormat::Rgba5551 => Frame::from_rgba5551(tile_width as u32, tile_height as u32, frame_bytes),
-				PixelFormat::RgbaClut8 => Frame::from_rgba_clut8(tile_width as u32, tile_height as u32, &clut, frame_bytes).with_double_alpha(),
-				PixelFormat::RgbaClut4 => Frame::from_rgba_clut4(tile_width as u32, tile_height as u32, &clut, frame_bytes).with_double_alpha(),
+				PixelFormat::Rgba => Frame::from_rgba(tile_width as u32, tile_height as u32, frame_bytes)?.with_alpha_mode(AlphaMode::Ps2),
+				PixelFormat::Rgb => Frame::from_rgb(tile_width as u32, tile_height as u32, frame_bytes)?,
+				PixelFormat::Rgba5551 => Frame::from_rgba5551(tile_width as u32, tile_height as u32, frame_bytes)?,
+				PixelFormat::RgbaClut8 => Frame::from_rgba_clut8(tile_width as u32, tile_height as u32, &clut, frame_bytes)?.with_alpha_mode(AlphaMode::Ps2),
+				PixelFormat::RgbaClut4 => Frame::from_rgba_clut4(tile_width as u32, tile_height as u32, &clut, frame_bytes)?.with_alpha_mode(AlphaMode::Ps2),
 				_ => unreachable!()
 			};
-			final_image.paste(tile_x as u32, tile_y as u32, &tile);
+			final_image.get_or_insert_with(|| Frame::empty_like((tile_width * column_count) as u32, (tile_height * row_count) as u32, &tile))
+				.paste(tile_x as u32, tile_y as u32, &tile);
 			tile_x += tile_width;
 			if tile_x >= tile_width * column_count {
 				tile_x = 0;
 				tile_y += tile_height;
+				opts.poll(Progress::Bytes {done: tile_start + tile_size, total: buf.len()})?;
 			}
 		}
-		Ok(Image {frames: Box::new([final_image])})
+		let final_image = final_image.unwrap_or_else(|| Frame::empty((tile_width * column_count) as u32, (tile_height * row_count) as u32, fmt));
+		Ok(Image {frames: Box::new([final_image]), animation: None})
 	}
 };
 
-fn fix_clut(clut: &[u8]) -> Vec<u8> {
-	let clut = bytemuck::cast_slice::<u8, Pixel>(clut);
-	let mut out = Vec::with_capacity(clut.len());
-
-	const BLOCK_WIDTH: usize = 8;
-	const BLOCK_HEIGHT: usize = 2;
-
-	for block_idx in 0..clut.len() / 32 {
-		for y in 0..BLOCK_HEIGHT {
-			for y2 in 0..BLOCK_HEIGHT {
-				for x in 0..BLOCK_WIDTH {
-					out.push(clut[block_idx * 32 + y * BLOCK_WIDTH + y2 * BLOCK_WIDTH * BLOCK_HEIGHT + x]);
-				}
-			}
-		}
-	}
-
-	bytemuck::cast_vec(out)
+// the tiles are put together into one frame
+fn full_size(file: &mut FileData) -> Option<(u32, u32)> {
+	let width = file.get_u16_at(8)? as u32 * file.get_u8_at(12)? as u32;
+	let height = file.get_u16_at(10)? as u32 * file.get_u8_at(14)? as u32;
+	Some((width, height))
 }
@@ -1,21 +1,34 @@
 use std::borrow::Cow;
 
-use crate::{byte_slice::ByteSlice, image::{Frame, Image, Pixel, PixelFormat}, Certainty, Decoder};
+use crate::{byte_slice::ByteSlice, image::{Frame, Image, Pixel, PixelFormat, Placement}, Decoder, Detection, Error, HeaderInfo};
 
 pub const ENTRY_PRT: Decoder<Image> = Decoder {
 	id: "prt",
 	desc: "Old KID PC image format",
-	detect: |file| Certainty::certain_if(file.starts_with(b"PRT\0") || file.starts_with_at(b"PRT\0", 16)),
-	decode: |file| {
-		let buf = file.read();
+	detect: |file| match Detection::magic(file, b"PRT\0", 0) {
+		x if x.is_match() => x,
+		_ => Detection::magic(file, b"PRT\0", 16)
+	},
+	identify: |file| {
+		let base = if file.starts_with(b"PRT\0") {0} else {16};
+		let (width, height) = match file.get_u16_at(base + 4) {
+			Some(102) => (file.get_u32_at(base + 28), file.get_u32_at(base + 32)),
+			_ => (file.get_u16_at(base + 12).map(u32::from), file.get_u16_at(base + 14).map(u32::from))
+		};
+		HeaderInfo {width, height, frame_count: Some(1), ..Default::default()}
+	},
+	decode: |file, opts| {
+		let buf = file.read()?;
 		let buf = if buf.starts_with(b"PRT\0") {
-			&buf
-		} else {
+			buf
+		} else if buf.get(16..).is_some_and(|x| x.starts_with(b"PRT\0")) {
 			&buf[16..]
+		} else {
+			return Err(Error::bad_magic("no PRT signature at 0x0 or 0x10"));
 		};
 		let version = buf.read_u16(4)?;
 		if version != 101 && version != 102 {
-			return Err(format!("unsupported prt version {version}"));
+			return Err(Error::unsupported(format!("unsupported prt version {version}")).at(4));
 		}
 		let bpp = buf.read_u16(6)? as u32;
 		let palette_pos = buf.read_u16(8)? as usize;
@@ -23,30 +36,47 @@ pub const ENTRY_PRT: Decoder<Image> = Decoder {
 		let has_alpha = buf.read_u32(16)? != 0;
 		let img_w;
 		let img_h;
+		let mut placement = Placement::default();
 		if version == 102 {
-			//offx = buf.read_u32(20)?; // what is this?
-			//offy = buf.read_u32(24)?;
+			// where the image goes on screen, for sprites and parts of character portraits
+			placement.origin = Some((buf.read_i32(20)?, buf.read_i32(24)?));
 			img_w = buf.read_u32(28)?;
 			img_h = buf.read_u32(32)?;
 		} else {
 			img_w = buf.read_u16(12)? as u32;
 			img_h = buf.read_u16(14)? as u32;
 		}
-		let stride = (img_w * (bpp / 8) + 3) / 4 * 4;
+		let size_pos = if version == 102 {28} else {12};
+		if img_w == 0 || img_h == 0 {
+			return Err(Error::bad_field(format!("empty {img_w}x{img_h} image")).at(size_pos));
+		}
+		opts.check_frame(img_w, img_h).map_err(|e| e.at(size_pos))?;
+		if bpp != 8 && bpp != 24 {
+			return Err(Error::unsupported(format!("unexpected bpp of {bpp}")).at(6));
+		}
+		let stride = (img_w as usize * (bpp as usize / 8)).next_multiple_of(4);
+		// everything below indexes freely, so check the whole pixel section (and alpha plane, if any) up front
+		let mut needed_size = stride.checked_mul(img_h as usize).and_then(|x| x.checked_add(pixel_pos));
+		if bpp == 24 && has_alpha {
+			needed_size = needed_size.and_then(|x| x.checked_add(img_w as usize * img_h as usize));
+		}
+		if needed_size.is_none_or(|x| x > buf.len()) {
+			return Err(Error::truncated(format!("{img_w}x{img_h} image does not fit in the file")).at(pixel_pos));
+		}
 		if bpp == 8 {
-			let mut frame = Frame::empty(img_w, img_h, PixelFormat::BgrxClut8);
+			// rows are stored bottom to top and padded, so they are put together before looking up the palette
+			let mut indices = Vec::with_capacity(img_w as usize * img_h as usize);
 			for y in (0..img_h).rev() {
-				let row_pos = pixel_pos + (stride * y) as usize;
-				let row = Frame::from_bgrx_clut8(img_w, 1, &buf[palette_pos..], &buf[row_pos..]);
-				frame.paste(0, img_h - y - 1, &row);
+				let row_pos = pixel_pos + stride * y as usize;
+				indices.extend_from_slice(&buf[row_pos..row_pos + img_w as usize]);
 			}
-			Ok(Image {frames: Box::new([frame])})
-		} else if bpp == 24 {
+			Ok(Image {frames: Box::new([Frame::from_bgrx_clut8(img_w, img_h, buf.get(palette_pos..).unwrap_or_default(), &indices)?.with_placement(placement)]), animation: None})
+		} else {
 			let mut frame = Frame::empty(img_w, img_h, PixelFormat::Bgra);
-			let mut alpha_pos = pixel_pos + (stride * img_h) as usize;
+			let mut alpha_pos = pixel_pos + stride * img_h as usize;
 			let mut i = 0;
 			for y in (0..img_h).rev() {
-				let mut row_pos = pixel_pos + (stride * y) as usize;
+				let mut row_pos = pixel_pos + stride * y as usize;
 				for _ in 0..img_w {
 					frame.pixels[i] = Pixel {
 						r: buf[row_pos + 2],
@@ -59,9 +89,7 @@ pub const ENTRY_PRT: Decoder<Image> = Decoder {
 					i += 1;
 				}
 			}
-			Ok(Image {frames: Box::new([frame])})
-		} else {
-			Err(format!("unexpected bpp of {bpp}"))
+			Ok(Image {frames: Box::new([frame.with_placement(placement)]), animation: None})
 		}
 	}
 };
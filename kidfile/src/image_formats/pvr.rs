@@ -1,62 +1,108 @@
 use std::{fs::File, io::{Read, Seek, SeekFrom}};
 
-use crate::{Certainty, Decoder, byte_slice::ByteSlice, image::{Frame, Image, PixelFormat, bit_twiddle}};
+use crate::{Decoder, Detection, Error, HeaderInfo, binary_struct, file_data::FileData, image::{Frame, Image, PixelFormat}, swizzle::dc_twiddled_index};
 
 // https://www.fabiensanglard.net/Mykaruga/tools/segaPVRFormat.txt
 // https://dreamcast.wiki/Twiddling
 
+binary_struct! {
+	struct PvrChunkHeader("pvr chunk", Little) {
+		0 => magic: [u8; 4],
+		// not counting this header
+		4 => len: u32
+	}
+
+	struct PvrTextureHeader("pvr texture chunk", Little) {
+		8 => pixel_format: u8,
+		9 => twiddle_type: u8,
+		12 => width: u16,
+		14 => height: u16
+	}
+}
+
+// chunk headers are small, but there could be a lot of them
+const IDENTIFY_CHUNK_LIMIT: usize = 64;
+
+fn identify(file: &mut FileData) -> HeaderInfo {
+	let mut info = HeaderInfo::default();
+	let mut pos = 0;
+	for _ in 0..IDENTIFY_CHUNK_LIMIT {
+		if pos >= file.len() {
+			// the palettes are alternates of the same frame
+			info.frame_count = info.width.map(|_| 1);
+			break;
+		}
+		let Ok(chunk) = PvrChunkHeader::read_from(file, pos) else {
+			break;
+		};
+		if &chunk.magic == b"PVRT" && let Ok(texture) = PvrTextureHeader::read_from(file, pos) {
+			info.width = Some(texture.width as u32);
+			info.height = Some(texture.height as u32);
+		}
+		pos += chunk.len as usize + 8;
+	}
+	info
+}
+
 pub const ENTRY_PVR: Decoder<Image> = Decoder {
 	id: "pvr",
 	desc: "Dreamcast image format",
 	detect: |file| {
 		let file_start = if file.starts_with(b"GBIX") {16} else {0};
-		Certainty::certain_if(file.starts_with_at(b"PVRT", file_start) || file.starts_with_at(b"PVPL", file_start))
+		Detection::any_magic(file, &[b"PVRT", b"PVPL"], file_start)
 	},
-	decode: |file| {
+	identify,
+	decode: |file, opts| {
 		let mut file_start = 0;
 		let mut tex_start = 0;
 		let mut tex_len = 0;
 		let mut palettes = Vec::new();
 		while file_start < file.len() {
-			let file_len = file.read_u32(file_start + 4)? as usize;
-			if file.starts_with_at(b"PVPL", file_start) {
-				let mut palette_bytes = Default::default();
-				palette_bytes = unsafe {Box::new_uninit_slice(file_len - 8).assume_init()};
-				file.read_chunk_exact(&mut palette_bytes, file_start + 16).map_err(|_| "PVPL length field is incorrect")?;
+			let chunk = PvrChunkHeader::read_from(file, file_start)?;
+			let file_len = chunk.len as usize;
+			if &chunk.magic == b"PVPL" {
+				if file_len < 8 || file_start + 8 + file_len > file.len() {
+					return Err(Error::bad_field("PVPL length field is incorrect").at(file_start + 4));
+				}
+				let mut palette_bytes = vec![0u8; file_len - 8].into_boxed_slice();
+				file.read_chunk_exact(&mut palette_bytes, file_start + 16).map_err(|e| Error {msg: "PVPL length field is incorrect".into(), ..e})?;
 				palettes.push(palette_bytes);
-			} else if file.starts_with_at(b"PVRT", file_start) {
+			} else if &chunk.magic == b"PVRT" {
 				tex_start = file_start;
 				tex_len = file_len;
 			}
 			file_start += file_len + 8;
 		}
-		if tex_len <= 0 {
-			return Err("PVRT header not found in file".to_string());
+		if tex_len == 0 {
+			return Err(Error::bad_magic("PVRT header not found in file"));
+		}
+		if tex_start + 8 + tex_len > file.len() {
+			return Err(Error::bad_field("PVRT length field is incorrect").at(tex_start + 4));
 		}
-		let mut buf = unsafe {Box::new_uninit_slice(tex_len + 8).assume_init()};
-		file.read_chunk_exact(&mut buf, tex_start).map_err(|_| "PVRT length field is incorrect")?;
-		let pixel_fmt = buf.read_u8(8)?;
-		let twiddle_type = buf.read_u8(9)?;
-		println!("twiddle type {twiddle_type}");
-		let width = buf.read_u16(12)? as usize;
-		let height = buf.read_u16(14)? as usize;
+		let header = PvrTextureHeader::read_from(file, tex_start)?;
+		let mut buf = vec![0u8; tex_len + 8].into_boxed_slice();
+		file.read_chunk_exact(&mut buf, tex_start).map_err(|e| Error {msg: "PVRT length field is incorrect".into(), ..e})?;
+		let pixel_fmt = header.pixel_format;
+		let twiddle_type = header.twiddle_type;
+		let width = header.width as usize;
+		let height = header.height as usize;
+		opts.check_frame(width as u32, height as u32).map_err(|e| e.at(tex_start + 12))?;
 		let mut frames = Vec::new();
 		if palettes.len() == 0 {
-			let palette_bytes = unsafe {Box::new_uninit_slice(0).assume_init()};
+			let palette_bytes = Box::<[u8]>::default();
 			palettes.push(palette_bytes);
 		}
 		for palette_bytes in palettes.iter() {
 			let mut frame = match pixel_fmt {
 				0 | 1 | 2 => {
 					if twiddle_type == 3 { // vq compression
-						let codebook = buf.get(16..16 + 2048).ok_or("not enough 16-bit VQ codebook data")?;
-						let indices = buf.get(16 + 2048..16 + 2048 + width * height / 4).ok_or("not enough VQ index data")?;
+						let codebook = buf.get(16..16 + 2048).ok_or_else(|| Error::truncated("not enough 16-bit VQ codebook data").at(16))?;
+						let indices = buf.get(16 + 2048..16 + 2048 + width * height / 4).ok_or_else(|| Error::truncated("not enough VQ index data").at(16 + 2048))?;
 						let mut pixels = vec![0u8; width * height * 2];
 						for block_y in 0..height / 2 {
-							let twiddled_block_y = bit_twiddle(block_y);
 							for block_x in 0..width / 2 {
-								let twiddled_block_idx = twiddled_block_y | bit_twiddle(block_x) << 1;
-								let codebook_pos = indices[twiddled_block_idx] as usize * 8;
+								let twiddled_block_idx = dc_twiddled_index(block_x, block_y, width / 2, height / 2);
+								let codebook_pos = *indices.get(twiddled_block_idx).ok_or_else(|| Error::corrupt("VQ index out of range").at(16 + 2048))? as usize * 8;
 								let x = block_x * 2;
 								let y = block_y * 2;
 								pixels[(y * width + x) * 2] = codebook[codebook_pos];
@@ -70,63 +116,71 @@ pub const ENTRY_PVR: Decoder<Image> = Decoder {
 							}
 						}
 						if pixel_fmt == 0 {
-							Frame::from_bgra5551(width as u32, height as u32, &pixels).with_og_fmt(PixelFormat::Bgra5551Vq8)
+							Frame::from_bgra5551(width as u32, height as u32, &pixels)?.with_og_fmt(PixelFormat::Bgra5551Vq8)
 						} else if pixel_fmt == 1 {
-							Frame::from_bgr565(width as u32, height as u32, &pixels).with_og_fmt(PixelFormat::Bgr565Vq8)
+							Frame::from_bgr565(width as u32, height as u32, &pixels)?.with_og_fmt(PixelFormat::Bgr565Vq8)
 						} else {
-							Frame::from_bgra4444(width as u32, height as u32, &pixels).with_og_fmt(PixelFormat::Bgra4444Vq8)
+							Frame::from_bgra4444(width as u32, height as u32, &pixels)?.with_og_fmt(PixelFormat::Bgra4444Vq8)
 						}
 					} else {
 						if pixel_fmt == 0 {
-							Frame::from_bgra5551(width as u32, height as u32, buf.get(16..16 + width * height * 2).ok_or("not enough pixel data for BGRA5551")?)
+							Frame::from_bgra5551(width as u32, height as u32, buf.get(16..16 + width * height * 2).ok_or_else(|| Error::truncated("not enough pixel data for BGRA5551").at(16))?)?
 						} else if pixel_fmt == 1 {
-							Frame::from_bgr565(width as u32, height as u32, buf.get(16..16 + width * height * 2).ok_or("not enough pixel data for BGR565")?)
+							Frame::from_bgr565(width as u32, height as u32, buf.get(16..16 + width * height * 2).ok_or_else(|| Error::truncated("not enough pixel data for BGR565").at(16))?)?
 						} else {
-							Frame::from_bgra4444(width as u32, height as u32, buf.get(16..16 + width * height * 2).ok_or("not enough pixel data for BGRA4444")?)
+							Frame::from_bgra4444(width as u32, height as u32, buf.get(16..16 + width * height * 2).ok_or_else(|| Error::truncated("not enough pixel data for BGRA4444").at(16))?)?
 						}
 					}
 				}
 				5 => {
 					if twiddle_type == 7 && palette_bytes.is_empty(){
-						return Err("file needs external palette, unimplemented".into());
+						return Err(Error::unsupported("file needs external palette, unimplemented"));
 					} else if palette_bytes.is_empty() {
 						Frame::from_bgra_clut4(
 							width as u32, height as u32,
-							buf.get(16..16 + 1024).ok_or("not enough palette data for BGRA clut4")?,
-							buf.get(16 + 1024..16 + 1024 + width * height / 2).ok_or("not enough index data for BGRA clut4")?
-						)
+							buf.get(16..16 + 1024).ok_or_else(|| Error::truncated("not enough palette data for BGRA clut4").at(16))?,
+							buf.get(16 + 1024..16 + 1024 + width * height / 2).ok_or_else(|| Error::truncated("not enough index data for BGRA clut4").at(16 + 1024))?
+						)?
 					} else {
 						Frame::from_bgra_clut4(
 							width as u32, height as u32,
 							&palette_bytes,
-							buf.get(16..16 + width * height / 2).ok_or("not enough data for BGRA clut4")?
-						)
+							buf.get(16..16 + width * height / 2).ok_or_else(|| Error::truncated("not enough data for BGRA clut4").at(16))?
+						)?
 					}
 				}
 				6 => {
 					if twiddle_type == 7 && palette_bytes.is_empty(){
-						return Err("file needs external palette, unimplemented".into());
+						return Err(Error::unsupported("file needs external palette, unimplemented"));
 					} else if palette_bytes.is_empty() {
 						Frame::from_bgra_clut8(
 							width as u32, height as u32,
-							buf.get(16..16 + 1024).ok_or("not enough palette data for BGRA clut8")?,
-							buf.get(16 + 1024..16 + 1024 + width * height).ok_or("not enough index data for BGRA clut8")?
-						)
+							buf.get(16..16 + 1024).ok_or_else(|| Error::truncated("not enough palette data for BGRA clut8").at(16))?,
+							buf.get(16 + 1024..16 + 1024 + width * height).ok_or_else(|| Error::truncated("not enough index data for BGRA clut8").at(16 + 1024))?
+						)?
 					} else {
 						Frame::from_bgra_clut8(
 							width as u32, height as u32,
 							&palette_bytes,
-							buf.get(16..16 + width * height).ok_or("not enough data for BGRA clut8")?
-						)
+							buf.get(16..16 + width * height).ok_or_else(|| Error::truncated("not enough data for BGRA clut8").at(16))?
+						)?
 					}
 				}
-				_ => return Err(format!("unhandled PVR pixel format {pixel_fmt}"))
+				_ => return Err(Error::unsupported(format!("unhandled PVR pixel format {pixel_fmt}")).at(8))
 			};
 			if [1, 2, 5, 6, 7, 8, 13].contains(&twiddle_type) {
-				frame = frame.twiddled_dc();
+				frame = frame.twiddled_dc()?;
 			}
 			frames.push(frame);
+			// other palettes only change the colors of paletted formats
+			if frames[0].indexed.is_none() {
+				break;
+			}
 		}
-		Ok(Image {frames: frames.into_boxed_slice()})
+		// every PVPL gives the same indices, so the other palettes become alternates of the first frame
+		let mut frames = frames.into_iter();
+		let frame = frames.next().unwrap();
+		let alt_palettes = frames.filter_map(|x| x.indexed).map(|mut x| x.palettes.swap_remove(0));
+		Ok(Image {frames: Box::new([frame.with_palettes(alt_palettes)]), animation: None})
 	}
 };
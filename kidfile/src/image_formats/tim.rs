@@ -1,6 +1,4 @@
-use std::mem::MaybeUninit;
-
-use crate::{byte_slice::ByteSlice, file_data::FileData, image::{Frame, Image, Pixel, PixelFormat}, Certainty, Decoder};
+use crate::{byte_slice::ByteSlice, file_data::FileData, image::{Frame, Image, Pixel, PixelFormat}, Decoder, Detection, Error, HeaderInfo};
 
 // https://www.psxdev.net/forum/viewtopic.php?t=109
 
@@ -36,6 +34,31 @@ fn decode_header(data: &mut FileData) -> Option<TimFormat> {
 	}
 }
 
+// vram width is in 16-bit units, so each row has twice that many bytes
+fn check_pixel_section(buf: &[u8], pixel_start: usize, vram_width: usize, height: usize) -> Result<(), Error> {
+	if buf.len() < pixel_start + vram_width * 2 * height {
+		return Err(Error::truncated("not enough pixels").at(pixel_start));
+	}
+	Ok(())
+}
+
+// the clut block has a 12-byte header with its size in vram units, and can hold several palettes in its rows or along them
+fn read_cluts(buf: &[u8], bits: u8) -> Result<Vec<Box<[Pixel]>>, Error> {
+	let width = buf.read_u16(16)? as usize;
+	let height = buf.read_u16(18)? as usize;
+	let colors = buf.read_bytes(20, width * height * 2, "clut")?.chunks_exact(2).map(|x| psx_to_rgba(u16::from_le_bytes([x[0], x[1]])));
+	let palettes = colors.collect::<Vec<_>>().chunks(1 << bits).map(Box::from).collect::<Vec<_>>();
+	if palettes.is_empty() {
+		return Err(Error::bad_field("clut is empty").at(16));
+	}
+	Ok(palettes)
+}
+
+// the pixel block comes after the clut block, which starts with its size
+fn clut_end(buf: &[u8]) -> Result<usize, Error> {
+	Ok(8 + buf.read_u32(8)? as usize)
+}
+
 fn psx_to_rgba(color: u16) -> Pixel {
 	Pixel {
 		r: ((color << 3) & 0b11111000 | (color & 0b111)) as u8,
@@ -48,69 +71,76 @@ fn psx_to_rgba(color: u16) -> Pixel {
 pub const ENTRY_TIM: Decoder<Image> = Decoder {
 	id: "tim",
 	desc: "PlayStation 1 official image format",
-	detect: |file| Certainty::certain_if(decode_header(file).is_some()),
-	decode: |file| {
-		let header = decode_header(file).ok_or("could not decode header")?;
-		let buf = file.read();
+	detect: |file| match decode_header(file) {
+		Some(format) => Detection::certain(format!("TIM header, {format:?}")),
+		None => Detection::impossible("no TIM header")
+	},
+	identify: |file| {
+		// the size is in vram units, which are 16 bits wide
+		let clut_end = file.get_u32_at(8).map_or(0, |x| 8 + x as usize);
+		let (size_pos, width_mul, width_div) = match decode_header(file) {
+			Some(TimFormat::Clut4) => (clut_end + 8, 4, 1),
+			Some(TimFormat::Clut8) => (clut_end + 8, 2, 1),
+			Some(TimFormat::Psx16) => (16, 1, 1),
+			Some(TimFormat::Rgb24) => (16, 1, 3),
+			None => return HeaderInfo::default()
+		};
+		HeaderInfo {
+			width: file.get_u16_at(size_pos).map(|x| x as u32 * width_mul / width_div),
+			height: file.get_u16_at(size_pos + 2).map(u32::from),
+			frame_count: Some(1),
+			..Default::default()
+		}
+	},
+	decode: |file, opts| {
+		let header = decode_header(file).ok_or_else(|| Error::bad_magic("could not decode header"))?;
+		let buf = file.read()?;
 		match header {
 			TimFormat::Clut4 => {
-				let clut = bytemuck::cast_slice::<u8, u16>(&buf[20..20 + 16 * 2]);
-				let pixel_start = 20 + 16 * 2 + 12;
+				let mut palettes = read_cluts(buf, 4)?;
+				let pixel_start = clut_end(buf)? + 12;
 				let vram_width = buf.read_u16(pixel_start - 4)? as usize;
 				let height = buf.read_u16(pixel_start - 2)? as usize;
-				if buf.len() < pixel_start + vram_width * height {
-					return Err("not enough pixels".into());
-				}
+				check_pixel_section(buf, pixel_start, vram_width, height)?;
 				let pixel_width = vram_width * 4;
+				opts.check_frame(pixel_width as u32, height as u32).map_err(|e| e.at(pixel_start - 4))?;
 				let pixel_count = pixel_width * height;
-				let mut pixels = unsafe {Box::new_uninit_slice(pixel_count).assume_init()};
-				for i in 0..pixel_count / 2 {
-					let two_pixels = buf[pixel_start + i] as usize;
-					pixels[i * 2] = psx_to_rgba(clut[(two_pixels & 0xF) as usize]);
-					pixels[i * 2 + 1] = psx_to_rgba(clut[(two_pixels >> 4) as usize]);
-				}
-				Ok(Image {frames: Box::new([Frame {
-					width: pixel_width as u32,
-					height: height as u32,
-					og_fmt: PixelFormat::PsxClut4,
-					pixels
-				}])})
+				let indices = buf[pixel_start..pixel_start + pixel_count / 2].iter().flat_map(|&x| [x & 0xF, x >> 4]).collect();
+				let palette = palettes.remove(0);
+				Ok(Image {frames: Box::new([Frame::from_indexed(
+					pixel_width as u32, height as u32, PixelFormat::PsxClut4, 4, indices, palette
+				)?.with_palettes(palettes)]), animation: None})
 			}
 			TimFormat::Clut8 => {
-				let clut = bytemuck::cast_slice::<u8, u16>(&buf[20..20 + 256 * 2]);
-				let pixel_start = 20 + 256 * 2 + 12;
+				let mut palettes = read_cluts(buf, 8)?;
+				let pixel_start = clut_end(buf)? + 12;
 				let vram_width = buf.read_u16(pixel_start - 4)? as usize;
 				let height = buf.read_u16(pixel_start - 2)? as usize;
-				if buf.len() < pixel_start + vram_width * height {
-					return Err("not enough pixels".into());
-				}
+				check_pixel_section(buf, pixel_start, vram_width, height)?;
 				let pixel_width = vram_width * 2;
+				opts.check_frame(pixel_width as u32, height as u32).map_err(|e| e.at(pixel_start - 4))?;
 				let pixel_count = pixel_width * height;
-				let mut pixels = unsafe {Box::new_uninit_slice(pixel_count).assume_init()};
-				for i in 0..pixel_count {
-					pixels[i] = psx_to_rgba(clut[buf[pixel_start + i] as usize]);
-				}
-				Ok(Image {frames: Box::new([Frame {
-					width: pixel_width as u32,
-					height: height as u32,
-					og_fmt: PixelFormat::PsxClut8,
-					pixels
-				}])})
+				let palette = palettes.remove(0);
+				Ok(Image {frames: Box::new([Frame::from_indexed(
+					pixel_width as u32, height as u32, PixelFormat::PsxClut8, 8,
+					buf[pixel_start..pixel_start + pixel_count].into(), palette
+				)?.with_palettes(palettes)]), animation: None})
 			}
 			TimFormat::Rgb24 => {
 				let pixel_start = 20;
 				let vram_width = buf.read_u16(pixel_start - 4)? as usize;
 				let height = buf.read_u16(pixel_start - 2)? as usize;
 				if buf.len() < pixel_start + vram_width * height {
-					return Err("not enough pixels".into());
+					return Err(Error::truncated("not enough pixels").at(pixel_start));
 				}
 				let pixel_width = vram_width / 3;
+				opts.check_frame(pixel_width as u32, height as u32).map_err(|e| e.at(pixel_start - 4))?;
 				Ok(Image {frames: Box::new([Frame::from_rgb(
 					pixel_width as u32, height as u32,
 					&buf[pixel_start..pixel_start + vram_width * height]
-				)])})
+				)?]), animation: None})
 			}
-			_ => Err(format!("todo {:?}", header))
+			_ => Err(Error::unsupported(format!("todo {:?}", header)).at(4))
 		}
 	}
 };
\ No newline at end of file
@@ -1,35 +1,159 @@
-use crate::{image::{Frame, Image, PixelFormat}, Certainty, Decoder};
+use crate::{binary_struct, byte_slice::ByteSlice, image::{AlphaMode, Frame, Image, Pixel, PixelFormat}, swizzle::reorder_csm1_clut, DecodeOptions, Decoder, Detection, Error, HeaderInfo};
+
+binary_struct! {
+	struct Tim2PictureHeader("tim2 picture", Little) {
+		0 => total_size: u32,
+		8 => image_size: u32,
+		12 => header_size: u16,
+		14 => clut_colors: u16,
+		18 => clut_type: u8,
+		19 => image_type: u8,
+		20 => width: u16,
+		22 => height: u16
+	}
+}
+
+// the tim2 crate only gives out RGBA, so the indices and palette of paletted pictures are read here
+fn read_indexed(buf: &[u8], picture_start: usize) -> Result<Frame, Error> {
+	let header = Tim2PictureHeader::parse(buf, picture_start)?;
+	let (width, height) = (header.width as u32, header.height as u32);
+	let (bits, og_fmt) = match header.image_type {
+		4 => (4, PixelFormat::RgbaClut4),
+		5 => (8, PixelFormat::RgbaClut8),
+		x => return Err(Error::unsupported(format!("image type {x} is not paletted")).at(picture_start + 19))
+	};
+	let pixel_start = picture_start + header.header_size as usize;
+	let clut_start = pixel_start + header.image_size as usize;
+	let pixels = buf.read_bytes(pixel_start, (width as usize * height as usize * bits as usize).div_ceil(8), "pixels")?;
+	let indices = if bits == 4 {
+		pixels.iter().flat_map(|&x| [x & 0xF, x >> 4]).take(width as usize * height as usize).collect()
+	} else {
+		pixels.into()
+	};
+	// a clut with more colors than the indices can reach holds several palettes one after another
+	let entry_count = header.clut_colors as usize;
+	let colors = match header.clut_type & 0x3F {
+		1 => buf.read_bytes(clut_start, entry_count * 2, "clut")?.chunks_exact(2).map(|x| {
+			let color = u16::from_le_bytes([x[0], x[1]]);
+			let bits_5_to_8 = |x: u16| ((x & 0x1F) << 3 | (x & 0x1F) >> 2) as u8;
+			Pixel {r: bits_5_to_8(color), g: bits_5_to_8(color >> 5), b: bits_5_to_8(color >> 10), a: if color & 0x8000 != 0 {0x80} else {0}}
+		}).collect::<Box<_>>(),
+		2 => buf.read_bytes(clut_start, entry_count * 3, "clut")?.chunks_exact(3).map(|x| Pixel {r: x[0], g: x[1], b: x[2], a: 0x80}).collect(),
+		3 => buf.read_bytes(clut_start, entry_count * 4, "clut")?.chunks_exact(4).map(|x| Pixel {r: x[0], g: x[1], b: x[2], a: x[3]}).collect(),
+		x => return Err(Error::unsupported(format!("unknown clut type {x}")).at(picture_start + 18))
+	};
+	let mut palettes = colors.chunks(1 << bits).map(Box::<[Pixel]>::from).collect::<Vec<_>>();
+	if palettes.is_empty() {
+		return Err(Error::bad_field("clut is empty").at(picture_start + 14));
+	}
+	// the top bit is set for CSM2, which is stored in order
+	if bits == 8 && header.clut_type & 0x80 == 0 {
+		for palette in &mut palettes {
+			reorder_csm1_clut(palette);
+		}
+	}
+	let palette = palettes.remove(0);
+	Ok(Frame::from_indexed(width, height, og_fmt, bits, indices, palette)?.with_palettes(palettes))
+}
+
+// the tim2 crate allocates every picture as soon as it parses the file, so the limits are checked on the headers first
+fn check_limits(buf: &[u8], opts: &DecodeOptions) -> Result<(), Error> {
+	let picture_count = buf.read_u16(6)? as usize;
+	opts.check_frames(picture_count).map_err(|e| e.at(6))?;
+	let mut picture_start = if buf.get_u8_at(5) == Some(1) {128} else {16};
+	for _ in 0..picture_count {
+		let header = Tim2PictureHeader::parse(buf, picture_start)?;
+		opts.check_frame(header.width as u32, header.height as u32).map_err(|e| e.at(picture_start + 20))?;
+		picture_start = picture_start.saturating_add(header.total_size as usize);
+	}
+	Ok(())
+}
 
 pub const ENTRY_TIM2: Decoder<Image> = Decoder {
 	id: "tim2",
 	desc: "PlayStation 2 official image format",
-	detect: |file| Certainty::certain_if(file.starts_with(b"TIM2") && !file.starts_with_at(b"PNGFILE3", 0x40)),
-	decode: |file| {
-		let tim2_img = tim2::from_buffer(file.read()).map_err(|e| format!("{:?}", e))?;
-		let frames = tim2_img.frames().iter().filter_map(|tim2_frame| {
+	detect: |file| if file.starts_with_at(b"PNGFILE3", 0x40) {
+		Detection::impossible("PNGFILE3 at 0x40, this is a KLZ")
+	} else {
+		Detection::magic(file, b"TIM2", 0)
+	},
+	identify: |file| {
+		// the first picture header comes right after the file header, which is padded to 128 bytes in the aligned variant
+		let picture_start = if file.get_u8_at(5) == Some(1) {128} else {16};
+		HeaderInfo {
+			width: file.get_u16_at(picture_start + 20).map(u32::from),
+			height: file.get_u16_at(picture_start + 22).map(u32::from),
+			frame_count: file.get_u16_at(6).map(usize::from),
+			..Default::default()
+		}
+	},
+	decode: |file, opts| {
+		let buf = file.read()?;
+		check_limits(buf, opts)?;
+		let tim2_img = tim2::from_buffer(buf).map_err(|e| Error::corrupt(format!("{:?}", e)))?;
+		let mut frames = Vec::new();
+		let mut picture_start = if buf.get_u8_at(5) == Some(1) {128} else {16};
+		for tim2_frame in tim2_img.frames() {
+			let picture = picture_start;
+			picture_start = Tim2PictureHeader::parse(buf, picture_start).map_or(usize::MAX, |x| picture.saturating_add(x.total_size as usize));
 			let pixels = tim2_frame.to_raw(None);
 			if pixels.is_empty() {
-				None
-			} else {
-				let mut frame = Frame::from_rgba(tim2_frame.width() as u32, tim2_frame.height() as u32, &pixels);
-				frame.og_fmt = match tim2_frame.format().unwrap() {
-					tim2::Format::Indexed4 => PixelFormat::RgbaClut4,
-					tim2::Format::Indexed8 => PixelFormat::RgbaClut8,
-					tim2::Format::Rgb888 => PixelFormat::Rgb,
-					tim2::Format::Rgba8888 => PixelFormat::Rgba,
-					tim2::Format::Abgr1555 => PixelFormat::Rgba5551
-				};
-				if matches!(frame.og_fmt, PixelFormat::RgbaClut4 | PixelFormat::RgbaClut8 | PixelFormat::Rgba) {
-					Some(frame.with_double_alpha())
-				} else {
-					Some(frame)
+				continue;
+			}
+			let mut frame = Frame::from_rgba(tim2_frame.width() as u32, tim2_frame.height() as u32, &pixels)?;
+			frame.og_fmt = match tim2_frame.format().map_err(|e| Error::unsupported(format!("{:?}", e)))? {
+				tim2::Format::Indexed4 => PixelFormat::RgbaClut4,
+				tim2::Format::Indexed8 => PixelFormat::RgbaClut8,
+				tim2::Format::Rgb888 => PixelFormat::Rgb,
+				tim2::Format::Rgba8888 => PixelFormat::Rgba,
+				tim2::Format::Abgr1555 => PixelFormat::Rgba5551
+			};
+			if matches!(frame.og_fmt, PixelFormat::RgbaClut4 | PixelFormat::RgbaClut8) {
+				// kept only if it decodes to the same thing as the tim2 crate, which knows more of the format's variants
+				if let Ok(indexed) = read_indexed(buf, picture) && indexed.pixels == frame.pixels {
+					frame = indexed;
 				}
 			}
-		}).collect::<Box<[_]>>();
+			if matches!(frame.og_fmt, PixelFormat::RgbaClut4 | PixelFormat::RgbaClut8 | PixelFormat::Rgba) {
+				frames.push(frame.with_alpha_mode(AlphaMode::Ps2));
+			} else {
+				frames.push(frame);
+			}
+		}
 		if frames.is_empty() {
-			Err("no frames were decoded successfully".into())
+			Err(Error::corrupt("no frames were decoded successfully"))
 		} else {
-			Ok(Image {frames})
+			Ok(Image {frames: frames.into_boxed_slice(), animation: None})
 		}
 	}
 };
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{file_data::FileData, ErrorKind};
+
+	fn tim2_header(picture_count: u16, width: u16, height: u16) -> Vec<u8> {
+		let mut buf = vec![0u8; 16 + 48];
+		buf[..4].copy_from_slice(b"TIM2");
+		buf[4] = 4;
+		buf[6..8].copy_from_slice(&picture_count.to_le_bytes());
+		buf[16..20].copy_from_slice(&48u32.to_le_bytes());
+		buf[28..30].copy_from_slice(&48u16.to_le_bytes());
+		buf[16 + 19] = 3;
+		buf[36..38].copy_from_slice(&width.to_le_bytes());
+		buf[38..40].copy_from_slice(&height.to_le_bytes());
+		buf
+	}
+
+	#[test]
+	fn limits_checked_from_headers() {
+		let opts = DecodeOptions {max_frame_width: 64, max_frame_height: 64, max_frames: 4, ..Default::default()};
+		let decode = |buf: Vec<u8>| (ENTRY_TIM2.decode)(&mut FileData::Memory {buf: buf.into()}, &opts);
+		let err = decode(tim2_header(1, 65535, 65535)).err().unwrap();
+		assert_eq!(err.kind, ErrorKind::LimitExceeded);
+		let err = decode(tim2_header(5, 1, 1)).err().unwrap();
+		assert_eq!(err.kind, ErrorKind::LimitExceeded);
+		assert!(check_limits(&tim2_header(1, 64, 64), &opts).is_ok());
+	}
+}
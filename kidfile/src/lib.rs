@@ -1,17 +1,30 @@
+use std::borrow::Cow;
 use file_data::FileData;
 use image::Image;
 
+pub mod error;
+pub use error::{Error, ErrorKind};
+pub mod options;
+pub use options::{CancelToken, DecodeOptions, Progress};
 pub mod file_data;
+pub mod patch;
+pub use patch::Patch;
+pub mod hash;
 pub mod byte_slice;
 pub mod byte_iter;
 pub mod image;
+pub mod swizzle;
+pub mod quantize;
 mod data_formats;
 pub use data_formats::DATA_DECODERS;
 mod archive_formats;
 pub use archive_formats::{Archive, ARCHIVE_DECODERS};
 mod image_formats;
 pub use image_formats::IMAGE_DECODERS;
+pub mod registry;
+pub use registry::{Candidate, DecoderKind, DecoderRegistry, Identification, DEFAULT_REGISTRY};
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Certainty {
 	Impossible,
 	Possible,
@@ -36,14 +49,96 @@ impl Certainty {
 	}
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// The verdict of a decoder's detect function, with a short human-readable reason for it.
+#[derive(Clone, Debug)]
+pub struct Detection {
+	pub certainty: Certainty,
+	/// 0 to 100, used to rank candidates in [`probe`]; defaults to 100 for certain and 50 for possible matches
+	pub score: u8,
+	pub reason: Cow<'static, str>
+}
+
+impl Detection {
+	pub fn certain(reason: impl Into<Cow<'static, str>>) -> Self {
+		Self {certainty: Certainty::Certain, score: 100, reason: reason.into()}
+	}
+
+	pub fn possible(reason: impl Into<Cow<'static, str>>) -> Self {
+		Self {certainty: Certainty::Possible, score: 50, reason: reason.into()}
+	}
+
+	pub fn impossible(reason: impl Into<Cow<'static, str>>) -> Self {
+		Self {certainty: Certainty::Impossible, score: 0, reason: reason.into()}
+	}
+
+	pub fn with_score(mut self, score: u8) -> Self {
+		self.score = score.min(100);
+		self
+	}
+
+	/// Certain if `magic` is found at `offset`, with the reason saying so either way.
+	pub fn magic(data: &mut FileData, magic: &[u8], offset: usize) -> Self {
+		if data.starts_with_at(magic, offset) {
+			Self::certain(format!("magic {} at {offset:#X}", magic.escape_ascii()))
+		} else {
+			Self::impossible(format!("no {} magic at {offset:#X}", magic.escape_ascii()))
+		}
+	}
+
+	/// Like [`Detection::magic`], but accepts any of the given signatures at the same offset.
+	pub fn any_magic(data: &mut FileData, magics: &[&[u8]], offset: usize) -> Self {
+		for magic in magics {
+			if data.starts_with_at(magic, offset) {
+				return Self::certain(format!("magic {} at {offset:#X}", magic.escape_ascii()));
+			}
+		}
+		Self::impossible(format!(
+			"none of {} at {offset:#X}",
+			magics.iter().map(|x| x.escape_ascii().to_string()).collect::<Vec<_>>().join(", ")
+		))
+	}
+
+	pub fn is_match(&self) -> bool {
+		self.certainty != Certainty::Impossible
+	}
+}
+
+/// What a decoder can tell about a file from its header alone, see [`identify`].
+/// Anything that would take more than a few small reads is left empty.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderInfo {
+	/// of the first frame, for images
+	pub width: Option<u32>,
+	pub height: Option<u32>,
+	pub frame_count: Option<usize>,
+	pub entry_count: Option<usize>,
+	/// size of the data once decompressed
+	pub unpacked_size: Option<usize>
+}
+
+/// A format handler. Downstream crates can build their own and add them to a [`DecoderRegistry`].
 pub struct Decoder<T> {
-	id: &'static str,
-	desc: &'static str,
-	detect: fn(data: &mut FileData) -> Certainty,
-	decode: fn(data: &mut FileData) -> Result<T, String>
+	/// short unique name, shown in decode steps and used to refer to the decoder in the registry
+	pub id: &'static str,
+	pub desc: &'static str,
+	/// should only look at signatures and header fields, it's called for every decoder on every step.
+	/// it must only read a bounded prefix with [`FileData::read_chunk_exact`] or [`FileData::starts_with_at`], never the whole file
+	pub detect: fn(data: &mut FileData) -> Detection,
+	/// reads the basic info from the header, with the same restrictions as `detect`
+	pub identify: fn(data: &mut FileData) -> HeaderInfo,
+	/// should check header-declared sizes against the options before allocating for them
+	pub decode: fn(data: &mut FileData, opts: &DecodeOptions) -> Result<T, Error>
+}
+
+// derived impls would require T: Clone, which archives and images aren't
+impl<T> Clone for Decoder<T> {
+	fn clone(&self) -> Self {
+		*self
+	}
 }
 
+impl<T> Copy for Decoder<T> {}
+
 pub enum DynData {
 	Raw(FileData),
 	Archive(Archive),
@@ -80,80 +175,34 @@ impl From<Image> for DynData {
 	}
 }
 
-fn decode_step<T: Into<DynData>>(data: &mut FileData, decoders: &[Decoder<T>], disallow_id: Option<&'static str>, discard_low_confidence: bool) -> Result<Option<(&'static str, DynData)>, String> {
-	for decoder in decoders {
-		if let Certainty::Certain = (decoder.detect)(data) {
-			if Some(decoder.id) == disallow_id {
-				return Ok(None);
-			}
-			return (decoder.decode)(data)
-				.map(|x| Some((decoder.id, x.into())))
-				.map_err(|msg| {
-					if msg.is_empty() {
-						format!("unspecified error from {}", decoder.id)
-					} else {
-						format!("error from {}: {}", decoder.id, msg)
-					}
-				});
-		}
-	}
-	if !discard_low_confidence {
-		for decoder in decoders {
-			if Some(decoder.id) != disallow_id {
-				if let Certainty::Possible = (decoder.detect)(data) {
-					if let Ok(x) = (decoder.decode)(data) {
-						return Ok(Some((decoder.id, x.into())));
-					}
-				}
-			}
-		}
-	}
-	Ok(None)
-}
-
-pub fn auto_decode_step(data: &mut FileData, disallow_id: Option<&'static str>, in_archive: Option<&'static str>) -> Result<(&'static str, DynData), String> {
-	if let Some(x) = decode_step(data, &ARCHIVE_DECODERS, disallow_id, in_archive.is_some())? {
-		return Ok(x);
-	}
-	if let Some(x) = decode_step(data, &IMAGE_DECODERS, disallow_id, false)? {
-		return Ok(x);
-	}
-	if let Some(x) = decode_step(data, &DATA_DECODERS, disallow_id, false)? {
-		return Ok(x);
-	}
-	Err("could not fully decode file".into())
+/// Decodes one step with the default registry, see [`DecoderRegistry::auto_decode_step`].
+pub fn auto_decode_step(data: &mut FileData, disallow_id: Option<&'static str>, in_archive: Option<&'static str>, opts: &DecodeOptions) -> Result<(&'static str, DynData), Error> {
+	DEFAULT_REGISTRY.auto_decode_step(data, disallow_id, in_archive, opts)
 }
 
 pub struct DecodeResult {
 	pub data: DynData,
 	pub steps_taken: Vec<&'static str>,
-	pub error_msg: String
-}
-
-pub fn auto_decode_full(initial_data: &mut FileData, in_archive: Option<&'static str>) -> DecodeResult {
-	let mut steps_taken = Vec::<&'static str>::new();
-	let mut cur_data = None;
-	loop {
-		match auto_decode_step(cur_data.as_mut().unwrap_or(initial_data), steps_taken.last().cloned(), in_archive) {
-			Ok((id, decoded)) => {
-				steps_taken.push(id);
-				if let DynData::Raw(new_data) = decoded {
-					cur_data = Some(new_data);
-				} else {
-					return DecodeResult {
-						data: decoded,
-						steps_taken,
-						error_msg: String::new()
-					}
-				}
-			}
-			Err(msg) => {
-				return DecodeResult {
-					data: DynData::Raw(cur_data.unwrap_or_else(|| initial_data.clone())),
-					steps_taken,
-					error_msg: msg
-				}
-			}
-		}
-	}
+	/// why decoding stopped before reaching an image or archive, if it did
+	pub error: Option<Error>
+}
+
+/// Decodes with an explicit chain of decoder ids from the default registry, see [`DecoderRegistry::decode_as`].
+pub fn decode_as(initial_data: &mut FileData, ids: &[&str], opts: &DecodeOptions) -> DecodeResult {
+	DEFAULT_REGISTRY.decode_as(initial_data, ids, opts)
+}
+
+/// Runs every decoder's detection in the default registry and ranks the results, see [`DecoderRegistry::probe`].
+pub fn probe(data: &mut FileData) -> Vec<Candidate> {
+	DEFAULT_REGISTRY.probe(data)
+}
+
+/// Finds the format of the data and reads its basic info without decoding it, see [`DecoderRegistry::identify`].
+pub fn identify(data: &mut FileData) -> Option<Identification> {
+	DEFAULT_REGISTRY.identify(data)
+}
+
+/// Decodes as far as possible with the default registry, see [`DecoderRegistry::auto_decode_full`].
+pub fn auto_decode_full(initial_data: &mut FileData, in_archive: Option<&'static str>, opts: &DecodeOptions) -> DecodeResult {
+	DEFAULT_REGISTRY.auto_decode_full(initial_data, in_archive, opts)
 }
\ No newline at end of file
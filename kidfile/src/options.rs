@@ -0,0 +1,173 @@
+use std::{fmt::Debug, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+use crate::Error;
+
+/// How often decoders call [`DecodeOptions::poll`] in loops over bytes, in bytes of input.
+pub const POLL_INTERVAL: usize = 0x10000;
+
+/// A flag shared between threads to stop a decode that's in progress.
+/// Decoders notice it the next time they poll and fail with [`ErrorKind::Cancelled`](crate::ErrorKind::Cancelled).
+#[derive(Clone, Default, Debug)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn cancel(&self) {
+		self.0.store(true, Ordering::Release);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.0.load(Ordering::Acquire)
+	}
+}
+
+/// Reported by decoders as they go through long inputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Progress {
+	/// bytes of input scanned or decompressed
+	Bytes {done: usize, total: usize},
+	/// archive entries parsed
+	Entries {done: usize, total: usize}
+}
+
+impl Progress {
+	/// From 0 to 1.
+	pub fn fraction(&self) -> f32 {
+		let (Self::Bytes {done, total} | Self::Entries {done, total}) = *self;
+		if total == 0 {
+			1.0
+		} else {
+			(done as f64 / total as f64).min(1.0) as f32
+		}
+	}
+}
+
+pub type ProgressCallback = Arc<dyn Fn(Progress) + Send + Sync>;
+
+/// Limits for decoding untrusted files, plus hooks for long-running decodes.
+/// Decoders check the limits before allocating anything whose size comes from the file,
+/// and going over one fails with [`ErrorKind::LimitExceeded`](crate::ErrorKind::LimitExceeded) instead.
+#[derive(Clone)]
+pub struct DecodeOptions {
+	/// largest buffer a single decoder may produce, whether decompressed data or the pixels of one frame
+	pub max_output_bytes: usize,
+	pub max_frame_width: u32,
+	pub max_frame_height: u32,
+	/// frames in one image
+	pub max_frames: usize,
+	pub max_archive_entries: usize,
+	/// how many decoders may be chained on one file, like compression inside compression
+	pub max_depth: usize,
+	pub cancel: Option<CancelToken>,
+	/// called from the decoding thread, so it should be quick
+	pub progress: Option<ProgressCallback>
+}
+
+impl Default for DecodeOptions {
+	fn default() -> Self {
+		Self {
+			max_output_bytes: 256 * 1024 * 1024,
+			max_frame_width: 16384,
+			max_frame_height: 16384,
+			max_frames: 4096,
+			max_archive_entries: 0x10000,
+			max_depth: 8,
+			cancel: None,
+			progress: None
+		}
+	}
+}
+
+impl DecodeOptions {
+	/// No limits at all, for files that are known to be good.
+	pub const UNLIMITED: Self = Self {
+		max_output_bytes: usize::MAX,
+		max_frame_width: u32::MAX,
+		max_frame_height: u32::MAX,
+		max_frames: usize::MAX,
+		max_archive_entries: usize::MAX,
+		max_depth: usize::MAX,
+		cancel: None,
+		progress: None
+	};
+
+	pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+		self.cancel = Some(cancel);
+		self
+	}
+
+	pub fn with_progress(mut self, progress: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+		self.progress = Some(Arc::new(progress));
+		self
+	}
+
+	pub fn check_cancelled(&self) -> Result<(), Error> {
+		if self.cancel.as_ref().is_some_and(|x| x.is_cancelled()) {
+			return Err(Error::cancelled());
+		}
+		Ok(())
+	}
+
+	/// Reports progress and checks for cancellation. Decoders call it every so often in their long loops.
+	pub fn poll(&self, progress: Progress) -> Result<(), Error> {
+		if let Some(callback) = &self.progress {
+			callback(progress);
+		}
+		self.check_cancelled()
+	}
+
+	pub fn check_output(&self, size: usize) -> Result<(), Error> {
+		if size > self.max_output_bytes {
+			return Err(Error::limit_exceeded(format!("output of {size} bytes is over the limit of {}", self.max_output_bytes)));
+		}
+		Ok(())
+	}
+
+	/// Checks the dimensions and the size of the frame once converted to 32-bit pixels.
+	pub fn check_frame(&self, width: u32, height: u32) -> Result<(), Error> {
+		if width > self.max_frame_width || height > self.max_frame_height {
+			return Err(Error::limit_exceeded(format!(
+				"{width}x{height} frame is over the limit of {}x{}", self.max_frame_width, self.max_frame_height
+			)));
+		}
+		self.check_output((width as usize).saturating_mul(height as usize).saturating_mul(4))
+	}
+
+	pub fn check_frames(&self, count: usize) -> Result<(), Error> {
+		if count > self.max_frames {
+			return Err(Error::limit_exceeded(format!("{count} frames is over the limit of {}", self.max_frames)));
+		}
+		Ok(())
+	}
+
+	pub fn check_entries(&self, count: usize) -> Result<(), Error> {
+		if count > self.max_archive_entries {
+			return Err(Error::limit_exceeded(format!("{count} archive entries is over the limit of {}", self.max_archive_entries)));
+		}
+		Ok(())
+	}
+
+	pub fn check_depth(&self, depth: usize) -> Result<(), Error> {
+		if depth > self.max_depth {
+			return Err(Error::limit_exceeded(format!("{depth} decode steps is over the limit of {}", self.max_depth)));
+		}
+		Ok(())
+	}
+}
+
+impl Debug for DecodeOptions {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("DecodeOptions")
+			.field("max_output_bytes", &self.max_output_bytes)
+			.field("max_frame_width", &self.max_frame_width)
+			.field("max_frame_height", &self.max_frame_height)
+			.field("max_frames", &self.max_frames)
+			.field("max_archive_entries", &self.max_archive_entries)
+			.field("max_depth", &self.max_depth)
+			.field("cancel", &self.cancel)
+			.field("progress", &self.progress.as_ref().map(|_| "..."))
+			.finish()
+	}
+}
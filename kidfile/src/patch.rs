@@ -0,0 +1,160 @@
+use crate::{byte_slice::ByteSlice, Error};
+
+// lengths come from the file, so no adding them to offsets before they are known to fit
+fn read_bytes(buf: &[u8], offset: usize, len: usize, name: &str) -> Result<Box<[u8]>, Error> {
+	buf.get(offset..).and_then(|x| x.get(..len)).map(Box::from).ok_or_else(|| Error::truncated(format!("could not read {name}")).at(offset))
+}
+
+const MAGIC: &[u8] = b"KIDPATCH";
+const VERSION: u32 = 1;
+
+/// Changes to some data that are kept apart from it, see [`FileData::Patched`](crate::file_data::FileData::Patched).
+/// Can be saved on its own with [`Patch::to_bytes`] and applied again later.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Patch {
+	/// new contents that take the place of the original data, the size included
+	pub replacement: Option<Box<[u8]>>,
+	/// bytes to write over the data at each offset, in order, so later ones win where they overlap
+	pub ranges: Vec<(usize, Box<[u8]>)>
+}
+
+impl Patch {
+	pub fn is_empty(&self) -> bool {
+		self.replacement.is_none() && self.ranges.is_empty()
+	}
+
+	/// Size of the data once patched.
+	pub fn len(&self, original_len: usize) -> usize {
+		self.replacement.as_ref().map_or(original_len, |x| x.len())
+	}
+
+	/// Adds the changes from another patch after the ones in this one.
+	pub fn merge(&mut self, other: Patch) {
+		if other.replacement.is_some() {
+			// the old ranges were relative to the old contents
+			*self = other;
+		} else {
+			self.ranges.extend(other.ranges);
+		}
+	}
+
+	/// Writes the patched ranges over a chunk of data that starts at `chunk_start`.
+	/// Parts of ranges outside the chunk are skipped.
+	pub fn apply(&self, out_buf: &mut [u8], chunk_start: usize) {
+		let chunk_end = chunk_start + out_buf.len();
+		for (offset, bytes) in &self.ranges {
+			let start = (*offset).max(chunk_start);
+			let end = offset.saturating_add(bytes.len()).min(chunk_end);
+			if start < end {
+				out_buf[start - chunk_start..end - chunk_start].copy_from_slice(&bytes[start - offset..end - offset]);
+			}
+		}
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut out = MAGIC.to_vec();
+		out.extend(VERSION.to_le_bytes());
+		if let Some(replacement) = &self.replacement {
+			out.extend(1u32.to_le_bytes());
+			out.extend((replacement.len() as u64).to_le_bytes());
+			out.extend_from_slice(replacement);
+		} else {
+			out.extend(0u32.to_le_bytes());
+		}
+		out.extend((self.ranges.len() as u64).to_le_bytes());
+		for (offset, bytes) in &self.ranges {
+			out.extend((*offset as u64).to_le_bytes());
+			out.extend((bytes.len() as u64).to_le_bytes());
+			out.extend_from_slice(bytes);
+		}
+		out
+	}
+
+	pub fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+		if !buf.starts_with(MAGIC) {
+			return Err(Error::bad_magic("no KIDPATCH signature"));
+		}
+		let version = buf.read_u32(8)?;
+		if version != VERSION {
+			return Err(Error::unsupported(format!("unsupported patch version {version}")).at(8));
+		}
+		let mut pos = 16;
+		let replacement = match buf.read_u32(12)? {
+			0 => None,
+			1 => {
+				let len = buf.read_u64(pos)? as usize;
+				let bytes = read_bytes(buf, pos + 8, len, "replacement")?;
+				pos += 8 + len;
+				Some(bytes)
+			}
+			x => return Err(Error::bad_field(format!("replacement flag should be 0 or 1, not {x}")).at(12))
+		};
+		let count = buf.read_u64(pos)? as usize;
+		pos += 8;
+		// every range takes at least 16 bytes, so a bogus count can't make a huge allocation
+		let mut ranges = Vec::with_capacity(count.min((buf.len() - pos) / 16));
+		for _ in 0..count {
+			let offset = buf.read_u64(pos)? as usize;
+			let len = buf.read_u64(pos + 8)? as usize;
+			ranges.push((offset, read_bytes(buf, pos + 16, len, "patched range")?));
+			pos += 16 + len;
+		}
+		Ok(Self {replacement, ranges})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::file_data::FileData;
+
+	fn test_patch() -> Patch {
+		Patch {replacement: None, ranges: vec![(4, Box::from(*b"abcd")), (6, Box::from(*b"XY")), (20, Box::from(*b""))]}
+	}
+
+	#[test]
+	fn bytes_round_trip() {
+		let patch = test_patch();
+		assert_eq!(Patch::from_bytes(&patch.to_bytes()).unwrap(), patch);
+		let replaced = Patch {replacement: Some(Box::from(*b"new contents")), ranges: vec![(1, Box::from(*b"E"))]};
+		assert_eq!(Patch::from_bytes(&replaced.to_bytes()).unwrap(), replaced);
+		assert_eq!(Patch::from_bytes(&Patch::default().to_bytes()).unwrap(), Patch::default());
+	}
+
+	#[test]
+	fn from_bytes_rejects_bad_input() {
+		for patch in [test_patch(), Patch {replacement: Some(Box::from(*b"new")), ranges: Vec::new()}] {
+			let bytes = patch.to_bytes();
+			for len in 0..bytes.len() {
+				assert!(Patch::from_bytes(&bytes[..len]).is_err(), "truncated to {len}");
+			}
+		}
+		let mut bytes = test_patch().to_bytes();
+		bytes[8] = 2;
+		assert!(Patch::from_bytes(&bytes).is_err());
+		let mut bytes = test_patch().to_bytes();
+		bytes[12] = 2;
+		assert!(Patch::from_bytes(&bytes).is_err());
+		// a huge range length can't overflow the position
+		let mut bytes = test_patch().to_bytes();
+		bytes[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+		assert!(Patch::from_bytes(&bytes).is_err());
+	}
+
+	#[test]
+	fn read_across_patched_range() {
+		let mut data = FileData::Memory {buf: (0..32).collect()}.with_patch(test_patch());
+		// over the start of the first range
+		let mut buf = [0u8; 4];
+		data.read_chunk_exact(&mut buf, 2).unwrap();
+		assert_eq!(buf, [2, 3, b'a', b'b']);
+		// over the end of the second range, which overlaps the first
+		data.read_chunk_exact(&mut buf, 6).unwrap();
+		assert_eq!(buf, [b'X', b'Y', 8, 9]);
+		// inside both
+		data.read_chunk_exact(&mut buf[..1], 7).unwrap();
+		assert_eq!(buf[0], b'Y');
+		assert_eq!(&data.read().unwrap()[..10], [0, 1, 2, 3, b'a', b'b', b'X', b'Y', 8, 9]);
+		assert!(data.read_chunk_exact(&mut buf, 30).is_err());
+	}
+}
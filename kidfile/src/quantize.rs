@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use crate::{image::{AlphaMode, Pixel}, Error};
+
+/// How [`Frame::quantized`](crate::image::Frame::quantized) picks a palette and looks the pixels up in it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuantizeOptions {
+	/// 4 or 8, for 16 or 256 colors
+	pub bits: u8,
+	/// spreads the error of each pixel to the next ones (Floyd-Steinberg), only for color so edges stay sharp
+	pub dither: bool
+}
+
+impl Default for QuantizeOptions {
+	fn default() -> Self {
+		Self {bits: 8, dither: false}
+	}
+}
+
+impl QuantizeOptions {
+	pub fn new(bits: u8) -> Self {
+		Self {bits, ..Default::default()}
+	}
+
+	pub fn with_dither(mut self) -> Self {
+		self.dither = true;
+		self
+	}
+}
+
+fn check_bits(opts: &QuantizeOptions) -> Result<(), Error> {
+	if !matches!(opts.bits, 4 | 8) {
+		return Err(Error::unsupported(format!("cannot quantize to {} bits, only 4 or 8", opts.bits)));
+	}
+	Ok(())
+}
+
+// fully transparent pixels are all the same color, whatever was left in their color channels
+fn normalized(p: Pixel, alpha_mode: AlphaMode) -> Pixel {
+	match alpha_mode.snap(p) {
+		Pixel {a: 0, ..} => Pixel::default(),
+		p => p
+	}
+}
+
+fn channel(p: Pixel, c: usize) -> u8 {
+	[p.r, p.g, p.b, p.a][c]
+}
+
+// color matters less the more transparent both pixels are, alpha always matters fully
+fn distance(x: Pixel, y: Pixel) -> u32 {
+	let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+	let color = d(x.r, y.r) + d(x.g, y.g) + d(x.b, y.b);
+	color * x.a.max(y.a) as u32 / 255 + d(x.a, y.a) * 2
+}
+
+fn nearest(palette: &[Pixel], p: Pixel) -> u8 {
+	palette.iter().enumerate().min_by_key(|(_, x)| distance(**x, p)).map_or(0, |(i, _)| i as u8)
+}
+
+// the channel with the biggest spread and how big it is, alpha counting double like in the distance
+fn widest_channel(colors: &[(Pixel, u32)]) -> (usize, u32) {
+	(0..4).map(|c| {
+		let min = colors.iter().map(|x| channel(x.0, c)).min().unwrap_or(0);
+		let max = colors.iter().map(|x| channel(x.0, c)).max().unwrap_or(0);
+		(c, (max - min) as u32 * if c == 3 {2} else {1})
+	}).max_by_key(|x| x.1).unwrap()
+}
+
+fn average(colors: &[(Pixel, u32)], alpha_mode: AlphaMode) -> Pixel {
+	let total = colors.iter().map(|x| x.1 as u64).sum::<u64>().max(1);
+	let avg = |c: usize| ((colors.iter().map(|x| channel(x.0, c) as u64 * x.1 as u64).sum::<u64>() + total / 2) / total) as u8;
+	normalized(Pixel {r: avg(0), g: avg(1), b: avg(2), a: avg(3).max(1)}, alpha_mode)
+}
+
+// median cut, splitting the box with the widest channel at its median pixel until there are enough boxes
+fn median_cut(colors: Vec<(Pixel, u32)>, max_colors: usize, alpha_mode: AlphaMode) -> Vec<Pixel> {
+	if colors.len() <= max_colors {
+		return colors.into_iter().map(|x| x.0).collect();
+	}
+	let mut boxes = vec![colors];
+	while boxes.len() < max_colors {
+		let Some((box_idx, (c, _))) = boxes.iter().enumerate()
+			.filter(|(_, x)| x.len() > 1)
+			.map(|(i, x)| (i, widest_channel(x)))
+			.max_by_key(|(_, (_, range))| *range) else {
+			break;
+		};
+		let mut colors = boxes.swap_remove(box_idx);
+		colors.sort_unstable_by_key(|x| channel(x.0, c));
+		let total = colors.iter().map(|x| x.1 as u64).sum::<u64>();
+		let mut seen = 0;
+		let split = colors.iter().position(|x| {
+			seen += x.1 as u64;
+			seen * 2 >= total
+		}).map_or(1, |x| x + 1).clamp(1, colors.len() - 1);
+		let rest = colors.split_off(split);
+		boxes.push(colors);
+		boxes.push(rest);
+	}
+	boxes.iter().map(|x| average(x, alpha_mode)).collect()
+}
+
+/// A palette of exactly `1 << bits` colors for the pixels, with transparency first if there is any,
+/// only using colors that can be stored in the alpha mode.
+pub fn palette(pixels: &[Pixel], alpha_mode: AlphaMode, opts: &QuantizeOptions) -> Result<Vec<Pixel>, Error> {
+	check_bits(opts)?;
+	let max_colors = 1 << opts.bits;
+	let mut counts = HashMap::<u32, u32>::new();
+	for &p in pixels {
+		*counts.entry(bytemuck::cast(normalized(p, alpha_mode))).or_default() += 1;
+	}
+	// transparency gets its own entry, so averaging never makes it visible
+	let has_transparent = counts.remove(&0).is_some();
+	let mut colors = counts.into_iter().map(|(k, count)| (bytemuck::cast::<u32, Pixel>(k), count)).collect::<Vec<_>>();
+	// the map's order is random, this keeps the output the same for the same input
+	colors.sort_unstable_by_key(|x| bytemuck::cast::<Pixel, u32>(x.0));
+	let mut palette = Vec::with_capacity(max_colors);
+	if has_transparent {
+		palette.push(Pixel::default());
+	}
+	palette.extend(median_cut(colors, max_colors - palette.len(), alpha_mode));
+	palette.resize(max_colors, Pixel::default());
+	Ok(palette)
+}
+
+/// Looks every pixel up in the palette, one index per pixel.
+/// The palette can have at most `1 << bits` colors, like the one from [`palette`].
+pub fn indices(pixels: &[Pixel], width: u32, palette: &[Pixel], alpha_mode: AlphaMode, opts: &QuantizeOptions) -> Result<Vec<u8>, Error> {
+	check_bits(opts)?;
+	if palette.is_empty() || palette.len() > 1 << opts.bits {
+		return Err(Error::bad_field(format!("a {}-bit palette cannot have {} colors", opts.bits, palette.len())));
+	}
+	if !opts.dither {
+		let mut cache = HashMap::<u32, u8>::new();
+		return Ok(pixels.iter().map(|&p| {
+			let p = normalized(p, alpha_mode);
+			*cache.entry(bytemuck::cast(p)).or_insert_with(|| nearest(palette, p))
+		}).collect());
+	}
+	let width = width.max(1) as usize;
+	let mut out = Vec::with_capacity(pixels.len());
+	// error for this row and the next, with a spare column on each side
+	let mut errors = vec![[0i32; 3]; width + 2];
+	let mut next_errors = vec![[0i32; 3]; width + 2];
+	for row in pixels.chunks(width) {
+		for (x, &p) in row.iter().enumerate() {
+			let p = normalized(p, alpha_mode);
+			if p.a == 0 {
+				out.push(nearest(palette, p));
+				continue;
+			}
+			let e = errors[x + 1];
+			let adjusted = Pixel {
+				r: (p.r as i32 + e[0] / 16).clamp(0, 255) as u8,
+				g: (p.g as i32 + e[1] / 16).clamp(0, 255) as u8,
+				b: (p.b as i32 + e[2] / 16).clamp(0, 255) as u8,
+				a: p.a
+			};
+			let idx = nearest(palette, adjusted);
+			out.push(idx);
+			let q = palette[idx as usize];
+			let diff = [adjusted.r as i32 - q.r as i32, adjusted.g as i32 - q.g as i32, adjusted.b as i32 - q.b as i32];
+			for c in 0..3 {
+				errors[x + 2][c] += diff[c] * 7;
+				next_errors[x][c] += diff[c] * 3;
+				next_errors[x + 1][c] += diff[c] * 5;
+				next_errors[x + 2][c] += diff[c];
+			}
+		}
+		std::mem::swap(&mut errors, &mut next_errors);
+		next_errors.fill([0; 3]);
+	}
+	Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// a gradient with some transparent pixels in it, with junk color in them
+	fn test_pixels() -> Vec<Pixel> {
+		(0..64 * 16).map(|i| match i % 64 {
+			0..8 => Pixel {r: i as u8, g: 1, b: 2, a: 0},
+			x => Pixel {r: (x * 4) as u8, g: (i / 64 * 16) as u8, b: 255 - (x * 4) as u8, a: if x < 32 {0x80} else {255}}
+		}).collect()
+	}
+
+	#[test]
+	fn palette_size_and_transparency() {
+		let pixels = test_pixels();
+		for bits in [4, 8] {
+			let palette = palette(&pixels, AlphaMode::Full, &QuantizeOptions::new(bits)).unwrap();
+			assert_eq!(palette.len(), 1 << bits);
+			assert!(palette[0] == Pixel::default());
+			assert!(palette[1..].iter().all(|x| x.a != 0));
+			let indices = indices(&pixels, 64, &palette, AlphaMode::Full, &QuantizeOptions::new(bits)).unwrap();
+			assert_eq!(indices.len(), pixels.len());
+			assert!(indices.iter().zip(&pixels).all(|(&i, p)| (i == 0) == (p.a == 0)));
+		}
+		// few colors are kept as they are, with padding after them
+		let few = [Pixel {r: 1, g: 2, b: 3, a: 255}, Pixel {r: 4, g: 5, b: 6, a: 255}];
+		let palette = palette(&few, AlphaMode::Full, &QuantizeOptions::new(4)).unwrap();
+		assert_eq!(palette.len(), 16);
+		assert!(palette[..2] == few);
+	}
+
+	#[test]
+	fn deterministic() {
+		let pixels = test_pixels();
+		let opts = QuantizeOptions::new(4).with_dither();
+		let first = palette(&pixels, AlphaMode::Ps2, &opts).unwrap();
+		let first_indices = indices(&pixels, 64, &first, AlphaMode::Ps2, &opts).unwrap();
+		for _ in 0..4 {
+			let again = palette(&pixels, AlphaMode::Ps2, &opts).unwrap();
+			assert!(again == first);
+			assert_eq!(indices(&pixels, 64, &again, AlphaMode::Ps2, &opts).unwrap(), first_indices);
+		}
+	}
+
+	#[test]
+	fn dithered_indices_in_range() {
+		let pixels = test_pixels();
+		for bits in [4, 8] {
+			let opts = QuantizeOptions::new(bits).with_dither();
+			let palette = palette(&pixels, AlphaMode::Full, &opts).unwrap();
+			let indices = indices(&pixels, 64, &palette, AlphaMode::Full, &opts).unwrap();
+			assert_eq!(indices.len(), pixels.len());
+			assert!(indices.iter().all(|&x| (x as usize) < 1 << bits));
+		}
+		// a shorter palette than the bits allow still only gets indices into it
+		let short = [Pixel {r: 0, g: 0, b: 0, a: 255}, Pixel {r: 255, g: 255, b: 255, a: 255}];
+		let indices = indices(&pixels, 64, &short, AlphaMode::Full, &QuantizeOptions::new(8).with_dither()).unwrap();
+		assert!(indices.iter().all(|&x| x < 2));
+	}
+
+	#[test]
+	fn bad_bits() {
+		let pixels = test_pixels();
+		for bits in [0, 1, 2, 7, 16, 32, 200] {
+			assert!(palette(&pixels, AlphaMode::Full, &QuantizeOptions::new(bits)).is_err());
+			assert!(indices(&pixels, 64, &[Pixel::default()], AlphaMode::Full, &QuantizeOptions::new(bits)).is_err());
+		}
+		assert!(indices(&pixels, 64, &[], AlphaMode::Full, &QuantizeOptions::new(4)).is_err());
+		assert!(indices(&pixels, 64, &[Pixel::default(); 17], AlphaMode::Full, &QuantizeOptions::new(4)).is_err());
+	}
+}
@@ -0,0 +1,554 @@
+use std::{borrow::Cow, collections::HashSet, sync::LazyLock};
+use crate::{archive_formats::ARCHIVE_DECODERS, data_formats::DATA_DECODERS, file_data::FileData, image::Image, image_formats::IMAGE_DECODERS, Archive, Certainty, DecodeOptions, Decoder, DecodeResult, DynData, Error, ErrorKind, HeaderInfo};
+
+/// The decoders that the automatic decoding functions in the crate root use.
+/// Built once on first access.
+pub static DEFAULT_REGISTRY: LazyLock<DecoderRegistry> = LazyLock::new(DecoderRegistry::builtin);
+
+/// Implemented for each type a decoder can produce, so that the registry can keep one list per kind.
+pub trait DecoderOutput: Into<DynData> + Sized + 'static {
+	const KIND: DecoderKind;
+	fn list(registry: &DecoderRegistry) -> &Vec<Decoder<Self>>;
+	fn list_mut(registry: &mut DecoderRegistry) -> &mut Vec<Decoder<Self>>;
+	/// checked on every decoder's output, in case the decoder itself missed something
+	fn check_limits(&self, opts: &DecodeOptions) -> Result<(), Error>;
+}
+
+impl DecoderOutput for Archive {
+	const KIND: DecoderKind = DecoderKind::Archive;
+
+	fn list(registry: &DecoderRegistry) -> &Vec<Decoder<Self>> {
+		&registry.archive
+	}
+
+	fn list_mut(registry: &mut DecoderRegistry) -> &mut Vec<Decoder<Self>> {
+		&mut registry.archive
+	}
+
+	fn check_limits(&self, opts: &DecodeOptions) -> Result<(), Error> {
+		opts.check_entries(self.entries.len())
+	}
+}
+
+impl DecoderOutput for Image {
+	const KIND: DecoderKind = DecoderKind::Image;
+
+	fn list(registry: &DecoderRegistry) -> &Vec<Decoder<Self>> {
+		&registry.image
+	}
+
+	fn list_mut(registry: &mut DecoderRegistry) -> &mut Vec<Decoder<Self>> {
+		&mut registry.image
+	}
+
+	fn check_limits(&self, opts: &DecodeOptions) -> Result<(), Error> {
+		opts.check_frames(self.frames.len())?;
+		for frame in &self.frames {
+			opts.check_frame(frame.width, frame.height)?;
+		}
+		Ok(())
+	}
+}
+
+impl DecoderOutput for Box<[u8]> {
+	const KIND: DecoderKind = DecoderKind::Data;
+
+	fn list(registry: &DecoderRegistry) -> &Vec<Decoder<Self>> {
+		&registry.data
+	}
+
+	fn list_mut(registry: &mut DecoderRegistry) -> &mut Vec<Decoder<Self>> {
+		&mut registry.data
+	}
+
+	fn check_limits(&self, opts: &DecodeOptions) -> Result<(), Error> {
+		opts.check_output(self.len())
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecoderKind {
+	Archive,
+	Image,
+	/// decoders that produce raw bytes, like decompressors
+	Data
+}
+
+/// One decoder's opinion about some data, as returned by [`DecoderRegistry::probe`].
+#[derive(Clone, Debug)]
+pub struct Candidate {
+	pub id: &'static str,
+	pub kind: DecoderKind,
+	pub certainty: Certainty,
+	pub score: u8,
+	pub reason: Cow<'static, str>
+}
+
+/// The format a decoder would claim some data as, with what its header says, as returned by [`DecoderRegistry::identify`].
+#[derive(Clone, Debug)]
+pub struct Identification {
+	pub id: &'static str,
+	pub kind: DecoderKind,
+	pub certainty: Certainty,
+	pub info: HeaderInfo
+}
+
+/// An ordered set of decoders for each kind of output.
+/// Archives are tried first, then images, then data (compression) decoders, each in list order.
+#[derive(Clone)]
+pub struct DecoderRegistry {
+	archive: Vec<Decoder<Archive>>,
+	image: Vec<Decoder<Image>>,
+	data: Vec<Decoder<Box<[u8]>>>,
+	disabled: HashSet<&'static str>
+}
+
+fn run_decoder<T: DecoderOutput>(decoder: &Decoder<T>, data: &mut FileData, opts: &DecodeOptions) -> Result<DynData, Error> {
+	(decoder.decode)(data, opts)
+		.and_then(|x| x.check_limits(opts).map(|_| x.into()))
+		.map_err(|e| e.in_decoder(decoder.id))
+}
+
+impl Default for DecoderRegistry {
+	fn default() -> Self {
+		Self::builtin()
+	}
+}
+
+impl DecoderRegistry {
+	pub fn empty() -> Self {
+		Self {
+			archive: Vec::new(),
+			image: Vec::new(),
+			data: Vec::new(),
+			disabled: HashSet::new()
+		}
+	}
+
+	/// All decoders that come with kidfile, in their default order.
+	pub fn builtin() -> Self {
+		Self {
+			archive: ARCHIVE_DECODERS.clone(),
+			image: IMAGE_DECODERS.clone(),
+			data: DATA_DECODERS.clone(),
+			disabled: HashSet::new()
+		}
+	}
+
+	/// Adds a decoder at the end of its list. A decoder with the same id is replaced in place.
+	pub fn register<T: DecoderOutput>(&mut self, decoder: Decoder<T>) {
+		let list = T::list_mut(self);
+		if let Some(existing) = list.iter_mut().find(|x| x.id == decoder.id) {
+			*existing = decoder;
+		} else {
+			list.push(decoder);
+		}
+	}
+
+	/// Adds a decoder so that it's tried right before `before_id`, or at the end if there is no such decoder.
+	pub fn register_before<T: DecoderOutput>(&mut self, decoder: Decoder<T>, before_id: &str) {
+		let list = T::list_mut(self);
+		list.retain(|x| x.id != decoder.id);
+		let pos = list.iter().position(|x| x.id == before_id).unwrap_or(list.len());
+		list.insert(pos, decoder);
+	}
+
+	/// Moves a decoder so that it's tried right before another one of the same kind.
+	/// Returns false if either id isn't in the same list.
+	pub fn move_before(&mut self, id: &str, before_id: &str) -> bool {
+		fn move_in<T>(list: &mut Vec<Decoder<T>>, id: &str, before_id: &str) -> bool {
+			let (Some(from), Some(_)) = (list.iter().position(|x| x.id == id), list.iter().position(|x| x.id == before_id)) else {
+				return false;
+			};
+			let decoder = list.remove(from);
+			let to = list.iter().position(|x| x.id == before_id).unwrap();
+			list.insert(to, decoder);
+			true
+		}
+		move_in(&mut self.archive, id, before_id) || move_in(&mut self.image, id, before_id) || move_in(&mut self.data, id, before_id)
+	}
+
+	/// Moves a decoder to the front of its list so it gets the first chance at claiming data.
+	pub fn move_to_front(&mut self, id: &str) -> bool {
+		fn move_in<T>(list: &mut Vec<Decoder<T>>, id: &str) -> bool {
+			if let Some(from) = list.iter().position(|x| x.id == id) {
+				let decoder = list.remove(from);
+				list.insert(0, decoder);
+				true
+			} else {
+				false
+			}
+		}
+		move_in(&mut self.archive, id) || move_in(&mut self.image, id) || move_in(&mut self.data, id)
+	}
+
+	/// Removes a decoder entirely. Returns false if there was no decoder with that id.
+	pub fn unregister(&mut self, id: &str) -> bool {
+		let count = self.archive.len() + self.image.len() + self.data.len();
+		self.archive.retain(|x| x.id != id);
+		self.image.retain(|x| x.id != id);
+		self.data.retain(|x| x.id != id);
+		count != self.archive.len() + self.image.len() + self.data.len()
+	}
+
+	/// Keeps a decoder registered, but skips it when decoding.
+	pub fn disable(&mut self, id: &str) {
+		let found = self.ids().find(|x| *x == id);
+		if let Some(id) = found {
+			self.disabled.insert(id);
+		}
+	}
+
+	pub fn enable(&mut self, id: &str) {
+		self.disabled.remove(id);
+	}
+
+	pub fn is_enabled(&self, id: &str) -> bool {
+		!self.disabled.contains(id) && self.ids().any(|x| x == id)
+	}
+
+	/// Enabled decoders of one kind, in the order they are tried.
+	pub fn decoders<T: DecoderOutput>(&self) -> impl Iterator<Item = &Decoder<T>> {
+		T::list(self).iter().filter(|x| !self.disabled.contains(x.id))
+	}
+
+	/// Ids of every registered decoder, enabled or not, in the order they are tried.
+	pub fn ids(&self) -> impl Iterator<Item = &'static str> + '_ {
+		self.archive.iter().map(|x| x.id)
+			.chain(self.image.iter().map(|x| x.id))
+			.chain(self.data.iter().map(|x| x.id))
+	}
+
+	pub fn desc(&self, id: &str) -> Option<&'static str> {
+		self.archive.iter().map(|x| (x.id, x.desc))
+			.chain(self.image.iter().map(|x| (x.id, x.desc)))
+			.chain(self.data.iter().map(|x| (x.id, x.desc)))
+			.find(|x| x.0 == id)
+			.map(|x| x.1)
+	}
+
+	fn probe_kind<T: DecoderOutput>(&self, data: &mut FileData, out: &mut Vec<Candidate>) {
+		for decoder in self.decoders::<T>() {
+			let detection = (decoder.detect)(data);
+			out.push(Candidate {
+				id: decoder.id,
+				kind: T::KIND,
+				certainty: detection.certainty,
+				score: detection.score,
+				reason: detection.reason
+			});
+		}
+	}
+
+	/// Runs the detection of every enabled decoder and returns all of them, best match first.
+	/// Ties keep the order in which decoders would be tried. Nothing is decoded.
+	pub fn probe(&self, data: &mut FileData) -> Vec<Candidate> {
+		let mut candidates = Vec::new();
+		self.probe_kind::<Archive>(data, &mut candidates);
+		self.probe_kind::<Image>(data, &mut candidates);
+		self.probe_kind::<Box<[u8]>>(data, &mut candidates);
+		candidates.sort_by_key(|x| std::cmp::Reverse(x.score));
+		candidates
+	}
+
+	fn identify_kind<T: DecoderOutput>(&self, data: &mut FileData, certainty: Certainty) -> Option<Identification> {
+		let decoder = self.decoders::<T>().find(|x| (x.detect)(data).certainty == certainty)?;
+		Some(Identification {id: decoder.id, kind: T::KIND, certainty, info: (decoder.identify)(data)})
+	}
+
+	/// Picks the decoder that [`DecoderRegistry::auto_decode_step`] would try first and reads the header info with it.
+	/// Only reads small parts of the data, so it's fine to call on every file in a big directory.
+	pub fn identify(&self, data: &mut FileData) -> Option<Identification> {
+		[Certainty::Certain, Certainty::Possible].into_iter().find_map(|certainty| {
+			self.identify_kind::<Archive>(data, certainty)
+				.or_else(|| self.identify_kind::<Image>(data, certainty))
+				.or_else(|| self.identify_kind::<Box<[u8]>>(data, certainty))
+		})
+	}
+
+	fn decode_step<T: DecoderOutput>(&self, data: &mut FileData, disallow_id: Option<&'static str>, discard_low_confidence: bool, opts: &DecodeOptions) -> Result<Option<(&'static str, DynData)>, Error> {
+		for decoder in self.decoders::<T>() {
+			if let Certainty::Certain = (decoder.detect)(data).certainty {
+				if Some(decoder.id) == disallow_id {
+					return Ok(None);
+				}
+				return run_decoder(decoder, data, opts).map(|x| Some((decoder.id, x)));
+			}
+		}
+		if !discard_low_confidence {
+			for decoder in self.decoders::<T>() {
+				if Some(decoder.id) != disallow_id && let Certainty::Possible = (decoder.detect)(data).certainty {
+					match run_decoder(decoder, data, opts) {
+						Ok(x) => return Ok(Some((decoder.id, x))),
+						Err(e) if e.kind == ErrorKind::Cancelled => return Err(e),
+						Err(_) => {}
+					}
+				}
+			}
+		}
+		Ok(None)
+	}
+
+	/// Runs one decoder by id, skipping detection. Disabled decoders can still be used this way.
+	pub fn decode_with(&self, id: &str, data: &mut FileData, opts: &DecodeOptions) -> Result<DynData, Error> {
+		fn run<T: DecoderOutput>(list: &[Decoder<T>], id: &str, data: &mut FileData, opts: &DecodeOptions) -> Option<Result<DynData, Error>> {
+			Some(run_decoder(list.iter().find(|x| x.id == id)?, data, opts))
+		}
+		run(&self.archive, id, data, opts)
+			.or_else(|| run(&self.image, id, data, opts))
+			.or_else(|| run(&self.data, id, data, opts))
+			.unwrap_or_else(|| Err(Error::unsupported(format!("no decoder with id {id}"))))
+	}
+
+	/// Applies the given decoders in order, without any detection, for when the heuristics pick the wrong chain.
+	/// Every step but the last has to produce raw data. On failure, `steps_taken` holds the steps that succeeded
+	/// and `data` the output of the last one, like with [`DecoderRegistry::auto_decode_full`].
+	pub fn decode_as(&self, initial_data: &mut FileData, ids: &[&str], opts: &DecodeOptions) -> DecodeResult {
+		let mut steps_taken = Vec::<&'static str>::new();
+		let mut cur_data = None;
+		if let Err(e) = opts.check_depth(ids.len()) {
+			return DecodeResult {data: DynData::Raw(initial_data.clone()), steps_taken, error: Some(e)};
+		}
+		for (i, id) in ids.iter().enumerate() {
+			let Some(id) = self.ids().find(|x| x == id) else {
+				return DecodeResult {
+					data: DynData::Raw(cur_data.unwrap_or_else(|| initial_data.clone())),
+					steps_taken,
+					error: Some(Error::unsupported(format!("no decoder with id {id}")))
+				};
+			};
+			match opts.check_cancelled().and_then(|_| self.decode_with(id, cur_data.as_mut().unwrap_or(initial_data), opts)) {
+				Ok(DynData::Raw(new_data)) => {
+					steps_taken.push(id);
+					cur_data = Some(new_data);
+				}
+				Ok(decoded) => {
+					steps_taken.push(id);
+					let error = ids.get(i + 1).map(|next| Error::unsupported(format!("{id} did not produce raw data, so {next} cannot be applied")));
+					return DecodeResult {data: decoded, steps_taken, error};
+				}
+				Err(e) => {
+					return DecodeResult {
+						data: DynData::Raw(cur_data.unwrap_or_else(|| initial_data.clone())),
+						steps_taken,
+						error: Some(e)
+					};
+				}
+			}
+		}
+		DecodeResult {
+			data: DynData::Raw(cur_data.unwrap_or_else(|| initial_data.clone())),
+			steps_taken,
+			error: None
+		}
+	}
+
+	pub fn auto_decode_step(&self, data: &mut FileData, disallow_id: Option<&'static str>, in_archive: Option<&'static str>, opts: &DecodeOptions) -> Result<(&'static str, DynData), Error> {
+		if let Some(x) = self.decode_step::<Archive>(data, disallow_id, in_archive.is_some(), opts)? {
+			return Ok(x);
+		}
+		if let Some(x) = self.decode_step::<Image>(data, disallow_id, false, opts)? {
+			return Ok(x);
+		}
+		if let Some(x) = self.decode_step::<Box<[u8]>>(data, disallow_id, false, opts)? {
+			return Ok(x);
+		}
+		Err(Error::new(ErrorKind::Unrecognized, "could not fully decode file"))
+	}
+
+	/// Stops with an error after [`DecodeOptions::max_depth`] steps, so alternating decompressors can't go on forever.
+	pub fn auto_decode_full(&self, initial_data: &mut FileData, in_archive: Option<&'static str>, opts: &DecodeOptions) -> DecodeResult {
+		let mut steps_taken = Vec::<&'static str>::new();
+		let mut cur_data = None;
+		loop {
+			let step = opts.check_depth(steps_taken.len() + 1)
+				.and_then(|_| opts.check_cancelled())
+				.and_then(|_| self.auto_decode_step(cur_data.as_mut().unwrap_or(initial_data), steps_taken.last().cloned(), in_archive, opts));
+			match step {
+				Ok((id, decoded)) => {
+					steps_taken.push(id);
+					if let DynData::Raw(new_data) = decoded {
+						cur_data = Some(new_data);
+					} else {
+						return DecodeResult {
+							data: decoded,
+							steps_taken,
+							error: None
+						}
+					}
+				}
+				Err(e) => {
+					return DecodeResult {
+						data: DynData::Raw(cur_data.unwrap_or_else(|| initial_data.clone())),
+						steps_taken,
+						error: Some(e)
+					}
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::Detection;
+
+	const fn stub(id: &'static str) -> Decoder<Box<[u8]>> {
+		Decoder {
+			id,
+			desc: "test decoder",
+			detect: |_| Detection::impossible("never matches"),
+			identify: |_| HeaderInfo::default(),
+			decode: |data, _| Ok(data.read()?.into())
+		}
+	}
+
+	fn data_ids(registry: &DecoderRegistry) -> Vec<&'static str> {
+		registry.decoders::<Box<[u8]>>().map(|x| x.id).collect()
+	}
+
+	#[test]
+	fn register_and_move() {
+		let mut registry = DecoderRegistry::empty();
+		registry.register(stub("a"));
+		registry.register(stub("b"));
+		registry.register(stub("c"));
+		assert_eq!(data_ids(&registry), ["a", "b", "c"]);
+		registry.register_before(stub("d"), "b");
+		assert_eq!(data_ids(&registry), ["a", "d", "b", "c"]);
+		registry.register_before(stub("e"), "missing");
+		assert_eq!(data_ids(&registry), ["a", "d", "b", "c", "e"]);
+		assert!(registry.move_before("e", "a"));
+		assert_eq!(data_ids(&registry), ["e", "a", "d", "b", "c"]);
+		assert!(registry.move_before("e", "c"));
+		assert_eq!(data_ids(&registry), ["a", "d", "b", "e", "c"]);
+		assert!(!registry.move_before("e", "missing"));
+		assert!(!registry.move_before("missing", "a"));
+		assert!(registry.move_to_front("c"));
+		assert_eq!(data_ids(&registry), ["c", "a", "d", "b", "e"]);
+		assert!(!registry.move_to_front("missing"));
+		assert!(registry.unregister("d"));
+		assert!(!registry.unregister("d"));
+		assert_eq!(registry.ids().collect::<Vec<_>>(), ["c", "a", "b", "e"]);
+	}
+
+	#[test]
+	fn duplicate_id() {
+		let mut registry = DecoderRegistry::empty();
+		registry.register(stub("a"));
+		registry.register(stub("b"));
+		// replaced where it was, not added again
+		registry.register(Decoder {desc: "replacement", ..stub("a")});
+		assert_eq!(data_ids(&registry), ["a", "b"]);
+		assert_eq!(registry.desc("a"), Some("replacement"));
+		// moved to its new place
+		registry.register_before(stub("b"), "a");
+		assert_eq!(data_ids(&registry), ["b", "a"]);
+		// the builtin ids are unique too
+		let builtin = DecoderRegistry::builtin();
+		assert_eq!(builtin.ids().collect::<HashSet<_>>().len(), builtin.ids().count());
+	}
+
+	#[test]
+	fn disable_and_enable() {
+		let mut registry = DecoderRegistry::empty();
+		registry.register(stub("a"));
+		registry.register(stub("b"));
+		registry.disable("a");
+		assert!(!registry.is_enabled("a"));
+		assert_eq!(data_ids(&registry), ["b"]);
+		// still registered, and still usable by id
+		assert_eq!(registry.ids().collect::<Vec<_>>(), ["a", "b"]);
+		assert!(registry.decode_with("a", &mut FileData::Memory {buf: Box::new([1])}, &DecodeOptions::default()).is_ok());
+		registry.enable("a");
+		assert!(registry.is_enabled("a"));
+		assert_eq!(data_ids(&registry), ["a", "b"]);
+		// unknown ids are never enabled, even after disabling them first
+		registry.disable("missing");
+		registry.enable("missing");
+		assert!(!registry.is_enabled("missing"));
+	}
+	#[test]
+	fn probe_ranks_by_score() {
+		let mut registry = DecoderRegistry::empty();
+		registry.register(stub("never"));
+		registry.register(Decoder {detect: |_| Detection::possible("maybe").with_score(30), ..stub("maybe")});
+		registry.register(Decoder {detect: |_| Detection::possible("also maybe").with_score(30), ..stub("also maybe")});
+		registry.register(Decoder {detect: |data| Detection::magic(data, b"AB", 0), ..stub("magic")});
+		let candidates = registry.probe(&mut FileData::Memory {buf: Box::new(*b"ABCD")});
+		// ties stay in the order the decoders are tried
+		assert_eq!(candidates.iter().map(|x| x.id).collect::<Vec<_>>(), ["magic", "maybe", "also maybe", "never"]);
+		assert_eq!(candidates.iter().map(|x| x.certainty).collect::<Vec<_>>(), [Certainty::Certain, Certainty::Possible, Certainty::Possible, Certainty::Impossible]);
+		assert!(candidates.iter().all(|x| !x.reason.is_empty() && x.kind == DecoderKind::Data));
+		assert_eq!(candidates[3].reason, "never matches");
+		// disabled decoders aren't asked
+		registry.disable("magic");
+		let candidates = registry.probe(&mut FileData::Memory {buf: Box::new(*b"ABCD")});
+		assert_eq!(candidates.iter().map(|x| x.id).collect::<Vec<_>>(), ["maybe", "also maybe", "never"]);
+	}
+	// a registry where "upper" and "fail" would also be picked by auto-detection, to tell if decode_as falls back to it
+	fn decode_as_registry() -> DecoderRegistry {
+		let mut registry = DecoderRegistry::empty();
+		registry.register(Decoder {
+			detect: |_| Detection::certain("always"),
+			decode: |data, _| Ok(data.read()?.to_ascii_uppercase().into()),
+			..stub("upper")
+		});
+		registry.register(Decoder {
+			detect: |_| Detection::certain("always"),
+			decode: |_, _| Err(Error::corrupt("test failure")),
+			..stub("fail")
+		});
+		registry
+	}
+
+	fn raw_bytes(data: &mut DynData) -> Vec<u8> {
+		match data {
+			DynData::Raw(x) => x.read().unwrap().to_vec(),
+			_ => panic!("not raw data")
+		}
+	}
+
+	#[test]
+	fn decode_as_steps() {
+		let registry = decode_as_registry();
+		let mut result = registry.decode_as(&mut FileData::Memory {buf: Box::new(*b"abc")}, &["upper", "upper"], &DecodeOptions::default());
+		assert!(result.error.is_none());
+		assert_eq!(result.steps_taken, ["upper", "upper"]);
+		assert_eq!(raw_bytes(&mut result.data), b"ABC");
+	}
+
+	#[test]
+	fn decode_as_unknown_id() {
+		let registry = decode_as_registry();
+		let mut result = registry.decode_as(&mut FileData::Memory {buf: Box::new(*b"abc")}, &["upper", "missing", "upper"], &DecodeOptions::default());
+		let error = result.error.unwrap();
+		assert_eq!(error.kind, ErrorKind::Unsupported);
+		assert!(error.msg.contains("missing"));
+		// stops there instead of detecting something else
+		assert_eq!(result.steps_taken, ["upper"]);
+		assert_eq!(raw_bytes(&mut result.data), b"ABC");
+	}
+
+	#[test]
+	fn decode_as_failing_step() {
+		let registry = decode_as_registry();
+		let mut result = registry.decode_as(&mut FileData::Memory {buf: Box::new(*b"abc")}, &["upper", "fail", "upper"], &DecodeOptions::default());
+		let error = result.error.unwrap();
+		assert_eq!(error.kind, ErrorKind::Corrupt);
+		assert_eq!(result.steps_taken, ["upper"]);
+		assert_eq!(raw_bytes(&mut result.data), b"ABC");
+	}
+
+	#[test]
+	fn decode_as_too_deep() {
+		let registry = decode_as_registry();
+		let opts = DecodeOptions {max_depth: 2, ..Default::default()};
+		let mut result = registry.decode_as(&mut FileData::Memory {buf: Box::new(*b"abc")}, &["upper"; 3], &opts);
+		assert_eq!(result.error.unwrap().kind, ErrorKind::LimitExceeded);
+		// checked before anything is decoded
+		assert!(result.steps_taken.is_empty());
+		assert_eq!(raw_bytes(&mut result.data), b"abc");
+	}
+}
@@ -0,0 +1,414 @@
+use crate::Error;
+
+// conversions between linear pixel order and the tiled orders that consoles keep textures in,
+// in both directions so that encoders can use them too
+
+// ---- Dreamcast twiddling ----
+
+/// Spreads the bits of `x` out to every other bit, so `0b111` becomes `0b10101`.
+pub fn bit_twiddle(x: usize) -> usize {
+	let mut x = x as u64 & 0xFFFFFFFF;
+	x = (x | x << 16) & 0x0000FFFF0000FFFF;
+	x = (x | x << 8) & 0x00FF00FF00FF00FF;
+	x = (x | x << 4) & 0x0F0F0F0F0F0F0F0F;
+	x = (x | x << 2) & 0x3333333333333333;
+	x = (x | x << 1) & 0x5555555555555555;
+	x as usize
+}
+
+/// Index of a pixel in a twiddled Dreamcast texture. Non-square textures are twiddled as a row or column
+/// of squares as big as the smaller side. Both sides need to be powers of 2.
+pub fn dc_twiddled_index(x: usize, y: usize, width: usize, height: usize) -> usize {
+	let side = width.min(height).max(1);
+	let square = if width > height {x / side} else {y / side};
+	square * side * side + (bit_twiddle(y % side) | bit_twiddle(x % side) << 1)
+}
+
+fn check_dc_size(width: usize, height: usize, len: usize) -> Result<(), Error> {
+	if !width.is_power_of_two() || !height.is_power_of_two() {
+		return Err(Error::unsupported(format!("cannot twiddle a {width}x{height} texture, the sides must be powers of 2")));
+	}
+	if len < width * height {
+		return Err(Error::truncated(format!("not enough pixels for a {width}x{height} texture")));
+	}
+	Ok(())
+}
+
+pub fn untwiddle_dc<T: Copy>(buf: &[T], width: usize, height: usize) -> Result<Vec<T>, Error> {
+	check_dc_size(width, height, buf.len())?;
+	let mut out = Vec::with_capacity(width * height);
+	for y in 0..height {
+		for x in 0..width {
+			out.push(buf[dc_twiddled_index(x, y, width, height)]);
+		}
+	}
+	Ok(out)
+}
+
+pub fn twiddle_dc<T: Copy>(buf: &[T], width: usize, height: usize) -> Result<Vec<T>, Error> {
+	check_dc_size(width, height, buf.len())?;
+	let mut out = buf[..width * height].to_vec();
+	for y in 0..height {
+		for x in 0..width {
+			out[dc_twiddled_index(x, y, width, height)] = buf[y * width + x];
+		}
+	}
+	Ok(out)
+}
+
+// ---- PSP ----
+
+// PSP textures are swizzled in blocks of 16 bytes by 8 rows, whatever the pixel format
+const PSP_BLOCK_WIDTH: usize = 16;
+const PSP_BLOCK_HEIGHT: usize = 8;
+
+fn psp_swizzled_index(x: usize, y: usize, width_bytes: usize) -> usize {
+	let blocks_per_row = width_bytes / PSP_BLOCK_WIDTH;
+	let block_start = ((y / PSP_BLOCK_HEIGHT) * blocks_per_row + x / PSP_BLOCK_WIDTH) * (PSP_BLOCK_WIDTH * PSP_BLOCK_HEIGHT);
+	block_start + (y % PSP_BLOCK_HEIGHT) * PSP_BLOCK_WIDTH + x % PSP_BLOCK_WIDTH
+}
+
+/// Missing bytes at the end come out as 0.
+pub fn unswizzle_psp(buf: &[u8], width_bytes: usize, height: usize) -> Vec<u8> {
+	let mut out = Vec::with_capacity(width_bytes * height);
+	for y in 0..height {
+		for x in 0..width_bytes {
+			out.push(buf.get(psp_swizzled_index(x, y, width_bytes)).copied().unwrap_or_default());
+		}
+	}
+	out
+}
+
+/// The width should be a multiple of 16 bytes and the height a multiple of 8 rows, as the PSP requires.
+pub fn swizzle_psp(buf: &[u8], width_bytes: usize, height: usize) -> Vec<u8> {
+	let mut out = vec![0u8; width_bytes.next_multiple_of(PSP_BLOCK_WIDTH) * height.next_multiple_of(PSP_BLOCK_HEIGHT)];
+	for y in 0..height {
+		for x in 0..width_bytes {
+			out[psp_swizzled_index(x, y, width_bytes)] = buf.get(y * width_bytes + x).copied().unwrap_or_default();
+		}
+	}
+	out
+}
+
+// ---- PS2 ----
+
+/// Swaps entries 8-15 and 16-23 of every 32, converting between a linear CLUT and the CSM1 order the PS2 GS uses.
+/// Doing it twice gives back the original order.
+pub fn reorder_csm1_clut<T>(clut: &mut [T]) {
+	for group in clut.chunks_exact_mut(32) {
+		let (first, second) = group[8..24].split_at_mut(8);
+		first.swap_with_slice(second);
+	}
+}
+
+const GS_PAGE_WORDS: usize = 2048;
+const GS_BLOCK_WORDS: usize = 64;
+const GS_PAGE_BYTES: usize = GS_PAGE_WORDS * 4;
+
+// which block is where in a page, by block row and column
+const GS_BLOCKS_32: [[u8; 8]; 4] = [
+	[0, 1, 4, 5, 16, 17, 20, 21],
+	[2, 3, 6, 7, 18, 19, 22, 23],
+	[8, 9, 12, 13, 24, 25, 28, 29],
+	[10, 11, 14, 15, 26, 27, 30, 31]
+];
+const GS_BLOCKS_4: [[u8; 4]; 8] = [
+	[0, 2, 8, 10],
+	[1, 3, 9, 11],
+	[4, 6, 12, 14],
+	[5, 7, 13, 15],
+	[16, 18, 24, 26],
+	[17, 19, 25, 27],
+	[20, 22, 28, 30],
+	[21, 23, 29, 31]
+];
+
+/// Pixel storage formats of the PS2 Graphics Synthesizer, whose memory is made of 8 KiB pages of 32 blocks,
+/// each block being 4 columns of 16 words.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GsFormat {
+	Psmct32,
+	Psmt8,
+	Psmt4
+}
+
+impl GsFormat {
+	pub const fn bits_per_pixel(self) -> usize {
+		match self {
+			Self::Psmct32 => 32,
+			Self::Psmt8 => 8,
+			Self::Psmt4 => 4
+		}
+	}
+
+	/// In pixels.
+	pub const fn page_size(self) -> (u32, u32) {
+		match self {
+			Self::Psmct32 => (64, 32),
+			Self::Psmt8 => (128, 64),
+			Self::Psmt4 => (128, 128)
+		}
+	}
+
+	const fn block_size(self) -> (u32, u32) {
+		match self {
+			Self::Psmct32 => (8, 8),
+			Self::Psmt8 => (16, 16),
+			Self::Psmt4 => (32, 16)
+		}
+	}
+
+	const fn pixels_per_word(self) -> usize {
+		32 / self.bits_per_pixel()
+	}
+
+	/// How many pages wide the buffer for a texture of this width is.
+	pub fn buffer_width(self, width: u32) -> u32 {
+		width.div_ceil(self.page_size().0).max(1)
+	}
+
+	/// Address of a pixel in GS memory, counted in pixels of this format, for a buffer `buffer_width` pages wide.
+	pub fn address(self, x: u32, y: u32, buffer_width: u32) -> usize {
+		let (page_width, page_height) = self.page_size();
+		let (block_width, block_height) = self.block_size();
+		let page = (y / page_height) as usize * buffer_width as usize + (x / page_width) as usize;
+		let block_x = (x % page_width / block_width) as usize;
+		let block_y = (y % page_height / block_height) as usize;
+		let block = match self {
+			Self::Psmt4 => GS_BLOCKS_4[block_y][block_x],
+			_ => GS_BLOCKS_32[block_y][block_x]
+		} as usize;
+		let (x, y) = (x % block_width, y % block_height);
+		let (column, word, sub) = match self {
+			Self::Psmct32 => (y / 2, column_word(x, y % 2), 0),
+			_ => {
+				// smaller pixels are 4 rows to a column, with rows 2 and 3 shifted over by half a column,
+				// and the other way around in odd columns. each group of 8 pixels across goes to the next byte or nibble of the words
+				let column = y / 4;
+				let row = y % 4;
+				let swap = ((row >> 1) ^ (column & 1)) * 4;
+				(column, column_word((x % 8) ^ swap, row & 1), (row >> 1) + 2 * (x / 8))
+			}
+		};
+		let word = page * GS_PAGE_WORDS + block * GS_BLOCK_WORDS + column as usize * 16 + word;
+		word * self.pixels_per_word() + sub as usize
+	}
+}
+
+// index of a word in a column, which is 8 words across and 2 down
+fn column_word(x: u32, y: u32) -> usize {
+	((x >> 1) * 4 + (x & 1) + y * 2) as usize
+}
+
+fn get_pixel(buf: &[u8], bits: usize, idx: usize) -> Option<u32> {
+	match bits {
+		32 => buf.get(idx * 4..idx * 4 + 4).map(|x| u32::from_le_bytes(x.try_into().unwrap())),
+		8 => buf.get(idx).map(|x| *x as u32),
+		_ => buf.get(idx / 2).map(|x| (*x >> (idx % 2 * 4)) as u32 & 0xF)
+	}
+}
+
+fn set_pixel(buf: &mut [u8], bits: usize, idx: usize, value: u32) {
+	match bits {
+		32 => buf[idx * 4..idx * 4 + 4].copy_from_slice(&value.to_le_bytes()),
+		8 => buf[idx] = value as u8,
+		_ => {
+			let shift = idx % 2 * 4;
+			buf[idx / 2] = buf[idx / 2] & !(0xF << shift) | (value as u8 & 0xF) << shift;
+		}
+	}
+}
+
+fn linear_size(width: u32, height: u32, fmt: GsFormat) -> usize {
+	(width as usize * height as usize * fmt.bits_per_pixel()).div_ceil(8)
+}
+
+fn gs_memory_size(height: u32, fmt: GsFormat, buffer_width: u32) -> usize {
+	buffer_width as usize * height.div_ceil(fmt.page_size().1) as usize * GS_PAGE_BYTES
+}
+
+fn read_gs(mem: &[u8], width: u32, height: u32, fmt: GsFormat, buffer_width: u32) -> Result<Vec<u8>, Error> {
+	let bits = fmt.bits_per_pixel();
+	let mut out = vec![0u8; linear_size(width, height, fmt)];
+	for y in 0..height {
+		for x in 0..width {
+			let value = get_pixel(mem, bits, fmt.address(x, y, buffer_width))
+				.ok_or_else(|| Error::truncated(format!("not enough GS memory for a {width}x{height} texture")))?;
+			set_pixel(&mut out, bits, (y * width + x) as usize, value);
+		}
+	}
+	Ok(out)
+}
+
+fn write_gs(mem: &mut [u8], linear: &[u8], width: u32, height: u32, fmt: GsFormat, buffer_width: u32) -> Result<(), Error> {
+	let bits = fmt.bits_per_pixel();
+	if linear.len() < linear_size(width, height, fmt) {
+		return Err(Error::truncated(format!("not enough pixels for a {width}x{height} texture")));
+	}
+	for y in 0..height {
+		for x in 0..width {
+			let value = get_pixel(linear, bits, (y * width + x) as usize).unwrap();
+			set_pixel(mem, bits, fmt.address(x, y, buffer_width), value);
+		}
+	}
+	Ok(())
+}
+
+/// Reads a texture out of a dump of GS memory, where it starts at address 0.
+pub fn gs_to_linear(mem: &[u8], width: u32, height: u32, fmt: GsFormat) -> Result<Vec<u8>, Error> {
+	read_gs(mem, width, height, fmt, fmt.buffer_width(width))
+}
+
+/// Lays a texture out the way it is in GS memory, in as many whole pages as it covers.
+pub fn linear_to_gs(linear: &[u8], width: u32, height: u32, fmt: GsFormat) -> Result<Vec<u8>, Error> {
+	let buffer_width = fmt.buffer_width(width);
+	let mut mem = vec![0u8; gs_memory_size(height, fmt, buffer_width)];
+	write_gs(&mut mem, linear, width, height, fmt, buffer_width)?;
+	Ok(mem)
+}
+
+// the PSMCT32 rectangle that covers exactly the same memory as an 8 or 4-bit texture
+fn psmct32_rect(width: u32, height: u32, fmt: GsFormat) -> Result<(u32, u32), Error> {
+	let buffer_width = fmt.buffer_width(width);
+	let words = |w: u32, h: u32, fmt: GsFormat| {
+		let mut words = (0..h).flat_map(|y| (0..w).map(move |x| fmt.address(x, y, buffer_width) / fmt.pixels_per_word())).collect::<Vec<_>>();
+		words.sort_unstable();
+		words.dedup();
+		words
+	};
+	let candidates: &[(u32, u32)] = match fmt {
+		GsFormat::Psmct32 => &[(width, height)],
+		GsFormat::Psmt8 => &[(width / 2, height / 2)],
+		GsFormat::Psmt4 => &[(width / 2, height / 4), (width / 4, height / 2)]
+	};
+	let texture_words = words(width, height, fmt);
+	candidates.iter().copied()
+		.find(|&(w, h)| w * h * 4 == width * height * fmt.bits_per_pixel() as u32 / 8 && words(w, h, GsFormat::Psmct32) == texture_words)
+		.ok_or_else(|| Error::unsupported(format!("a {width}x{height} {fmt:?} texture does not line up with PSMCT32 blocks")))
+}
+
+/// Undoes the swizzling of textures that were stored so they could be uploaded to the GS as PSMCT32,
+/// but are read as `fmt`, which is how 8 and 4-bit textures are usually swizzled.
+pub fn unswizzle_ps2(buf: &[u8], width: u32, height: u32, fmt: GsFormat) -> Result<Vec<u8>, Error> {
+	let (width_32, height_32) = psmct32_rect(width, height, fmt)?;
+	let buffer_width = fmt.buffer_width(width);
+	let mut mem = vec![0u8; gs_memory_size(height, fmt, buffer_width)];
+	write_gs(&mut mem, buf, width_32, height_32, GsFormat::Psmct32, buffer_width)?;
+	read_gs(&mem, width, height, fmt, buffer_width)
+}
+
+/// The inverse of [`unswizzle_ps2`].
+pub fn swizzle_ps2(buf: &[u8], width: u32, height: u32, fmt: GsFormat) -> Result<Vec<u8>, Error> {
+	let (width_32, height_32) = psmct32_rect(width, height, fmt)?;
+	let buffer_width = fmt.buffer_width(width);
+	let mut mem = vec![0u8; gs_memory_size(height, fmt, buffer_width)];
+	write_gs(&mut mem, buf, width, height, fmt, buffer_width)?;
+	read_gs(&mem, width_32, height_32, GsFormat::Psmct32, buffer_width)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn dc_twiddle_round_trip() {
+		for (width, height) in [(1, 1), (8, 8), (32, 8), (8, 32), (64, 4)] {
+			let buf = (0..width * height).collect::<Vec<usize>>();
+			let twiddled = twiddle_dc(&buf, width, height).unwrap();
+			assert!(width * height == 1 || twiddled != buf, "{width}x{height}");
+			assert_eq!(untwiddle_dc(&twiddled, width, height).unwrap(), buf, "{width}x{height}");
+		}
+		assert_eq!(twiddle_dc(&[0u8; 4], 2, 2).unwrap(), [0; 4]);
+		assert!(twiddle_dc(&[0u8; 12], 4, 3).is_err());
+		assert!(untwiddle_dc(&[0u8; 15], 4, 4).is_err());
+	}
+
+	#[test]
+	fn dc_twiddle_non_square() {
+		// a 4x2 texture is two 2x2 twiddled squares side by side
+		assert_eq!(untwiddle_dc(&[0, 1, 2, 3, 4, 5, 6, 7], 4, 2).unwrap(), [0, 2, 4, 6, 1, 3, 5, 7]);
+		// and a 2x4 one is the same squares stacked
+		assert_eq!(untwiddle_dc(&[0, 1, 2, 3, 4, 5, 6, 7], 2, 4).unwrap(), [0, 2, 1, 3, 4, 6, 5, 7]);
+	}
+
+	#[test]
+	fn psp_round_trip() {
+		for (width_bytes, height) in [(16, 8), (64, 16), (48, 24)] {
+			let buf = (0..width_bytes * height).map(|x| (x * 7 % 251) as u8).collect::<Vec<_>>();
+			let swizzled = swizzle_psp(&buf, width_bytes, height);
+			assert_eq!(swizzled.len(), buf.len());
+			assert_eq!(unswizzle_psp(&swizzled, width_bytes, height), buf);
+		}
+		// the second row of a block comes right after the first 16 bytes
+		let buf = (0..32 * 8).map(|x| x as u8).collect::<Vec<_>>();
+		assert_eq!(swizzle_psp(&buf, 32, 8)[16..32], buf[32..48]);
+	}
+
+	fn test_pixels(width: u32, height: u32, fmt: GsFormat) -> Vec<u8> {
+		(0..linear_size(width, height, fmt)).map(|x| (x * 7 + x / 251) as u8).collect()
+	}
+
+	#[test]
+	fn gs_round_trip() {
+		for fmt in [GsFormat::Psmct32, GsFormat::Psmt8, GsFormat::Psmt4] {
+			let (page_width, page_height) = fmt.page_size();
+			for (width, height) in [(page_width, page_height), (page_width * 2, page_height * 3), (page_width / 2, page_height / 4)] {
+				let linear = test_pixels(width, height, fmt);
+				let mem = linear_to_gs(&linear, width, height, fmt).unwrap();
+				assert_eq!(mem.len() % GS_PAGE_BYTES, 0);
+				assert_eq!(gs_to_linear(&mem, width, height, fmt).unwrap(), linear, "{fmt:?} {width}x{height}");
+			}
+			// every pixel of a page has its own address in it
+			let mut addresses = (0..page_height).flat_map(|y| (0..page_width).map(move |x| fmt.address(x, y, 1))).collect::<Vec<_>>();
+			addresses.sort_unstable();
+			addresses.dedup();
+			assert_eq!(addresses.len(), (page_width * page_height) as usize, "{fmt:?}");
+			assert_eq!(addresses.last(), Some(&(GS_PAGE_BYTES * 8 / fmt.bits_per_pixel() - 1)));
+		}
+		assert!(gs_to_linear(&[0; 16], 64, 32, GsFormat::Psmct32).is_err());
+		assert!(linear_to_gs(&[0; 16], 64, 32, GsFormat::Psmct32).is_err());
+	}
+
+	#[test]
+	fn gs_psmct32_layout() {
+		// the words of a column go 0 1 4 5 8 9 12 13 across the first row and 2 3 6 7 10 11 14 15 across the second
+		let row = |y| (0..8).map(|x| GsFormat::Psmct32.address(x, y, 1)).collect::<Vec<_>>();
+		assert_eq!(row(0), [0, 1, 4, 5, 8, 9, 12, 13]);
+		assert_eq!(row(1), [2, 3, 6, 7, 10, 11, 14, 15]);
+		// the next block across is block 1, and the one below is block 2
+		assert_eq!(GsFormat::Psmct32.address(8, 0, 1), GS_BLOCK_WORDS);
+		assert_eq!(GsFormat::Psmct32.address(0, 8, 1), GS_BLOCK_WORDS * 2);
+	}
+
+	#[test]
+	fn ps2_swizzle_round_trip() {
+		for (fmt, width, height) in [
+			(GsFormat::Psmct32, 64, 32),
+			(GsFormat::Psmt8, 128, 64),
+			(GsFormat::Psmt8, 256, 128),
+			(GsFormat::Psmt4, 128, 128),
+			(GsFormat::Psmt4, 256, 256)
+		] {
+			let linear = test_pixels(width, height, fmt);
+			let swizzled = swizzle_ps2(&linear, width, height, fmt).unwrap();
+			assert_eq!(swizzled.len(), linear.len());
+			if fmt != GsFormat::Psmct32 {
+				assert_ne!(swizzled, linear, "{fmt:?} {width}x{height}");
+			}
+			assert_eq!(unswizzle_ps2(&swizzled, width, height, fmt).unwrap(), linear, "{fmt:?} {width}x{height}");
+		}
+	}
+
+	#[test]
+	fn csm1_round_trip() {
+		let linear = (0..256).collect::<Vec<u32>>();
+		let mut clut = linear.clone();
+		reorder_csm1_clut(&mut clut);
+		assert_eq!(clut[..8], linear[..8]);
+		assert_eq!(clut[8..16], linear[16..24]);
+		assert_eq!(clut[16..24], linear[8..16]);
+		assert_eq!(clut[40..48], linear[48..56]);
+		reorder_csm1_clut(&mut clut);
+		assert_eq!(clut, linear);
+	}
+}